@@ -0,0 +1,76 @@
+use crate::ray::Ray;
+use crate::vec3::Vec3;
+
+#[derive(Debug, Copy, Clone)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Aabb {
+    pub fn new(min: Vec3, max: Vec3) -> Aabb {
+        return Aabb { min, max };
+    }
+
+    // Starts out inverted so that the first grow() snaps it to that point
+    pub fn empty() -> Aabb {
+        return Aabb {
+            min: Vec3::new(f64::MAX, f64::MAX, f64::MAX),
+            max: Vec3::new(-f64::MAX, -f64::MAX, -f64::MAX),
+        };
+    }
+
+    pub fn from_points(points: &[Vec3]) -> Aabb {
+        let mut result = Aabb::empty();
+        for p in points {
+            result.grow(p);
+        }
+        return result;
+    }
+
+    pub fn grow(&mut self, p: &Vec3) {
+        self.min = self.min.min(p);
+        self.max = self.max.max(p);
+    }
+
+    pub fn surrounding(&self, other: &Aabb) -> Aabb {
+        return Aabb {
+            min: self.min.min(&other.min),
+            max: self.max.max(&other.max),
+        };
+    }
+
+    pub fn centroid(&self) -> Vec3 {
+        return (self.min + self.max) * 0.5;
+    }
+
+    pub fn longest_axis(&self) -> usize {
+        let extent = self.max - self.min;
+        if extent.x > extent.y && extent.x > extent.z {
+            return 0;
+        } else if extent.y > extent.z {
+            return 1;
+        }
+        return 2;
+    }
+
+    // Slab test. Returns true if the ray overlaps the box anywhere in [t_min, t_max]
+    pub fn hit(&self, r: &Ray, mut t_min: f64, mut t_max: f64) -> bool {
+        for axis in 0..3 {
+            let inv_d = 1.0 / r.dir[axis];
+            let mut t0 = (self.min[axis] - r.orig[axis]) * inv_d;
+            let mut t1 = (self.max[axis] - r.orig[axis]) * inv_d;
+            if inv_d < 0.0 {
+                std::mem::swap(&mut t0, &mut t1);
+            }
+
+            t_min = if t0 > t_min { t0 } else { t_min };
+            t_max = if t1 < t_max { t1 } else { t_max };
+            if t_max < t_min {
+                return false;
+            }
+        }
+
+        return true;
+    }
+}
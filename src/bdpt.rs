@@ -0,0 +1,684 @@
+use crate::camera::Camera;
+use crate::hitable::{HitRecord, Hitable};
+use crate::integrator::{Integrator, Splat};
+use crate::light;
+use crate::medium::{self, MediumStack};
+use crate::ray::Ray;
+use crate::sampler::Sampler;
+use crate::scene::Scene;
+use crate::vec3::Vec3;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) enum VertexKind {
+    Camera,
+    Light,
+    Surface,
+    Medium,
+}
+
+// Which way light flows along a subpath: towards its start for camera
+// subpaths, away from it for light subpaths
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) enum Transport {
+    Radiance,
+    Importance,
+}
+
+#[derive(Copy, Clone)]
+pub(crate) struct Vertex<'a> {
+    pub kind: VertexKind,
+    pub p: Vec3,
+    // Geometric normal of surfaces and lights
+    pub n: Vec3,
+    pub rec: Option<HitRecord<'a>>,
+    // Unit vector towards the previous vertex of the same subpath
+    pub wo: Vec3,
+    // Media the vertex is in, for connections leaving from it
+    pub media: MediumStack,
+    pub anisotropy: f64,
+    // Throughput of the subpath up to and including this vertex
+    pub beta: Vec3,
+    // Scattered by something that eval() can't describe, like a mirror, so it
+    // can't be connected to
+    pub delta: bool,
+    // Density per unit area of sampling this vertex from the previous one, and
+    // from the next one if the subpath had been traced the other way round
+    pub pdf_fwd: f64,
+    pub pdf_rev: f64,
+}
+
+impl<'a> Vertex<'a> {
+    pub fn new(kind: VertexKind, p: Vec3, beta: Vec3) -> Vertex<'a> {
+        return Vertex {
+            kind,
+            p,
+            n: Vec3::new(0.0, 0.0, 0.0),
+            rec: None,
+            wo: Vec3::new(0.0, 0.0, 0.0),
+            media: MediumStack::new(),
+            anisotropy: 0.0,
+            beta,
+            delta: false,
+            pdf_fwd: 0.0,
+            pdf_rev: 0.0,
+        };
+    }
+
+    pub fn camera(camera: &Camera, lens_point: Vec3) -> Vertex<'a> {
+        let mut vertex = Vertex::new(VertexKind::Camera, lens_point, Vec3::new(1.0, 1.0, 1.0));
+        vertex.n = -camera.w;
+        return vertex;
+    }
+
+    // A point on a light, with beta left for the caller to fill in
+    pub fn light(rec: HitRecord<'a>, pdf_fwd: f64) -> Vertex<'a> {
+        let mut vertex = Vertex::new(VertexKind::Light, rec.p, Vec3::new(0.0, 0.0, 0.0));
+        vertex.n = rec.geometric_normal;
+        vertex.rec = Some(rec);
+        vertex.pdf_fwd = pdf_fwd;
+        return vertex;
+    }
+
+    fn on_surface(&self) -> bool {
+        return self.kind == VertexKind::Surface || self.kind == VertexKind::Light;
+    }
+
+    // Whether light subpaths can be merged into this vertex, which has to be on
+    // a surface that eval() describes
+    pub fn can_merge(&self) -> bool {
+        match (self.kind, &self.rec) {
+            (VertexKind::Surface, Some(rec)) => return !rec.mat_ptr.is_delta(),
+            _ => return false,
+        }
+    }
+
+    // Absolute cosine between dir and the normal that scattering uses
+    pub fn cos_to(&self, dir: &Vec3) -> f64 {
+        match (self.kind, &self.rec) {
+            (VertexKind::Surface, Some(rec)) => return rec.normal.dot(dir).abs(),
+            (VertexKind::Light, _) => return self.n.dot(dir).abs(),
+            _ => return 1.0,
+        }
+    }
+
+    pub fn emitted(&self) -> Vec3 {
+        match &self.rec {
+            Some(rec) => return rec.mat_ptr.emitted(rec),
+            None => return Vec3::new(0.0, 0.0, 0.0),
+        }
+    }
+
+    // Turns a solid angle density of sampling next from here into a density per
+    // unit area at next
+    pub fn convert_density(&self, pdf: f64, next: &Vertex) -> f64 {
+        let w = next.p - self.p;
+        let distance_squared = w.squared_length();
+        if distance_squared <= 0.0 {
+            return 0.0;
+        }
+        let mut result = pdf / distance_squared;
+        if next.on_surface() {
+            result *= next.n.dot(&w).abs() / distance_squared.sqrt();
+        }
+        return result;
+    }
+
+    // BSDF or phase function for light going between this vertex and next, in
+    // the direction given by mode. Lights emit uniformly, their radiance being
+    // part of beta already
+    pub fn f(&self, next: &Vertex, mode: Transport) -> Vec3 {
+        let black = Vec3::new(0.0, 0.0, 0.0);
+        let to_next = (next.p - self.p).normalized();
+        match self.kind {
+            VertexKind::Surface => {
+                let rec = match &self.rec {
+                    Some(rec) => rec,
+                    None => return black,
+                };
+                if !rec.is_consistent(&to_next) {
+                    return black;
+                }
+                match mode {
+                    Transport::Radiance => return rec.mat_ptr.eval(rec, &to_next, &self.wo),
+                    Transport::Importance => return rec.mat_ptr.eval(rec, &self.wo, &to_next),
+                }
+            }
+            VertexKind::Medium => {
+                let phase = medium::henyey_greenstein((-self.wo).dot(&to_next), self.anisotropy);
+                return Vec3::new(phase, phase, phase);
+            }
+            VertexKind::Light => return Vec3::new(1.0, 1.0, 1.0),
+            VertexKind::Camera => return black,
+        }
+    }
+
+    // Density per unit area of sampling next from this vertex, having arrived
+    // from prev
+    pub fn pdf(&self, camera: &Camera, prev: Option<&Vertex>, next: &Vertex) -> f64 {
+        let to_next = (next.p - self.p).normalized();
+        let pdf = match self.kind {
+            VertexKind::Camera => camera.pdf_dir(&self.p, &to_next),
+            VertexKind::Light => return self.pdf_emission(next),
+            VertexKind::Surface => {
+                let (rec, prev) = match (&self.rec, prev) {
+                    (Some(rec), Some(prev)) => (rec, prev),
+                    _ => return 0.0,
+                };
+                if rec.mat_ptr.is_delta() {
+                    return 0.0;
+                }
+                let wo = (prev.p - self.p).normalized();
+                rec.mat_ptr.pdf(rec, &to_next, &wo)
+            }
+            VertexKind::Medium => match prev {
+                Some(prev) => {
+                    let travel = (self.p - prev.p).normalized();
+                    medium::henyey_greenstein(travel.dot(&to_next), self.anisotropy)
+                }
+                None => return 0.0,
+            },
+        };
+        return self.convert_density(pdf, next);
+    }
+
+    // Density per unit area of a light subpath leaving this point of a light
+    // towards next
+    pub fn pdf_emission(&self, next: &Vertex) -> f64 {
+        let to_next = (next.p - self.p).normalized();
+        return self.convert_density(light::emission_pdf(&self.n, &to_next), next);
+    }
+
+    // Density per unit area of a light subpath starting at this point of a
+    // light
+    pub fn pdf_light_origin(&self, scene: &Scene) -> f64 {
+        match &self.rec {
+            Some(rec) => return scene.lights.pdf_area(rec),
+            None => return 0.0,
+        }
+    }
+}
+
+// Fraction of light making it from a to b. Zero if something is in the way
+pub(crate) fn transmittance(scene: &Scene, a: &Vertex, b: &Vertex) -> Vec3 {
+    let to_b = b.p - a.p;
+    let distance = to_b.length();
+    let mut ray = Ray::new(a.p, to_b / distance);
+    ray.media = a.media;
+    if scene.world.hit(&ray, 0.001, distance - 0.001).is_some() {
+        return Vec3::new(0.0, 0.0, 0.0);
+    }
+    return a.media.transmittance(distance);
+}
+
+// Extends path by following ray until it stops scattering or path has
+// max_vertices vertices. beta and pdf_dir are the throughput so far and the
+// solid angle density of ray's direction. Returns the light of the background
+// if a camera subpath escapes
+pub(crate) fn random_walk<'a>(
+    scene: &'a Scene,
+    ray: Ray,
+    beta: Vec3,
+    pdf_dir: f64,
+    max_vertices: usize,
+    mode: Transport,
+    path: &mut Vec<Vertex<'a>>,
+) -> Vec3 {
+    let mut ray = ray;
+    let mut beta = beta;
+    let mut pdf_fwd = pdf_dir;
+
+    while path.len() < max_vertices {
+        let rec = match scene.world.hit(&ray, 0.001, f64::MAX) {
+            Some(rec) => rec,
+            None => {
+                if mode == Transport::Radiance {
+                    return beta * scene.background.radiance(&ray);
+                }
+                break;
+            }
+        };
+
+        let prev_index = path.len() - 1;
+        let wo = -ray.dir.normalized();
+        let medium = ray.media.sample(rec.t * ray.dir.length());
+        beta *= medium.weight;
+
+        let pdf_rev;
+        if let Some(distance) = medium.scatter_distance {
+            let p = ray.point_at_parameter(distance / ray.dir.length());
+            let mut vertex = Vertex::new(VertexKind::Medium, p, beta);
+            vertex.wo = wo;
+            vertex.media = ray.media;
+            vertex.anisotropy = ray.media.anisotropy();
+            vertex.pdf_fwd = path[prev_index].convert_density(pdf_fwd, &vertex);
+            path.push(vertex);
+            if path.len() >= max_vertices {
+                break;
+            }
+
+            // Henyey-Greenstein is sampled exactly and is symmetric
+            let dir = medium::sample_henyey_greenstein(&ray.dir, vertex.anisotropy);
+            pdf_fwd = medium::henyey_greenstein(ray.dir.normalized().dot(&dir), vertex.anisotropy);
+            pdf_rev = pdf_fwd;
+            ray = ray.spawn(p, dir);
+        } else {
+            let mut vertex = Vertex::new(VertexKind::Surface, rec.p, beta);
+            vertex.n = rec.geometric_normal;
+            vertex.rec = Some(rec);
+            vertex.wo = wo;
+            vertex.media = ray.media;
+            vertex.pdf_fwd = path[prev_index].convert_density(pdf_fwd, &vertex);
+            path.push(vertex);
+            if path.len() >= max_vertices {
+                break;
+            }
+
+            let scattered = match rec.mat_ptr.scatter(&ray, &rec) {
+                Some(scattered) => scattered,
+                None => break,
+            };
+            if !rec.is_consistent(&scattered.out_ray.dir) {
+                break;
+            }
+
+            if rec.mat_ptr.is_delta() {
+                path.last_mut().unwrap().delta = true;
+                pdf_fwd = 0.0;
+                pdf_rev = 0.0;
+            } else {
+                let wi = scattered.out_ray.dir.normalized();
+                pdf_fwd = rec.mat_ptr.pdf(&rec, &wi, &wo);
+                pdf_rev = rec.mat_ptr.pdf(&rec, &wo, &wi);
+            }
+            beta *= scattered.attenuation;
+            ray = scattered.out_ray;
+        }
+
+        // How likely the previous vertex would have been if the subpath had been
+        // traced the other way round
+        let current = path[path.len() - 1];
+        path[prev_index].pdf_rev = current.convert_density(pdf_rev, &path[prev_index]);
+    }
+
+    return Vec3::new(0.0, 0.0, 0.0);
+}
+
+// Starts a path on a random point of a random light, leaving in a cosine
+// distributed direction on either side
+pub(crate) fn generate_light_subpath<'a>(
+    scene: &'a Scene,
+    max_vertices: usize,
+    path: &mut Vec<Vertex<'a>>,
+) {
+    let sample = match scene.lights.sample_emission() {
+        Some(sample) => sample,
+        None => return,
+    };
+
+    let emitted = sample.rec.mat_ptr.emitted(&sample.rec);
+    let mut vertex = Vertex::light(sample.rec, sample.pdf_pos);
+    vertex.beta = emitted / sample.pdf_pos;
+    path.push(vertex);
+
+    random_walk(
+        scene,
+        Ray::new(vertex.p, sample.dir),
+        sample.weight(),
+        sample.pdf_dir,
+        max_vertices,
+        Transport::Importance,
+        path,
+    );
+}
+
+// Bidirectional path tracer (Veach 1997). Each camera ray starts a subpath
+// from the camera, and another one gets traced from a random light. Every
+// prefix of one is connected to every prefix of the other, and all the ways
+// of building the same path are weighted against each other with the balance
+// heuristic. Connections that reach the camera directly can land anywhere on
+// the image and get splatted.
+//
+// Works in RGB only, and paths end after max_depth bounces
+pub struct Bdpt {
+    pub max_depth: u32,
+}
+
+impl Bdpt {
+    pub fn new(max_depth: u32) -> Bdpt {
+        return Bdpt { max_depth };
+    }
+}
+
+// Joins light and camera subpaths, weighting each way of doing so against all
+// the others. Merging is left to the caller, but gets taken into account when
+// eta isn't zero: it's the number of light subpaths times the area that
+// vertices get merged over, as in vertex connection and merging
+pub(crate) struct Connector<'c> {
+    pub scene: &'c Scene<'c>,
+    pub camera: &'c Camera,
+    pub eta: f64,
+}
+
+impl<'c> Connector<'c> {
+    // Light carried by the path made of the first s vertices of the light
+    // subpath and the first t of the camera subpath. For t == 1 the light goes
+    // into splats instead
+    pub fn connect(
+        &self,
+        light_path: &[Vertex],
+        camera_path: &[Vertex],
+        s: usize,
+        t: usize,
+        splats: &mut Vec<Splat>,
+    ) -> Vec3 {
+        let (scene, camera) = (self.scene, self.camera);
+        let black = Vec3::new(0.0, 0.0, 0.0);
+        let radiance;
+        let mut sampled: Option<Vertex> = None;
+        let mut splat_at: Option<(f64, f64)> = None;
+
+        if s == 0 {
+            // The camera subpath found a light on its own
+            let pt = &camera_path[t - 1];
+            if pt.kind != VertexKind::Surface {
+                return black;
+            }
+            radiance = pt.beta * pt.emitted();
+            if radiance.squared_length() <= 0.0 {
+                return black;
+            }
+            // Emitters that aren't in the list of lights can't be found any other way
+            if pt.pdf_light_origin(scene) <= 0.0 {
+                return radiance;
+            }
+        } else if t == 1 {
+            // Straight to the camera, through a random point of the lens
+            let qs = &light_path[s - 1];
+            if qs.delta {
+                return black;
+            }
+            let lens_point = camera.sample_lens();
+            splat_at = camera.project(&lens_point, &qs.p);
+            if splat_at.is_none() {
+                return black;
+            }
+            let camera_vertex = Vertex::camera(camera, lens_point);
+
+            let to_camera = lens_point - qs.p;
+            let distance = to_camera.length();
+            let wi = to_camera / distance;
+            let importance = camera.importance(&lens_point, &-wi);
+            let pdf = distance * distance / (wi.dot(&camera.w).abs() * camera.lens_area());
+            if importance <= 0.0 || pdf <= 0.0 {
+                return black;
+            }
+
+            let f = qs.f(&camera_vertex, Transport::Importance);
+            if f.squared_length() <= 0.0 {
+                return black;
+            }
+            radiance = qs.beta
+                * f
+                * transmittance(scene, qs, &camera_vertex)
+                * (importance * qs.cos_to(&wi) / pdf);
+            sampled = Some(camera_vertex);
+        } else if s == 1 {
+            // Next event estimation, picking a new point on the lights
+            let pt = &camera_path[t - 1];
+            if pt.delta {
+                return black;
+            }
+            let sample = match scene.lights.sample(&pt.p) {
+                Some(sample) => sample,
+                None => return black,
+            };
+            let light_vertex = Vertex::light(sample.rec, scene.lights.pdf_area(&sample.rec));
+
+            let f = pt.f(&light_vertex, Transport::Radiance);
+            if f.squared_length() <= 0.0 {
+                return black;
+            }
+            let wi = (light_vertex.p - pt.p).normalized();
+            radiance = pt.beta
+                * f
+                * light_vertex.emitted()
+                * transmittance(scene, pt, &light_vertex)
+                * (pt.cos_to(&wi) / sample.pdf);
+            sampled = Some(light_vertex);
+        } else {
+            let qs = &light_path[s - 1];
+            let pt = &camera_path[t - 1];
+            if qs.delta || pt.delta {
+                return black;
+            }
+
+            let f = qs.f(pt, Transport::Importance) * pt.f(qs, Transport::Radiance);
+            if f.squared_length() <= 0.0 {
+                return black;
+            }
+            let d = pt.p - qs.p;
+            let distance_squared = d.squared_length();
+            let w = d / distance_squared.sqrt();
+            let g = qs.cos_to(&w) * pt.cos_to(&w) / distance_squared;
+            radiance = qs.beta * f * pt.beta * transmittance(scene, pt, qs) * g;
+        }
+
+        if radiance.squared_length() <= 0.0 {
+            return black;
+        }
+        let weight = self.mis_weight(light_path, camera_path, sampled.as_ref(), s, t);
+        if let Some((s, t)) = splat_at {
+            splats.push(Splat {
+                s,
+                t,
+                color: radiance * weight,
+            });
+            return black;
+        }
+        return radiance * weight;
+    }
+
+    // Balance heuristic weight of building a path with s light and t camera
+    // vertices, against all the other ways of building it. sampled replaces the
+    // last light vertex when s == 1, and the camera vertex when t == 1
+    pub fn mis_weight(
+        &self,
+        light_path: &[Vertex],
+        camera_path: &[Vertex],
+        sampled: Option<&Vertex>,
+        s: usize,
+        t: usize,
+    ) -> f64 {
+        if s + t == 2 {
+            return 1.0;
+        }
+        let (connections, merges) = self.strategy_ratios(light_path, camera_path, sampled, s, t);
+        return 1.0 / (1.0 + connections + merges);
+    }
+
+    // Densities of the other ways of building the path with s light and t
+    // camera vertices, over its own, same arguments as mis_weight(). Returns the
+    // sums for connecting elsewhere, and for merging at any of the vertices
+    pub fn strategy_ratios(
+        &self,
+        light_path: &[Vertex],
+        camera_path: &[Vertex],
+        sampled: Option<&Vertex>,
+        s: usize,
+        t: usize,
+    ) -> (f64, f64) {
+        let (scene, camera) = (self.scene, self.camera);
+
+        // Vertices at both ends of the connection, and the ones before them
+        let qs = match s {
+            0 => None,
+            1 if t > 1 => sampled.copied(),
+            _ => Some(light_path[s - 1]),
+        };
+        let pt = if t == 1 {
+            *sampled.unwrap()
+        } else {
+            camera_path[t - 1]
+        };
+        let qs_minus = if s > 1 {
+            Some(&light_path[s - 2])
+        } else {
+            None
+        };
+        let pt_minus = if t > 1 {
+            Some(&camera_path[t - 2])
+        } else {
+            None
+        };
+
+        // Reverse densities that the connection changes
+        let pt_rev = match (&qs, pt_minus) {
+            (Some(qs), _) => qs.pdf(camera, qs_minus, &pt),
+            (None, Some(_)) => pt.pdf_light_origin(scene),
+            (None, None) => 0.0,
+        };
+        let pt_minus_rev = match (&qs, pt_minus) {
+            (Some(qs), Some(pt_minus)) => pt.pdf(camera, Some(qs), pt_minus),
+            (None, Some(pt_minus)) => pt.pdf_emission(pt_minus),
+            _ => 0.0,
+        };
+        let qs_rev = match &qs {
+            Some(qs) => pt.pdf(camera, pt_minus, qs),
+            None => 0.0,
+        };
+        let qs_minus_rev = match (&qs, qs_minus) {
+            (Some(qs), Some(qs_minus)) => qs.pdf(camera, Some(&pt), qs_minus),
+            _ => 0.0,
+        };
+
+        let remap = |pdf: f64| if pdf != 0.0 { pdf } else { 1.0 };
+        let mut connections = 0.0;
+        let mut merges = 0.0;
+
+        // Moving the connection towards the camera. Merging at a vertex is eta
+        // times as likely as the light subpath reaching it, after connecting
+        // just before it. Lights themselves can't be merged at
+        let mut ri = 1.0;
+        for i in (1..t).rev() {
+            let (vertex, rev, fwd) = if i == t - 1 {
+                (&pt, pt_rev, pt.pdf_fwd)
+            } else if i == t - 2 {
+                (&camera_path[i], pt_minus_rev, camera_path[i].pdf_fwd)
+            } else {
+                (
+                    &camera_path[i],
+                    camera_path[i].pdf_rev,
+                    camera_path[i].pdf_fwd,
+                )
+            };
+            if s + t - 1 - i > 0 && vertex.can_merge() {
+                merges += ri * rev * self.eta;
+            }
+            ri *= remap(rev) / remap(fwd);
+            let delta = i != t - 1 && vertex.delta;
+            if !delta && !camera_path[i - 1].delta {
+                connections += ri;
+            }
+        }
+
+        // And towards the light
+        if let Some(qs) = qs {
+            ri = 1.0;
+            for i in (0..s).rev() {
+                let (vertex, rev, fwd) = if i == s - 1 {
+                    (&qs, qs_rev, qs.pdf_fwd)
+                } else if i == s - 2 {
+                    (&light_path[i], qs_minus_rev, light_path[i].pdf_fwd)
+                } else {
+                    (&light_path[i], light_path[i].pdf_rev, light_path[i].pdf_fwd)
+                };
+                ri *= remap(rev) / remap(fwd);
+                let delta = i != s - 1 && vertex.delta;
+                let before_delta = i > 0 && light_path[i - 1].delta;
+                if !delta && !before_delta {
+                    connections += ri;
+                }
+                if i > 0 && vertex.can_merge() {
+                    merges += ri * fwd * self.eta;
+                }
+            }
+        }
+
+        return (connections, merges);
+    }
+}
+
+impl Integrator for Bdpt {
+    fn radiance(
+        &self,
+        ray: &Ray,
+        scene: &Scene,
+        camera: &Camera,
+        _sampler: &mut dyn Sampler,
+        splats: &mut Vec<Splat>,
+    ) -> Vec3 {
+        let max_depth = self.max_depth as usize;
+
+        let mut camera_path = vec![Vertex::camera(camera, ray.orig)];
+        let pdf_dir = camera.pdf_dir(&ray.orig, &ray.dir);
+        let mut result = random_walk(
+            scene,
+            *ray,
+            Vec3::new(1.0, 1.0, 1.0),
+            pdf_dir,
+            max_depth + 2,
+            Transport::Radiance,
+            &mut camera_path,
+        );
+
+        let mut light_path = Vec::new();
+        generate_light_subpath(scene, max_depth + 1, &mut light_path);
+
+        let connector = Connector {
+            scene,
+            camera,
+            eta: 0.0,
+        };
+        for t in 1..=camera_path.len() {
+            for s in 0..=light_path.len() {
+                // Lights seen straight from the camera are left to the camera subpath
+                if s + t < 2 || (s == 1 && t == 1) || s + t - 2 > max_depth {
+                    continue;
+                }
+                result += connector.connect(&light_path, &camera_path, s, t, splats);
+            }
+        }
+        return result;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::integrator::{self, Film};
+    use crate::path_tracer::PathTracer;
+    use crate::sampler::RandomSampler;
+    use crate::scene;
+
+    fn average(film: &Film) -> f64 {
+        let sum: f64 = film.pixels.iter().map(|pixel| pixel.x).sum();
+        return sum / film.pixels.len() as f64;
+    }
+
+    #[test]
+    fn agrees_with_path_tracing() {
+        let scene = scene::lit_floor(true, true);
+        let camera = scene::lit_floor_camera();
+        let bdpt = Bdpt::new(10);
+        let tracer = PathTracer::new(10);
+        let found = integrator::render(&scene, &camera, &bdpt, &mut RandomSampler, 16, 16, 16);
+        let expected = integrator::render(&scene, &camera, &tracer, &mut RandomSampler, 16, 16, 64);
+        let (found, expected) = (average(&found), average(&expected));
+        assert!(
+            (found - expected).abs() < 0.04 * expected,
+            "got {} for {}",
+            found,
+            expected
+        );
+    }
+}
@@ -1,3 +1,6 @@
+// Explicit returns are the style used throughout the crate
+#![allow(clippy::needless_return)]
+
 use rand::Rng;
 use std::f64;
 use std::fs;
@@ -9,33 +12,22 @@ extern crate time;
 
 use time::PreciseTime;
 
+use raytracer::bdpt::Bdpt;
 use raytracer::camera::Camera;
 use raytracer::hitable::{Hitable, HitableList};
-use raytracer::material::{Dielectric, Lambertian, Metal};
-use raytracer::ray::Ray;
+use raytracer::integrator::{PixelRenderer, Renderer};
+use raytracer::light::{Light, LightList};
+use raytracer::material::{Dielectric, DiffuseLight, Lambertian, Metal};
+use raytracer::path_tracer::PathTracer;
+use raytracer::pssmlt::Pssmlt;
+use raytracer::sampler::RandomSampler;
+use raytracer::scene::Scene;
 use raytracer::sphere::Sphere;
+use raytracer::sppm::Sppm;
+use raytracer::vcm::Vcm;
 use raytracer::vec3::Vec3;
 
-fn color<T: Hitable>(ray: &Ray, world: &T, depth: i32) -> Vec3 {
-    match world.hit(ray, 0.001, f64::MAX) {
-        Some(rec) => {
-            if depth >= 50 {
-                return Vec3::new(0.0, 0.0, 0.0);
-            } else if let Some(scat) = rec.mat_ptr.scatter(ray, &rec) {
-                return color(&scat.out_ray, world, depth + 1) * scat.attenuation;
-            } else {
-                return Vec3::new(0.0, 0.0, 0.0);
-            }
-        }
-        None => {
-            let unit_dir = ray.dir.normalized();
-            let t: f64 = 0.5 * (unit_dir.y + 1.0);
-            return Vec3::new(1.0, 1.0, 1.0) * (1.0 - t) + Vec3::new(0.5, 0.7, 1.0) * t;
-        }
-    }
-}
-
-fn random_scene<'a>() -> HitableList<'a> {
+fn random_scene<'a>() -> Scene<'a> {
     let mut list: Vec<Box<dyn Hitable>> = Vec::new();
 
     // Floor
@@ -112,7 +104,16 @@ fn random_scene<'a>() -> HitableList<'a> {
         Rc::new(Metal::new(Vec3::new(0.7, 0.6, 0.5), 0.0)),
     )));
 
-    return HitableList { list };
+    // A sun high above, out of view, that gives the integrators which sample
+    // lights something to find besides the sky
+    let sun: Rc<dyn Light> = Rc::new(Sphere::new(
+        Vec3::new(-20.0, 40.0, 10.0),
+        5.0,
+        Rc::new(DiffuseLight::new(Vec3::new(15.0, 14.0, 13.0))),
+    ));
+    list.push(Box::new(sun.clone()));
+
+    return Scene::new(HitableList { list }, LightList::new(vec![sun]));
 }
 
 fn main() {
@@ -122,9 +123,38 @@ fn main() {
     let ny = 100u32;
     let ns = 30000u32;
 
-    let mut output = format!("P3\n{} {}\n255\n", nx, ny);
-
-    let world = random_scene();
+    // Spectral rendering handles dispersion and blackbody emitters properly,
+    // at the cost of extra color noise
+    let spectral = std::env::args().any(|arg| arg == "--spectral");
+    // Bidirectional path tracing finds caustics and indirect light much faster,
+    // but only works in RGB
+    let bdpt = std::env::args().any(|arg| arg == "--bdpt");
+    // Photon mapping, for caustics seen through mirrors and glass. Photons only
+    // leave the scene's lights, so the sky only lights what's seen through
+    // mirrors and glass
+    let sppm = std::env::args().any(|arg| arg == "--sppm");
+    // Metropolis light transport over the path tracer, for light that only gets
+    // through narrow openings
+    let pssmlt = std::env::args().any(|arg| arg == "--pssmlt");
+    // Vertex connection and merging, bidirectional path tracing combined with
+    // photon mapping
+    let vcm = std::env::args().any(|arg| arg == "--vcm");
+
+    let scene = random_scene();
+    let renderer: Box<dyn Renderer> = if bdpt {
+        Box::new(PixelRenderer::new(Box::new(Bdpt::new(50)), ns))
+    } else if sppm {
+        Box::new(Sppm::new(ns, nx * ny, 0.05))
+    } else if pssmlt {
+        Box::new(Pssmlt::new(PathTracer::new(50).with_spectral(spectral), ns))
+    } else if vcm {
+        Box::new(Vcm::new(ns, 0.05))
+    } else {
+        Box::new(PixelRenderer::new(
+            Box::new(PathTracer::new(50).with_spectral(spectral)),
+            ns,
+        ))
+    };
 
     let lookfrom = Vec3::new(12.0, 2.0, 2.9);
     let lookat = Vec3::new(0.0, 0.0, 0.0);
@@ -139,37 +169,11 @@ fn main() {
         (lookfrom - lookat).length(),
     );
 
-    let mut rng = rand::thread_rng();
-
-    for j in (0..=ny - 1).rev() {
-        for i in 0..nx {
-            let mut col = Vec3::new(0.0, 0.0, 0.0);
-
-            for _ in 0..ns {
-                let u = (i as f64 + rng.gen::<f64>()) / nx as f64;
-                let v = (j as f64 + rng.gen::<f64>()) / ny as f64;
-
-                let r = cam.get_ray(u, v);
-                //let p = r.point_at_parameter(2.0);
-                col += color(&r, &world, 0);
-            }
-
-            col /= ns as f64;
-            col.x = col.x.sqrt();
-            col.y = col.y.sqrt();
-            col.z = col.z.sqrt();
-
-            let ir = (255.99 * col.r()) as u8;
-            let ig = (255.99 * col.g()) as u8;
-            let ib = (255.99 * col.b()) as u8;
-
-            output = output + &format!("{} {} {}\n", ir, ig, ib);
-        }
-    }
+    let film = renderer.render(&scene, &cam, &mut RandomSampler, nx, ny);
 
     let end = PreciseTime::now();
     println!("{} seconds", start.to(end));
 
-    fs::write("test.ppm", output).expect("Failed to write");
+    fs::write("test.ppm", film.to_ppm()).expect("Failed to write");
     open::that("test.ppm").expect("Failed to open file");
 }
@@ -56,13 +56,86 @@ impl Camera {
         };
     }
 
-    pub fn get_ray(&self, s: f64, t: f64) -> Ray {
-        let rd = utils::random_in_unit_disk() * self.lens_radius;
-        let offset = self.u * rd.x + self.v * rd.y;
+    // How many pixels one world unit covers at p, for an image with image_height rows.
+    // Returns None if p is behind the camera
+    pub fn pixels_per_unit(&self, p: &Vec3, image_height: u32) -> Option<f64> {
+        let depth = (*p - self.origin).dot(&-self.w);
+        if depth <= 0.0 {
+            return None;
+        }
 
+        let plane_center = self.lower_left_corner + self.horizontal * 0.5 + self.vertical * 0.5;
+        let focus_dist = (plane_center - self.origin).length();
+        return Some(image_height as f64 * focus_dist / (self.vertical.length() * depth));
+    }
+
+    pub fn get_ray(&self, s: f64, t: f64) -> Ray {
+        let lens_point = self.sample_lens();
         return Ray::new(
-            self.origin + offset,
-            self.lower_left_corner + self.horizontal * s + self.vertical * t - self.origin - offset,
+            lens_point,
+            self.lower_left_corner + self.horizontal * s + self.vertical * t - lens_point,
         );
     }
+
+    // Uniform over the lens, with density 1 / lens_area()
+    pub fn sample_lens(&self) -> Vec3 {
+        let rd = utils::random_in_unit_disk() * self.lens_radius;
+        return self.origin + self.u * rd.x + self.v * rd.y;
+    }
+
+    // Taken as 1 for pinholes, so that densities over the lens come out as 1
+    pub fn lens_area(&self) -> f64 {
+        if self.lens_radius <= 0.0 {
+            return 1.0;
+        }
+        return std::f64::consts::PI * self.lens_radius * self.lens_radius;
+    }
+
+    // The (s, t) that get_ray() would take for a ray from lens_point through p,
+    // or None if that falls outside of the image
+    pub fn project(&self, lens_point: &Vec3, p: &Vec3) -> Option<(f64, f64)> {
+        let dir = *p - *lens_point;
+        let denom = dir.dot(&self.w);
+        if denom >= 0.0 {
+            return None;
+        }
+
+        let k = (self.lower_left_corner - *lens_point).dot(&self.w) / denom;
+        let on_plane = *lens_point + dir * k - self.lower_left_corner;
+        let s = on_plane.dot(&self.horizontal) / self.horizontal.squared_length();
+        let t = on_plane.dot(&self.vertical) / self.vertical.squared_length();
+        if !(0.0..1.0).contains(&s) || !(0.0..1.0).contains(&t) {
+            return None;
+        }
+        return Some((s, t));
+    }
+
+    // Area of the image at distance 1 from the lens
+    fn image_area(&self) -> f64 {
+        let plane_center = self.lower_left_corner + self.horizontal * 0.5 + self.vertical * 0.5;
+        let focus_dist = (plane_center - self.origin).dot(&-self.w);
+        return self.horizontal.length() * self.vertical.length() / (focus_dist * focus_dist);
+    }
+
+    // Solid angle density with which get_ray() at a uniformly random (s, t)
+    // leaves lens_point along dir
+    pub fn pdf_dir(&self, lens_point: &Vec3, dir: &Vec3) -> f64 {
+        let cos_theta = dir.normalized().dot(&-self.w);
+        if cos_theta <= 0.0 || self.project(lens_point, &(*lens_point + *dir)).is_none() {
+            return 0.0;
+        }
+        return 1.0 / (self.image_area() * cos_theta * cos_theta * cos_theta);
+    }
+
+    // How much a ray leaving lens_point along dir contributes to the image,
+    // normalized so that light traced to the camera adds up to the same
+    // brightness as camera rays
+    pub fn importance(&self, lens_point: &Vec3, dir: &Vec3) -> f64 {
+        let cos_theta = dir.normalized().dot(&-self.w);
+        if cos_theta <= 0.0 || self.project(lens_point, &(*lens_point + *dir)).is_none() {
+            return 0.0;
+        }
+        let cos2 = cos_theta * cos_theta;
+        return 1.0 / (self.image_area() * self.lens_area() * cos2 * cos2);
+    }
 }
@@ -0,0 +1,183 @@
+use std::f64::consts::PI;
+
+use crate::hitable::HitRecord;
+use crate::material::{Material, ScatteredRay};
+use crate::ray::Ray;
+use crate::utils;
+use crate::vec3::Vec3;
+
+// Sheen gets too sharp to sample reliably below this
+const MIN_SHEEN_ALPHA: f64 = 0.005;
+
+// Fabric: a "Charlie" sheen lobe from fibers sticking out of the surface
+// (Estevez and Kulla 2017), over a diffuse base that reflects back towards the
+// light at grazing angles (Burley 2012). Low roughness gives the tight rim
+// highlights of satin, high roughness the soft glow of velvet. Both lobes are
+// simply added, so like Principled it gains some energy at grazing angles
+pub struct Cloth {
+    pub base_color: Vec3,
+    pub sheen_color: Vec3,
+    pub roughness: f64,
+}
+
+impl Cloth {
+    pub fn new(base_color: Vec3, sheen_color: Vec3, roughness: f64) -> Cloth {
+        return Cloth {
+            base_color,
+            sheen_color,
+            roughness,
+        };
+    }
+
+    // Probability of sampling the diffuse lobe rather than the sheen
+    fn diffuse_probability(&self) -> f64 {
+        let diffuse = utils::luminance(&self.base_color).max(0.0);
+        let sheen = utils::luminance(&self.sheen_color).max(0.0);
+        if diffuse + sheen <= 0.0 {
+            return 0.5;
+        }
+        return (diffuse / (diffuse + sheen)).clamp(0.1, 0.9);
+    }
+
+    // 1 / alpha of the Charlie distribution
+    fn inv_alpha(&self) -> f64 {
+        return 1.0 / (self.roughness * self.roughness).max(MIN_SHEEN_ALPHA);
+    }
+
+    // Charlie distribution of the fibers' half vectors, in the local frame
+    fn sheen_d(&self, wh: &Vec3) -> f64 {
+        let inv_alpha = self.inv_alpha();
+        let sin_h = (1.0 - wh.z * wh.z).max(0.0).sqrt();
+        return (2.0 + inv_alpha) * sin_h.powf(inv_alpha) / (2.0 * PI);
+    }
+
+    // Half vector distributed as sheen_d() times its cosine. The cdf of sin(h)
+    // works out to sin(h)^(2 + 1 / alpha)
+    fn sample_sheen_half_vector(&self) -> Vec3 {
+        let sin_h = utils::random_double().powf(1.0 / (2.0 + self.inv_alpha()));
+        let cos_h = (1.0 - sin_h * sin_h).max(0.0).sqrt();
+        let phi = 2.0 * PI * utils::random_double();
+        return Vec3::new(sin_h * phi.cos(), sin_h * phi.sin(), cos_h);
+    }
+
+    // BRDF with both directions in the local shading frame, above the surface
+    fn eval_local(&self, wo: &Vec3, wi: &Vec3) -> Vec3 {
+        let wh = (*wo + *wi).normalized();
+        let cos_d = wi.dot(&wh);
+
+        let schlick_weight = |cosine: f64| (1.0 - cosine).clamp(0.0, 1.0).powi(5);
+        let fd90 = 0.5 + 2.0 * self.roughness * cos_d * cos_d;
+        let fl = 1.0 + (fd90 - 1.0) * schlick_weight(wi.z);
+        let fv = 1.0 + (fd90 - 1.0) * schlick_weight(wo.z);
+        let diffuse = self.base_color * (fl * fv / PI);
+
+        // Charlie distribution, with Neubelt and Pettineo's visibility term
+        let v = 1.0 / (4.0 * (wi.z + wo.z - wi.z * wo.z));
+        let sheen = self.sheen_color * (self.sheen_d(&wh) * v);
+
+        return diffuse + sheen;
+    }
+
+    // Density of scatter(), with both directions in the local shading frame.
+    // The sheen half vector is reflected about, hence the usual Jacobian
+    fn pdf_local(&self, wo: &Vec3, wi: &Vec3) -> f64 {
+        if wo.z <= 0.0 || wi.z <= 0.0 {
+            return 0.0;
+        }
+        let wh = (*wo + *wi).normalized();
+        let sheen = self.sheen_d(&wh) * wh.z / (4.0 * wo.dot(&wh));
+        let p_diffuse = self.diffuse_probability();
+        return p_diffuse * wi.z / PI + (1.0 - p_diffuse) * sheen;
+    }
+}
+
+impl Material for Cloth {
+    fn scatter(&self, r_in: &Ray, rec: &HitRecord) -> Option<ScatteredRay> {
+        let wo = rec.world_to_local(&-r_in.dir.normalized());
+        if wo.z <= 0.0 {
+            return None;
+        }
+
+        // One lobe is picked at random, and the result weighted by the density
+        // of the whole mixture, so either lobe can produce any direction
+        let p_diffuse = self.diffuse_probability();
+        let wi = if utils::random_double() < p_diffuse {
+            utils::random_cosine_direction()
+        } else {
+            utils::reflect(&-wo, &self.sample_sheen_half_vector())
+        };
+        if wi.z <= 0.0 {
+            return None;
+        }
+
+        return Some(ScatteredRay {
+            out_ray: r_in.spawn(rec.p, rec.local_to_world(&wi)),
+            attenuation: self.eval_local(&wo, &wi) * (wi.z / self.pdf_local(&wo, &wi)),
+        });
+    }
+
+    fn eval(&self, rec: &HitRecord, wi: &Vec3, wo: &Vec3) -> Vec3 {
+        let wo = rec.world_to_local(wo);
+        let wi = rec.world_to_local(wi);
+        if wo.z <= 0.0 || wi.z <= 0.0 {
+            return Vec3::new(0.0, 0.0, 0.0);
+        }
+        return self.eval_local(&wo, &wi);
+    }
+
+    fn pdf(&self, rec: &HitRecord, wi: &Vec3, wo: &Vec3) -> f64 {
+        return self.pdf_local(&rec.world_to_local(wo), &rec.world_to_local(wi));
+    }
+
+    fn is_delta(&self) -> bool {
+        return false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pdf_matches_the_samples() {
+        // The density integrates to however many samples end up above the
+        // surface, which is less than all of them at grazing angles
+        let cloth = Cloth::new(Vec3::new(0.2, 0.2, 0.2), Vec3::new(1.0, 1.0, 1.0), 0.5);
+        let wo = Vec3::new(0.8, 0.0, 0.6);
+        let rec = HitRecord {
+            t: 1.0,
+            p: Vec3::new(0.0, 0.0, 0.0),
+            normal: Vec3::new(0.0, 0.0, 1.0),
+            geometric_normal: Vec3::new(0.0, 0.0, 1.0),
+            tangent: Vec3::new(1.0, 0.0, 0.0),
+            handedness: 1.0,
+            u: 0.0,
+            v: 0.0,
+            mat_ptr: &cloth,
+            light: None,
+        };
+        let r_in = Ray::new(wo, -wo);
+
+        let n = 200000;
+        let mut scattered = 0;
+        let mut integral = 0.0;
+        for _ in 0..n {
+            if cloth.scatter(&r_in, &rec).is_some() {
+                scattered += 1;
+            }
+            let z = utils::random_double();
+            let r = (1.0 - z * z).max(0.0).sqrt();
+            let phi = 2.0 * PI * utils::random_double();
+            let wi = Vec3::new(r * phi.cos(), r * phi.sin(), z);
+            integral += cloth.pdf_local(&wo, &wi) * 2.0 * PI;
+        }
+        let fraction = scattered as f64 / n as f64;
+        let integral = integral / n as f64;
+        assert!(
+            (fraction - integral).abs() < 0.01,
+            "{} of the samples against {}",
+            fraction,
+            integral
+        );
+    }
+}
@@ -0,0 +1,58 @@
+use std::rc::Rc;
+
+use crate::hitable::HitRecord;
+use crate::material::{Material, ScatteredRay};
+use crate::ray::Ray;
+use crate::texture::Texture;
+
+#[derive(Debug, Copy, Clone)]
+pub enum CutoutMode {
+    // Surface exists wherever alpha >= threshold. Hard edges, no noise
+    Threshold(f64),
+    // Each hit is kept with probability alpha, which averages out into
+    // partially transparent edges over many samples
+    Stochastic,
+}
+
+// Adds an alpha mask to any material, for leaves, fences and the like. Masked out
+// hits are skipped during traversal, so nothing is scattered from them
+pub struct Cutout {
+    pub base: Rc<dyn Material>,
+    pub alpha: Rc<dyn Texture>,
+    pub mode: CutoutMode,
+}
+
+impl Cutout {
+    pub fn new(base: Rc<dyn Material>, alpha: Rc<dyn Texture>, mode: CutoutMode) -> Cutout {
+        return Cutout { base, alpha, mode };
+    }
+}
+
+impl Material for Cutout {
+    fn scatter(&self, r_in: &Ray, rec: &HitRecord) -> Option<ScatteredRay> {
+        return self.base.scatter(r_in, rec);
+    }
+
+    fn base(&self) -> Option<&dyn Material> {
+        return Some(self.base.as_ref());
+    }
+
+    fn has_cutout(&self) -> bool {
+        return true;
+    }
+
+    fn opacity(&self, rec: &HitRecord) -> f64 {
+        let alpha = self.alpha.scalar(rec.u, rec.v, &rec.p);
+        let opacity = match self.mode {
+            CutoutMode::Threshold(threshold) => {
+                if alpha >= threshold {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+            CutoutMode::Stochastic => alpha.clamp(0.0, 1.0),
+        };
+        return opacity * self.base.opacity(rec);
+    }
+}
@@ -0,0 +1,258 @@
+use std::f64::consts::PI;
+use std::ops::{Add, Div, Mul, Sub};
+use std::sync::OnceLock;
+
+use crate::spectrum;
+use crate::vec3::Vec3;
+
+// Exact Fresnel reflectance at the interface with a conductor of complex index
+// of refraction eta + ik, for unpolarized light (see pbrt's FrConductor)
+pub fn conductor_reflectance(cos_theta_i: f64, eta: f64, k: f64) -> f64 {
+    let cos2 = cos_theta_i.clamp(0.0, 1.0).powi(2);
+    let sin2 = 1.0 - cos2;
+    let eta2 = eta * eta;
+    let k2 = k * k;
+
+    let t0 = eta2 - k2 - sin2;
+    let a2_plus_b2 = (t0 * t0 + 4.0 * eta2 * k2).sqrt();
+    let t1 = a2_plus_b2 + cos2;
+    let a = (0.5 * (a2_plus_b2 + t0)).max(0.0).sqrt();
+    let t2 = 2.0 * cos_theta_i.clamp(0.0, 1.0) * a;
+    let r_s = (t1 - t2) / (t1 + t2);
+
+    let t3 = cos2 * a2_plus_b2 + sin2 * sin2;
+    let t4 = t2 * sin2;
+    let r_p = r_s * (t3 - t4) / (t3 + t4);
+
+    return 0.5 * (r_p + r_s);
+}
+
+// conductor_reflectance for each of the RGB channels
+pub fn conductor_reflectance_rgb(cos_theta_i: f64, eta: &Vec3, k: &Vec3) -> Vec3 {
+    return Vec3::new(
+        conductor_reflectance(cos_theta_i, eta.x, k.x),
+        conductor_reflectance(cos_theta_i, eta.y, k.y),
+        conductor_reflectance(cos_theta_i, eta.z, k.z),
+    );
+}
+
+// Exact Fresnel reflectance for unpolarized light hitting a dielectric
+// interface. eta is the IOR of the far side over the IOR of the near side and
+// cos_theta_i is measured on the near side. Returns 1 under total internal
+// reflection
+pub fn dielectric_reflectance(cos_theta_i: f64, eta: f64) -> f64 {
+    let cos_i = cos_theta_i.clamp(0.0, 1.0);
+    let sin2_t = (1.0 - cos_i * cos_i) / (eta * eta);
+    if sin2_t >= 1.0 {
+        return 1.0;
+    }
+
+    let cos_t = (1.0 - sin2_t).sqrt();
+    let r_parallel = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    let r_perpendicular = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    return 0.5 * (r_parallel * r_parallel + r_perpendicular * r_perpendicular);
+}
+
+// Just enough complex arithmetic for the thin film equations
+#[derive(Debug, Copy, Clone)]
+struct Complex {
+    re: f64,
+    im: f64,
+}
+
+impl Complex {
+    fn new(re: f64, im: f64) -> Complex {
+        return Complex { re, im };
+    }
+
+    fn real(re: f64) -> Complex {
+        return Complex::new(re, 0.0);
+    }
+
+    fn norm_sqr(&self) -> f64 {
+        return self.re * self.re + self.im * self.im;
+    }
+
+    // Principal square root
+    fn sqrt(&self) -> Complex {
+        let norm = self.norm_sqr().sqrt();
+        let re = (0.5 * (norm + self.re)).max(0.0).sqrt();
+        let im = (0.5 * (norm - self.re)).max(0.0).sqrt();
+        return Complex::new(re, if self.im < 0.0 { -im } else { im });
+    }
+
+    // e^(i * self)
+    fn exp_i(&self) -> Complex {
+        let magnitude = (-self.im).exp();
+        return Complex::new(magnitude * self.re.cos(), magnitude * self.re.sin());
+    }
+}
+
+impl Add for Complex {
+    type Output = Complex;
+
+    fn add(self, other: Complex) -> Complex {
+        return Complex::new(self.re + other.re, self.im + other.im);
+    }
+}
+
+impl Sub for Complex {
+    type Output = Complex;
+
+    fn sub(self, other: Complex) -> Complex {
+        return Complex::new(self.re - other.re, self.im - other.im);
+    }
+}
+
+impl Mul for Complex {
+    type Output = Complex;
+
+    fn mul(self, other: Complex) -> Complex {
+        return Complex::new(
+            self.re * other.re - self.im * other.im,
+            self.re * other.im + self.im * other.re,
+        );
+    }
+}
+
+impl Div for Complex {
+    type Output = Complex;
+
+    fn div(self, other: Complex) -> Complex {
+        let denominator = other.norm_sqr();
+        return Complex::new(
+            (self.re * other.re + self.im * other.im) / denominator,
+            (self.im * other.re - self.re * other.im) / denominator,
+        );
+    }
+}
+
+// Cosine of the refracted angle going from n_i into n_t, by Snell's law.
+// Complex for absorbing media and past the critical angle
+fn cos_transmitted(n_i: Complex, cos_i: Complex, n_t: Complex) -> Complex {
+    let one = Complex::real(1.0);
+    let ratio = n_i / n_t;
+    let sin2_t = ratio * ratio * (one - cos_i * cos_i);
+    return (one - sin2_t).sqrt();
+}
+
+// Fresnel amplitude reflection coefficients (s, p) going from n_i into n_t
+fn amplitude_reflection(
+    n_i: Complex,
+    cos_i: Complex,
+    n_t: Complex,
+    cos_t: Complex,
+) -> (Complex, Complex) {
+    let r_s = (n_i * cos_i - n_t * cos_t) / (n_i * cos_i + n_t * cos_t);
+    let r_p = (n_t * cos_i - n_i * cos_t) / (n_t * cos_i + n_i * cos_t);
+    return (r_s, r_p);
+}
+
+// Wavelengths and normalized RGB weights used to integrate thin film
+// reflectance when a ray doesn't carry a wavelength of its own
+const THIN_FILM_RGB_SAMPLES: usize = 16;
+
+fn thin_film_rgb_weights() -> &'static [(f64, Vec3); THIN_FILM_RGB_SAMPLES] {
+    static WEIGHTS: OnceLock<[(f64, Vec3); THIN_FILM_RGB_SAMPLES]> = OnceLock::new();
+    return WEIGHTS.get_or_init(|| {
+        let step = (spectrum::LAMBDA_MAX - spectrum::LAMBDA_MIN) / THIN_FILM_RGB_SAMPLES as f64;
+        let mut weights = [(0.0, Vec3::new(0.0, 0.0, 0.0)); THIN_FILM_RGB_SAMPLES];
+        let mut total = Vec3::new(0.0, 0.0, 0.0);
+        for (i, (lambda, weight)) in weights.iter_mut().enumerate() {
+            *lambda = spectrum::LAMBDA_MIN + (i as f64 + 0.5) * step;
+            *weight = spectrum::wavelength_to_rgb_weight(*lambda);
+            total += *weight;
+        }
+
+        // So that a flat reflectance comes out as the same gray
+        for (_, weight) in weights.iter_mut() {
+            *weight /= total;
+        }
+        return weights;
+    });
+}
+
+// Value of per channel data such as NamedMetal::ior() at a given wavelength,
+// interpolating between the 650, 550 and 450nm samples
+pub fn channel_at(rgb: &Vec3, lambda: f64) -> f64 {
+    if lambda >= 550.0 {
+        let t = ((lambda - 550.0) / 100.0).min(1.0);
+        return rgb.y + (rgb.x - rgb.y) * t;
+    }
+    let t = ((550.0 - lambda) / 100.0).min(1.0);
+    return rgb.y + (rgb.z - rgb.y) * t;
+}
+
+// Thin transparent layer on top of a surface, like soap, oil or a lens
+// coating. Light bouncing between its two sides interferes with itself, which
+// makes reflectance depend on wavelength and angle
+#[derive(Debug, Copy, Clone)]
+pub struct ThinFilm {
+    // In nanometers
+    pub thickness: f64,
+    pub ior: f64,
+}
+
+impl ThinFilm {
+    pub fn new(thickness: f64, ior: f64) -> ThinFilm {
+        return ThinFilm { thickness, ior };
+    }
+
+    // Reflectance at wavelength lambda of the film over a substrate of complex
+    // IOR eta + ik, for unpolarized light arriving from a medium of IOR
+    // n_outside (Airy summation, see Born & Wolf 7.6). Use k = 0 for dielectrics
+    pub fn reflectance(
+        &self,
+        cos_theta_i: f64,
+        n_outside: f64,
+        substrate_eta: f64,
+        substrate_k: f64,
+        lambda: f64,
+    ) -> f64 {
+        let n_1 = Complex::real(n_outside);
+        let n_2 = Complex::real(self.ior);
+        let n_3 = Complex::new(substrate_eta, substrate_k);
+
+        let cos_1 = Complex::real(cos_theta_i.clamp(0.0, 1.0));
+        let cos_2 = cos_transmitted(n_1, cos_1, n_2);
+        let cos_3 = cos_transmitted(n_2, cos_2, n_3);
+
+        let (r12_s, r12_p) = amplitude_reflection(n_1, cos_1, n_2, cos_2);
+        let (r23_s, r23_p) = amplitude_reflection(n_2, cos_2, n_3, cos_3);
+
+        // Phase difference picked up by a round trip through the film
+        let phase = Complex::real(4.0 * PI * self.thickness / lambda) * n_2 * cos_2;
+        let shift = phase.exp_i();
+
+        let one = Complex::real(1.0);
+        let r_s = (r12_s + r23_s * shift) / (one + r12_s * r23_s * shift);
+        let r_p = (r12_p + r23_p * shift) / (one + r12_p * r23_p * shift);
+        return (0.5 * (r_s.norm_sqr() + r_p.norm_sqr())).clamp(0.0, 1.0);
+    }
+
+    // reflectance() integrated over the visible spectrum into RGB. The
+    // substrate's eta and k are per channel, as in conductor_reflectance_rgb
+    pub fn reflectance_rgb(
+        &self,
+        cos_theta_i: f64,
+        n_outside: f64,
+        substrate_eta: &Vec3,
+        substrate_k: &Vec3,
+    ) -> Vec3 {
+        let mut result = Vec3::new(0.0, 0.0, 0.0);
+        for (lambda, weight) in thin_film_rgb_weights() {
+            let reflectance = self.reflectance(
+                cos_theta_i,
+                n_outside,
+                channel_at(substrate_eta, *lambda),
+                channel_at(substrate_k, *lambda),
+                *lambda,
+            );
+            result += *weight * reflectance;
+        }
+
+        // Negative lobes of the color matching functions can push saturated
+        // colors slightly out of gamut
+        return result.max(&Vec3::new(0.0, 0.0, 0.0));
+    }
+}
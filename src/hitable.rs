@@ -1,16 +1,69 @@
+use std::rc::Rc;
+
+use crate::light::Light;
 use crate::material::Material;
 use crate::ray::Ray;
 use crate::vec3::Vec3;
 
+#[derive(Clone, Copy)]
 pub struct HitRecord<'a> {
     pub t: f64,
     pub p: Vec3,
+    // Shading normal, which is what materials should scatter around. It can be
+    // interpolated or perturbed by normal maps
     pub normal: Vec3,
-    pub mat_ptr: &'a Material,
+    // Normal of the actual surface, always on the same side as normal
+    pub geometric_normal: Vec3,
+    // Unit vector perpendicular to normal, pointing along increasing u
+    pub tangent: Vec3,
+    // 1 if normal x tangent points along increasing v, -1 if the UVs are
+    // mirrored and it points the other way. See bitangent()
+    pub handedness: f64,
+    // Surface parametrization, used for texture lookups
+    pub u: f64,
+    pub v: f64,
+    pub mat_ptr: &'a dyn Material,
+    // The shape that was hit, if it's one that can be sampled as a light, so
+    // that integrators can tell which light a path found
+    pub light: Option<&'a dyn Light>,
+}
+
+impl<'a> HitRecord<'a> {
+    // Unit vector perpendicular to normal and tangent, pointing along increasing v
+    pub fn bitangent(&self) -> Vec3 {
+        return self.normal.cross(&self.tangent) * self.handedness;
+    }
+
+    // Converts a direction given in the (tangent, bitangent, normal) frame
+    pub fn local_to_world(&self, local: &Vec3) -> Vec3 {
+        return self.tangent * local.x + self.bitangent() * local.y + self.normal * local.z;
+    }
+
+    pub fn world_to_local(&self, dir: &Vec3) -> Vec3 {
+        return Vec3::new(
+            dir.dot(&self.tangent),
+            dir.dot(&self.bitangent()),
+            dir.dot(&self.normal),
+        );
+    }
+
+    // A direction that the shading normal puts on one side of the surface and the
+    // geometric normal on the other would leak light through it
+    pub fn is_consistent(&self, dir: &Vec3) -> bool {
+        return dir.dot(&self.normal) * dir.dot(&self.geometric_normal) > 0.0;
+    }
 }
 
 pub trait Hitable {
-    fn hit(&self, r: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord>;
+    fn hit(&self, r: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>>;
+}
+
+// Lets a shape be shared between the world and other places, like a list of
+// lights
+impl<T: Hitable + ?Sized> Hitable for Rc<T> {
+    fn hit(&self, r: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        return (**self).hit(r, t_min, t_max);
+    }
 }
 
 pub struct HitableList<'a> {
@@ -18,7 +71,7 @@ pub struct HitableList<'a> {
 }
 
 impl<'a> Hitable for HitableList<'a> {
-    fn hit(&self, r: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+    fn hit(&self, r: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
         let mut temp_rec = None;
         let mut closest_so_far = t_max;
 
@@ -0,0 +1,154 @@
+use crate::camera::Camera;
+use crate::ray::Ray;
+use crate::sampler::Sampler;
+use crate::scene::Scene;
+use crate::vec3::Vec3;
+
+// Light that lands on some other point of the image than the camera ray being
+// traced, e.g. from paths traced from the lights. s and t are image coordinates
+// like Camera::get_ray() takes
+pub struct Splat {
+    pub s: f64,
+    pub t: f64,
+    pub color: Vec3,
+}
+
+// A way of computing how much light arrives at the camera
+pub trait Integrator {
+    // Estimate of the light arriving along a camera ray, in linear RGB. Light
+    // found for other points of the image goes into splats, which get added
+    // on top with the same weight as one camera ray per pixel
+    fn radiance(
+        &self,
+        ray: &Ray,
+        scene: &Scene,
+        camera: &Camera,
+        sampler: &mut dyn Sampler,
+        splats: &mut Vec<Splat>,
+    ) -> Vec3;
+}
+
+// A way of rendering a whole image at once, for methods that don't estimate
+// one camera ray at a time, like photon mapping or Metropolis
+pub trait Renderer {
+    fn render(
+        &self,
+        scene: &Scene,
+        camera: &Camera,
+        sampler: &mut dyn Sampler,
+        width: u32,
+        height: u32,
+    ) -> Film;
+}
+
+// Renders with an Integrator, through render() below
+pub struct PixelRenderer {
+    pub integrator: Box<dyn Integrator>,
+    pub samples_per_pixel: u32,
+}
+
+impl PixelRenderer {
+    pub fn new(integrator: Box<dyn Integrator>, samples_per_pixel: u32) -> PixelRenderer {
+        return PixelRenderer {
+            integrator,
+            samples_per_pixel,
+        };
+    }
+}
+
+impl Renderer for PixelRenderer {
+    fn render(
+        &self,
+        scene: &Scene,
+        camera: &Camera,
+        sampler: &mut dyn Sampler,
+        width: u32,
+        height: u32,
+    ) -> Film {
+        return render(
+            scene,
+            camera,
+            self.integrator.as_ref(),
+            sampler,
+            width,
+            height,
+            self.samples_per_pixel,
+        );
+    }
+}
+
+// Linear RGB pixels, stored row by row from the top left corner
+pub struct Film {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<Vec3>,
+}
+
+impl Film {
+    pub fn new(width: u32, height: u32) -> Film {
+        return Film {
+            width,
+            height,
+            pixels: vec![Vec3::new(0.0, 0.0, 0.0); (width * height) as usize],
+        };
+    }
+
+    pub fn pixel(&self, x: u32, y: u32) -> Vec3 {
+        return self.pixels[(y * self.width + x) as usize];
+    }
+
+    pub fn add(&mut self, x: u32, y: u32, color: &Vec3) {
+        self.pixels[(y * self.width + x) as usize] += *color;
+    }
+
+    pub fn add_splat(&mut self, splat: &Splat, weight: f64) {
+        let x = ((splat.s * self.width as f64) as u32).min(self.width - 1);
+        let row = ((splat.t * self.height as f64) as u32).min(self.height - 1);
+        self.add(x, self.height - 1 - row, &(splat.color * weight));
+    }
+
+    // Plain text PPM, with a gamma of 2 and anything brighter than 1 clipped
+    pub fn to_ppm(&self) -> String {
+        let mut output = format!("P3\n{} {}\n255\n", self.width, self.height);
+        for col in self.pixels.iter() {
+            let ir = (255.99 * col.r().max(0.0).sqrt()) as u8;
+            let ig = (255.99 * col.g().max(0.0).sqrt()) as u8;
+            let ib = (255.99 * col.b().max(0.0).sqrt()) as u8;
+            output += &format!("{} {} {}\n", ir, ig, ib);
+        }
+        return output;
+    }
+}
+
+// Averages samples_per_pixel camera rays through random points of each pixel
+pub fn render(
+    scene: &Scene,
+    camera: &Camera,
+    integrator: &dyn Integrator,
+    sampler: &mut dyn Sampler,
+    width: u32,
+    height: u32,
+    samples_per_pixel: u32,
+) -> Film {
+    let mut film = Film::new(width, height);
+    let mut splats = Vec::new();
+    for y in 0..height {
+        // The camera's t goes up, while film rows go down
+        let j = height - 1 - y;
+        for x in 0..width {
+            let mut col = Vec3::new(0.0, 0.0, 0.0);
+            for _ in 0..samples_per_pixel {
+                let (du, dv) = sampler.next_2d();
+                let u = (x as f64 + du) / width as f64;
+                let v = (j as f64 + dv) / height as f64;
+                let ray = camera.get_ray(u, v);
+                col += integrator.radiance(&ray, scene, camera, sampler, &mut splats);
+                for splat in splats.drain(..) {
+                    film.add_splat(&splat, 1.0 / samples_per_pixel as f64);
+                }
+            }
+            film.add(x, y, &(col / samples_per_pixel as f64));
+        }
+    }
+    return film;
+}
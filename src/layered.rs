@@ -0,0 +1,154 @@
+use std::rc::Rc;
+
+use crate::hitable::HitRecord;
+use crate::material::{Material, ScatteredRay};
+use crate::microfacet::GgxDistribution;
+use crate::ray::Ray;
+use crate::rough_dielectric;
+use crate::utils;
+use crate::vec3::Vec3;
+
+// Bounces between the coat and the base before Russian roulette kicks in
+const MIN_LAYER_BOUNCES: u32 = 3;
+
+// Highest odds of going on with Russian roulette, so that paths stuck between
+// a white base and the coat still end
+const MAX_SURVIVAL: f64 = 0.95;
+
+// A dielectric coating over any other material, like lacquer over wood or the
+// clear coat of car paint. Light is traced stochastically through the coat,
+// bouncing between its top and the base as many times as it takes to get out,
+// so the combination conserves energy without a dedicated BSDF. Long paths
+// inside the coat end through Russian roulette, which keeps that unbiased.
+//
+// Without a BSDF there's no eval() or pdf() either, so Layered counts as
+// delta: the path tracer doesn't sample lights from it, and BDPT, VCM and SPPM
+// don't connect or merge at it. Light only reaches it through scatter().
+//
+// The coat is treated as infinitely thin in terms of position: light enters
+// and leaves at the hit point, and thickness only affects absorption
+pub struct Layered {
+    pub base: Rc<dyn Material>,
+    pub ior: f64,
+    pub distribution: GgxDistribution,
+    pub thickness: f64,
+    // Absorption coefficient per unit distance travelled inside the coat
+    pub absorption: Vec3,
+}
+
+impl Layered {
+    pub fn new(base: Rc<dyn Material>, ior: f64, roughness: f64) -> Layered {
+        return Layered {
+            base,
+            ior,
+            distribution: GgxDistribution::isotropic(roughness),
+            thickness: 0.0,
+            absorption: Vec3::new(0.0, 0.0, 0.0),
+        };
+    }
+
+    // Tinted coat that lets through transmittance of the light crossing it
+    // straight down, thickness being how thick it is in scene units
+    pub fn with_transmittance(mut self, transmittance: Vec3, thickness: f64) -> Layered {
+        self.thickness = thickness;
+        self.absorption = utils::absorption_from_transmittance(&transmittance, thickness);
+        return self;
+    }
+
+    // Absorption for crossing the coat once with direction w, in the local frame
+    fn crossing_transmittance(&self, w: &Vec3) -> Vec3 {
+        if self.thickness <= 0.0 {
+            return Vec3::new(1.0, 1.0, 1.0);
+        }
+        return utils::beer_lambert(&self.absorption, self.thickness / w.z.abs().max(1e-4));
+    }
+}
+
+impl Material for Layered {
+    fn scatter(&self, r_in: &Ray, rec: &HitRecord) -> Option<ScatteredRay> {
+        let wo = rec.world_to_local(&-r_in.dir.normalized());
+        if wo.z <= 0.0 {
+            return None;
+        }
+
+        // Into the coat from the outside
+        let outside_ior = r_in.media.ior(r_in.wavelength);
+        let (mut w, reflecting, weight) = rough_dielectric::sample_rough_interface(
+            &self.distribution,
+            &wo,
+            self.ior / outside_ior,
+        )?;
+        if reflecting {
+            return Some(ScatteredRay {
+                out_ray: r_in.spawn(rec.p, rec.local_to_world(&w)),
+                attenuation: Vec3::new(weight, weight, weight),
+            });
+        }
+
+        let mut attenuation = Vec3::new(weight, weight, weight);
+        let mut bounces = 0;
+        loop {
+            // Down through the coat and off the base. Anything the base lets
+            // through just leaves from underneath
+            attenuation *= self.crossing_transmittance(&w);
+            let incoming = r_in.spawn(rec.p - rec.local_to_world(&w), rec.local_to_world(&w));
+            let scattered = self.base.scatter(&incoming, rec)?;
+            attenuation *= scattered.attenuation;
+            let ray = scattered.out_ray;
+
+            let up = rec.world_to_local(&ray.dir.normalized());
+            if up.z <= 0.0 {
+                return Some(ScatteredRay {
+                    out_ray: ray,
+                    attenuation,
+                });
+            }
+
+            // Back up to the top of the coat, where the ray either leaves or gets
+            // reflected down again. The frame is flipped so that the interface is
+            // seen from above, and flipped back afterwards
+            attenuation *= self.crossing_transmittance(&up);
+            let (wi, reflecting, weight) = rough_dielectric::sample_rough_interface(
+                &self.distribution,
+                &up,
+                outside_ior / self.ior,
+            )?;
+            attenuation = attenuation * weight;
+            if !reflecting {
+                return Some(ScatteredRay {
+                    out_ray: ray.spawn(rec.p, rec.local_to_world(&-wi)),
+                    attenuation,
+                });
+            }
+            w = -wi;
+
+            bounces += 1;
+            if bounces > MIN_LAYER_BOUNCES {
+                let a = attenuation;
+                let survival = a.x.max(a.y).max(a.z).min(MAX_SURVIVAL);
+                if survival <= 0.0 || utils::random_double() >= survival {
+                    return None;
+                }
+                attenuation /= survival;
+            }
+        }
+    }
+
+    fn base(&self) -> Option<&dyn Material> {
+        return Some(self.base.as_ref());
+    }
+
+    // The coat changes how light scatters off the base, so the base's eval()
+    // and pdf() don't describe the result, and Layered stays delta
+    fn eval(&self, _rec: &HitRecord, _wi: &Vec3, _wo: &Vec3) -> Vec3 {
+        return Vec3::new(0.0, 0.0, 0.0);
+    }
+
+    fn pdf(&self, _rec: &HitRecord, _wi: &Vec3, _wo: &Vec3) -> f64 {
+        return 0.0;
+    }
+
+    fn is_delta(&self) -> bool {
+        return true;
+    }
+}
@@ -4,10 +4,42 @@
 
 // Rust will look for a vec3.rs file, or a vec3/mod.rs file
 
+// Explicit returns are the style used throughout the crate
+#![allow(clippy::needless_return)]
+
+pub mod aabb;
+pub mod bdpt;
 pub mod camera;
+pub mod cloth;
+pub mod conductor;
+pub mod cutout;
+pub mod fresnel;
 pub mod hitable;
+pub mod integrator;
+pub mod layered;
+pub mod light;
 pub mod material;
+pub mod measured;
+pub mod medium;
+pub mod mesh;
+pub mod microfacet;
+pub mod mix;
+pub mod normal_map;
+pub mod path_tracer;
+pub mod point_grid;
+pub mod principled;
+pub mod pssmlt;
+pub mod quad;
 pub mod ray;
+pub mod rough_dielectric;
+pub mod sampler;
+pub mod scene;
+pub mod spectrum;
 pub mod sphere;
+pub mod sppm;
+pub mod subdivision;
+pub mod subsurface;
+pub mod texture;
 pub mod utils;
+pub mod vcm;
 pub mod vec3;
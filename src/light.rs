@@ -0,0 +1,259 @@
+use std::f64::consts::PI;
+use std::rc::Rc;
+
+use crate::hitable::{HitRecord, Hitable};
+use crate::utils;
+use crate::vec3::Vec3;
+
+// A point picked on a light, as seen from some origin
+pub struct LightSample<'a> {
+    // The point itself, with the light's material for what it emits
+    pub rec: HitRecord<'a>,
+    // Solid angle density of picking it, as seen from the origin
+    pub pdf: f64,
+}
+
+// Where and which way light leaves the lights, for paths traced from them
+pub struct EmissionSample<'a> {
+    pub rec: HitRecord<'a>,
+    // Unit vector, cosine distributed about either side of the surface
+    pub dir: Vec3,
+    // Density per unit area of picking rec
+    pub pdf_pos: f64,
+    // Solid angle density of picking dir, see emission_pdf()
+    pub pdf_dir: f64,
+}
+
+impl<'a> EmissionSample<'a> {
+    // Light leaving along dir over the densities of picking it, which is what a
+    // path traced from it starts out carrying
+    pub fn weight(&self) -> Vec3 {
+        let cosine = self.rec.geometric_normal.dot(&self.dir).abs();
+        return self.rec.mat_ptr.emitted(&self.rec) * (cosine / (self.pdf_pos * self.pdf_dir));
+    }
+}
+
+// Solid angle density of LightList::sample_emission() leaving a light with the
+// given normal along dir
+pub fn emission_pdf(normal: &Vec3, dir: &Vec3) -> f64 {
+    return normal.dot(dir).abs() / (2.0 * PI);
+}
+
+// Shapes that integrators can sample points on directly, rather than waiting
+// for scattered rays to find them by chance
+pub trait Light: Hitable {
+    fn sample(&self, origin: &Vec3) -> Option<LightSample<'_>>;
+
+    // Solid angle density of sample() returning rec, a point on this light
+    fn pdf(&self, origin: &Vec3, rec: &HitRecord) -> f64;
+
+    // Uniform over the surface, for paths that start on the light
+    fn sample_area(&self) -> Option<HitRecord<'_>>;
+
+    fn area(&self) -> f64;
+}
+
+// Weight for a sample taken with density pdf_f, when another technique could
+// have produced it with density pdf_g (Veach 1997)
+pub fn power_heuristic(pdf_f: f64, pdf_g: f64) -> f64 {
+    let f2 = pdf_f * pdf_f;
+    let g2 = pdf_g * pdf_g;
+    if f2 + g2 <= 0.0 {
+        return 0.0;
+    }
+    return f2 / (f2 + g2);
+}
+
+// The lights of a scene. They should be in the world as well, which taking
+// them through Rc makes possible without copies
+pub struct LightList {
+    pub lights: Vec<Rc<dyn Light>>,
+}
+
+impl LightList {
+    pub fn new(lights: Vec<Rc<dyn Light>>) -> LightList {
+        return LightList { lights };
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.lights.is_empty();
+    }
+
+    // Picks one of the lights uniformly, then a point on it
+    pub fn sample(&self, origin: &Vec3) -> Option<LightSample<'_>> {
+        if self.lights.is_empty() {
+            return None;
+        }
+        let index = utils::random_index(self.lights.len());
+        let mut sample = self.lights[index].sample(origin)?;
+        sample.pdf /= self.lights.len() as f64;
+        return Some(sample);
+    }
+
+    // The light that rec is on, if it's one of these. Shapes are told apart by
+    // address, so lights that touch or overlap can't be mistaken for another
+    fn find<'a>(&self, rec: &HitRecord<'a>) -> Option<&'a dyn Light> {
+        let light = rec.light?;
+        let address = light as *const dyn Light as *const ();
+        if self
+            .lights
+            .iter()
+            .any(|l| Rc::as_ptr(l) as *const () == address)
+        {
+            return Some(light);
+        }
+        return None;
+    }
+
+    // Density with which sample() would have picked rec, a point found by
+    // tracing a ray from origin. Zero if it isn't on any of the lights
+    pub fn pdf(&self, origin: &Vec3, rec: &HitRecord) -> f64 {
+        match self.find(rec) {
+            Some(light) => return light.pdf(origin, rec) / self.lights.len() as f64,
+            None => return 0.0,
+        }
+    }
+
+    // Picks one of the lights uniformly, then a point uniformly over its
+    // surface. Returns that point and its density per unit area
+    pub fn sample_area(&self) -> Option<(HitRecord<'_>, f64)> {
+        if self.lights.is_empty() {
+            return None;
+        }
+        let index = utils::random_index(self.lights.len());
+        let light = &self.lights[index];
+        let rec = light.sample_area()?;
+        return Some((rec, 1.0 / (light.area() * self.lights.len() as f64)));
+    }
+
+    // A point from sample_area(), and a direction leaving it. Lights emit from
+    // both sides, so either side gets picked half the time
+    pub fn sample_emission(&self) -> Option<EmissionSample<'_>> {
+        let (rec, pdf_pos) = self.sample_area()?;
+        let local = utils::random_cosine_direction();
+        let (b1, b2) = utils::orthonormal_basis(&rec.geometric_normal);
+        let mut dir = b1 * local.x + b2 * local.y + rec.geometric_normal * local.z;
+        if utils::random_double() < 0.5 {
+            dir = -dir;
+        }
+        let pdf_dir = emission_pdf(&rec.geometric_normal, &dir);
+        if pdf_dir <= 0.0 {
+            return None;
+        }
+        return Some(EmissionSample {
+            rec,
+            dir,
+            pdf_pos,
+            pdf_dir,
+        });
+    }
+
+    // Density per unit area with which sample_area() would have picked rec, zero
+    // if it isn't on any of the lights
+    pub fn pdf_area(&self, rec: &HitRecord) -> f64 {
+        match self.find(rec) {
+            Some(light) => return 1.0 / (light.area() * self.lights.len() as f64),
+            None => return 0.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::{DiffuseLight, Lambertian};
+    use crate::quad::Quad;
+    use crate::ray::Ray;
+    use crate::sphere::Sphere;
+
+    fn quad_light() -> LightList {
+        let quad: Rc<dyn Light> = Rc::new(Quad::new(
+            Vec3::new(-1.0, 1.0, -1.0),
+            Vec3::new(2.0, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, 2.0),
+            Rc::new(DiffuseLight::new(Vec3::new(1.0, 1.0, 1.0))),
+        ));
+        return LightList::new(vec![quad]);
+    }
+
+    fn sphere_light() -> LightList {
+        let sphere: Rc<dyn Light> = Rc::new(Sphere::new(
+            Vec3::new(0.0, 3.0, 0.0),
+            1.0,
+            Rc::new(DiffuseLight::new(Vec3::new(1.0, 1.0, 1.0))),
+        ));
+        return LightList::new(vec![sphere]);
+    }
+
+    // Averages 1 / pdf over samples, which comes out as the solid angle the
+    // lights cover if the densities are right
+    fn solid_angle(lights: &LightList, origin: &Vec3) -> f64 {
+        let n = 20000;
+        let mut sum = 0.0;
+        for _ in 0..n {
+            sum += 1.0 / lights.sample(origin).unwrap().pdf;
+        }
+        return sum / n as f64;
+    }
+
+    #[test]
+    fn quad_samples_cover_its_solid_angle() {
+        // A 2 x 2 square one unit above its center covers 4 asin(1 / 2)
+        let expected = 4.0 * (0.5_f64).asin();
+        let found = solid_angle(&quad_light(), &Vec3::new(0.0, 0.0, 0.0));
+        assert!((found - expected).abs() < 0.03, "got {}", found);
+    }
+
+    #[test]
+    fn sphere_samples_cover_its_solid_angle() {
+        let expected = 2.0 * PI * (1.0 - (1.0 - 1.0 / 9.0_f64).sqrt());
+        let found = solid_angle(&sphere_light(), &Vec3::new(0.0, 0.0, 0.0));
+        assert!((found - expected).abs() < 1e-9, "got {}", found);
+    }
+
+    #[test]
+    fn pdf_matches_the_samples() {
+        let origin = Vec3::new(0.3, 0.0, -0.2);
+        for lights in [quad_light(), sphere_light()].iter() {
+            for _ in 0..100 {
+                let sample = lights.sample(&origin).unwrap();
+                let pdf = lights.pdf(&origin, &sample.rec);
+                assert!((pdf - sample.pdf).abs() < 1e-6 * sample.pdf);
+            }
+        }
+    }
+
+    #[test]
+    fn points_off_the_lights_have_zero_pdf() {
+        let floor = Quad::new(
+            Vec3::new(-10.0, 0.0, 10.0),
+            Vec3::new(20.0, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, -20.0),
+            Rc::new(Lambertian::new(Vec3::new(0.5, 0.5, 0.5))),
+        );
+        let origin = Vec3::new(0.0, 0.5, 0.0);
+        let ray = Ray::new(origin, Vec3::new(0.2, -1.0, 0.1));
+        let rec = floor.hit(&ray, 0.001, f64::MAX).unwrap();
+        assert_eq!(quad_light().pdf(&origin, &rec), 0.0);
+        assert_eq!(sphere_light().pdf(&origin, &rec), 0.0);
+    }
+
+    #[test]
+    fn lights_are_told_apart_from_shapes_in_the_same_place() {
+        let lights = quad_light();
+        let copy = Quad::new(
+            Vec3::new(-1.0, 1.0, -1.0),
+            Vec3::new(2.0, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, 2.0),
+            Rc::new(DiffuseLight::new(Vec3::new(1.0, 1.0, 1.0))),
+        );
+        let origin = Vec3::new(0.0, 0.0, 0.0);
+        let ray = Ray::new(origin, Vec3::new(0.1, 1.0, 0.2));
+        let rec = lights.lights[0].hit(&ray, 0.001, f64::MAX).unwrap();
+        assert!(lights.pdf(&origin, &rec) > 0.0);
+        assert!(lights.pdf_area(&rec) > 0.0);
+        let rec = copy.hit(&ray, 0.001, f64::MAX).unwrap();
+        assert_eq!(lights.pdf(&origin, &rec), 0.0);
+        assert_eq!(lights.pdf_area(&rec), 0.0);
+    }
+}
@@ -1,7 +1,11 @@
-use rand::Rng;
+use std::f64::consts::PI;
 
+use crate::conductor::Conductor;
+use crate::fresnel::ThinFilm;
 use crate::hitable::HitRecord;
+use crate::medium::{self, Crossing, Medium};
 use crate::ray::Ray;
+use crate::spectrum::{self, Dispersion, Emission};
 use crate::utils;
 use crate::vec3::Vec3;
 
@@ -11,7 +15,97 @@ pub struct ScatteredRay {
 }
 
 pub trait Material {
+    // Normals aren't flipped towards the ray, so rays can arrive from behind
+    // them. Materials that only reflect absorb those, with eval() and pdf()
+    // returning zero for them too, and only ones that let light through, like
+    // dielectrics, are two-sided
     fn scatter(&self, r_in: &Ray, rec: &HitRecord) -> Option<ScatteredRay>;
+
+    // Material that this one wraps, like the base of a normal map or a cutout.
+    // The methods below take what they don't override from it, so that
+    // wrappers only implement what they change
+    fn base(&self) -> Option<&dyn Material> {
+        return None;
+    }
+
+    // Probability that a ray hitting this point stops here, rather than passing
+    // through and looking further along. Materials with masks override this
+    fn opacity(&self, rec: &HitRecord) -> f64 {
+        match self.base() {
+            Some(base) => return base.opacity(rec),
+            None => return 1.0,
+        }
+    }
+
+    // Whether opacity() can be below 1 anywhere, so that hitables know to
+    // build a full record and test it for every candidate hit
+    fn has_cutout(&self) -> bool {
+        match self.base() {
+            Some(base) => return base.has_cutout(),
+            None => return false,
+        }
+    }
+
+    // Queried by hitables while looking for the closest hit, picking whether
+    // the ray stops here with the odds opacity() gives
+    fn is_opaque(&self, rec: &HitRecord) -> bool {
+        let opacity = self.opacity(rec);
+        return opacity >= 1.0 || (opacity > 0.0 && utils::random_double() < opacity);
+    }
+
+    // Light given off at the hit point, on top of anything that gets scattered
+    fn emitted(&self, rec: &HitRecord) -> Vec3 {
+        match self.base() {
+            Some(base) => return base.emitted(rec),
+            None => return Vec3::new(0.0, 0.0, 0.0),
+        }
+    }
+
+    // Same as emitted(), at a single wavelength
+    fn emitted_at(&self, rec: &HitRecord, lambda: f64) -> f64 {
+        match self.base() {
+            Some(base) => return base.emitted_at(rec, lambda),
+            None => return spectrum::rgb_to_spectrum(&self.emitted(rec), lambda),
+        }
+    }
+
+    // Whether scattering depends on the exact wavelength of the ray, in which
+    // case spectral paths can only carry that one wavelength past it
+    fn is_dispersive(&self) -> bool {
+        match self.base() {
+            Some(base) => return base.is_dispersive(),
+            None => return false,
+        }
+    }
+
+    // BSDF for light arriving from wi and leaving towards wo, both unit vectors
+    // in world space pointing away from the surface. Doesn't include the cosine
+    // term. Only meaningful when is_delta() is false
+    fn eval(&self, rec: &HitRecord, wi: &Vec3, wo: &Vec3) -> Vec3 {
+        match self.base() {
+            Some(base) => return base.eval(rec, wi, wo),
+            None => return Vec3::new(0.0, 0.0, 0.0),
+        }
+    }
+
+    // Solid angle density with which scatter() picks wi for a ray leaving
+    // towards wo, same conventions as eval()
+    fn pdf(&self, rec: &HitRecord, wi: &Vec3, wo: &Vec3) -> f64 {
+        match self.base() {
+            Some(base) => return base.pdf(rec, wi, wo),
+            None => return 0.0,
+        }
+    }
+
+    // Whether scatter() picks directions that eval() and pdf() can't describe,
+    // like perfect mirrors and glass, so integrators have to rely on scatter()
+    // alone. Materials without eval() and pdf() keep the default
+    fn is_delta(&self) -> bool {
+        match self.base() {
+            Some(base) => return base.is_delta(),
+            None => return true,
+        }
+    }
 }
 
 pub struct Lambertian {
@@ -25,82 +119,314 @@ impl Lambertian {
 }
 
 impl Material for Lambertian {
-    fn scatter(&self, _r_in: &Ray, rec: &HitRecord) -> Option<ScatteredRay> {
-        let target = rec.p + rec.normal + utils::random_in_unit_sphere();
+    fn scatter(&self, r_in: &Ray, rec: &HitRecord) -> Option<ScatteredRay> {
+        if r_in.dir.dot(&rec.normal) >= 0.0 {
+            return None;
+        }
+
+        // Sampling proportionally to the cosine cancels out everything but albedo
+        let direction = rec.local_to_world(&utils::random_cosine_direction());
 
         return Some(ScatteredRay {
-            out_ray: Ray::new(rec.p, target - rec.p),
+            out_ray: r_in.spawn(rec.p, direction),
             attenuation: self.albedo,
         });
     }
+
+    fn eval(&self, rec: &HitRecord, wi: &Vec3, wo: &Vec3) -> Vec3 {
+        if wo.dot(&rec.normal) <= 0.0 || wi.dot(&rec.normal) <= 0.0 {
+            return Vec3::new(0.0, 0.0, 0.0);
+        }
+        return self.albedo / PI;
+    }
+
+    fn pdf(&self, rec: &HitRecord, wi: &Vec3, wo: &Vec3) -> f64 {
+        if wo.dot(&rec.normal) <= 0.0 {
+            return 0.0;
+        }
+        return wi.dot(&rec.normal).max(0.0) / PI;
+    }
+
+    fn is_delta(&self) -> bool {
+        return false;
+    }
 }
 
-pub struct Metal {
+// Rough diffuse surface made of tiny V-shaped Lambertian facets (Oren and Nayar
+// 1994, qualitative model). Looks flatter than Lambertian and reflects more
+// back towards the light, like clay, concrete or cloth
+pub struct OrenNayar {
     pub albedo: Vec3,
-    pub fuzz: f64,
+    // Standard deviation of the facet angles, in radians. 0 is Lambertian
+    pub roughness: f64,
+}
+
+impl OrenNayar {
+    pub fn new(albedo: Vec3, roughness: f64) -> OrenNayar {
+        return OrenNayar { albedo, roughness };
+    }
+}
+
+impl OrenNayar {
+    // BRDF times pi / albedo, with both directions in the local shading frame
+    fn factor(&self, wo: &Vec3, wi: &Vec3) -> f64 {
+        let sigma2 = self.roughness * self.roughness;
+        let a = 1.0 - 0.5 * sigma2 / (sigma2 + 0.33);
+        let b = 0.45 * sigma2 / (sigma2 + 0.09);
+
+        // Cosine of the azimuthal angle between both directions
+        let o_xy = (wo.x * wo.x + wo.y * wo.y).sqrt();
+        let i_xy = (wi.x * wi.x + wi.y * wi.y).sqrt();
+        let cos_phi = if o_xy > 0.0 && i_xy > 0.0 {
+            ((wo.x * wi.x + wo.y * wi.y) / (o_xy * i_xy)).max(0.0)
+        } else {
+            0.0
+        };
+
+        // sin(alpha) * tan(beta), with alpha the larger of both polar angles and
+        // beta the smaller one
+        let (sin_alpha, tan_beta) = if wi.z < wo.z {
+            (i_xy, o_xy / wo.z)
+        } else {
+            (o_xy, i_xy / wi.z)
+        };
+
+        return a + b * cos_phi * sin_alpha * tan_beta;
+    }
+}
+
+impl Material for OrenNayar {
+    fn scatter(&self, r_in: &Ray, rec: &HitRecord) -> Option<ScatteredRay> {
+        let wo = rec.world_to_local(&-r_in.dir.normalized());
+        if wo.z <= 0.0 {
+            return None;
+        }
+        let wi = utils::random_cosine_direction();
+
+        // With cosine sampling, f * cos / pdf is just albedo times the bracket
+        return Some(ScatteredRay {
+            out_ray: r_in.spawn(rec.p, rec.local_to_world(&wi)),
+            attenuation: self.albedo * self.factor(&wo, &wi),
+        });
+    }
+
+    fn eval(&self, rec: &HitRecord, wi: &Vec3, wo: &Vec3) -> Vec3 {
+        let wo = rec.world_to_local(wo);
+        let wi = rec.world_to_local(wi);
+        if wo.z <= 0.0 || wi.z <= 0.0 {
+            return Vec3::new(0.0, 0.0, 0.0);
+        }
+        return self.albedo * (self.factor(&wo, &wi) / PI);
+    }
+
+    fn pdf(&self, rec: &HitRecord, wi: &Vec3, wo: &Vec3) -> f64 {
+        if wo.dot(&rec.normal) <= 0.0 {
+            return 0.0;
+        }
+        return wi.dot(&rec.normal).max(0.0) / PI;
+    }
+
+    fn is_delta(&self) -> bool {
+        return false;
+    }
+}
+
+// The book's tinted metal, now a Conductor underneath so that it conserves
+// energy. eta and k are picked to reflect albedo at normal incidence, and fuzz
+// is used as the roughness
+pub struct Metal {
+    pub conductor: Conductor,
 }
 
 impl Metal {
     pub fn new(albedo: Vec3, fuzz: f64) -> Metal {
+        // With eta = 1, normal incidence reflects k^2 / (4 + k^2)
+        let k = |f0: f64| {
+            let f0 = f0.clamp(0.0, 0.999);
+            return 2.0 * (f0 / (1.0 - f0)).sqrt();
+        };
+        let eta = Vec3::new(1.0, 1.0, 1.0);
+        let k = Vec3::new(k(albedo.x), k(albedo.y), k(albedo.z));
         return Metal {
-            albedo,
-            fuzz: fuzz.min(1.0),
+            conductor: Conductor::new(eta, k, fuzz.clamp(0.0, 1.0)),
         };
     }
 }
 
 impl Material for Metal {
     fn scatter(&self, r_in: &Ray, rec: &HitRecord) -> Option<ScatteredRay> {
-        let reflected = utils::reflect(&r_in.dir.normalized(), &rec.normal);
-        let scattered = Ray::new(
-            rec.p,
-            reflected + utils::random_in_unit_sphere() * self.fuzz,
-        );
-
-        if scattered.dir.dot(&rec.normal) > 0.0 {
-            return Some(ScatteredRay {
-                out_ray: scattered,
-                attenuation: self.albedo,
-            });
-        }
+        return self.conductor.scatter(r_in, rec);
+    }
 
-        return None;
+    fn base(&self) -> Option<&dyn Material> {
+        return Some(&self.conductor);
     }
 }
 
 pub struct Dielectric {
     pub ref_idx: f64,
+    // Absorption coefficient per unit distance travelled inside, per channel
+    pub absorption: Vec3,
+    // Where dielectrics overlap, surfaces of lower priority ones are ignored.
+    // e.g. water in a glass should have a lower priority than the glass
+    pub priority: u32,
+    // Makes the IOR depend on the wavelength, which splits light into colors.
+    // ref_idx is still used for rays that haven't been assigned a wavelength
+    pub dispersion: Option<Dispersion>,
+    // Coating on the outside of the surface, e.g. for soap bubbles
+    pub film: Option<ThinFilm>,
+    medium_id: u32,
 }
 
 impl Dielectric {
     pub fn new(ri: f64) -> Dielectric {
-        return Dielectric { ref_idx: ri };
+        return Dielectric {
+            ref_idx: ri,
+            absorption: Vec3::new(0.0, 0.0, 0.0),
+            priority: 0,
+            dispersion: None,
+            film: None,
+            medium_id: medium::new_medium_id(),
+        };
+    }
+
+    // e.g. Dielectric::dispersive(Dispersion::bk7()) for a prism
+    pub fn dispersive(dispersion: Dispersion) -> Dielectric {
+        let mut result = Dielectric::new(dispersion.nominal_ior());
+        result.dispersion = Some(dispersion);
+        return result;
+    }
+
+    // Colored glass that lets through transmittance of the light after
+    // travelling distance inside it
+    pub fn with_transmittance(ri: f64, transmittance: Vec3, distance: f64) -> Dielectric {
+        let mut result = Dielectric::new(ri);
+        result.absorption = utils::absorption_from_transmittance(&transmittance, distance);
+        return result;
+    }
+
+    pub fn with_priority(mut self, priority: u32) -> Dielectric {
+        self.priority = priority;
+        return self;
+    }
+
+    // thickness is in nanometers. A soap bubble is a film of ior 1.33 over
+    // Dielectric::new(1.0)
+    pub fn with_film(mut self, thickness: f64, ior: f64) -> Dielectric {
+        self.film = Some(ThinFilm::new(thickness, ior));
+        return self;
+    }
+
+    // Reflectance of the film as seen from outside the surface, no matter which
+    // side the ray is on. Per channel unless the ray has a single wavelength
+    fn film_reflectance(
+        film: &ThinFilm,
+        r_in: &Ray,
+        rec: &HitRecord,
+        crossing: &Crossing,
+        entering: bool,
+        wavelength: Option<f64>,
+    ) -> Vec3 {
+        let near_ior = r_in.media.ior(wavelength);
+        let far_ior = crossing.transmitted.ior(wavelength);
+        let cos_near = r_in.dir.dot(&rec.normal).abs() / r_in.dir.length();
+
+        let (n_outside, n_inside, cos_outside) = if entering {
+            (near_ior, far_ior, cos_near)
+        } else {
+            let sin2_far = (near_ior / far_ior).powi(2) * (1.0 - cos_near * cos_near);
+            (far_ior, near_ior, (1.0 - sin2_far).max(0.0).sqrt())
+        };
+
+        match wavelength {
+            Some(lambda) => {
+                let r = film.reflectance(cos_outside, n_outside, n_inside, 0.0, lambda);
+                return Vec3::new(r, r, r);
+            }
+            None => {
+                let eta = Vec3::new(n_inside, n_inside, n_inside);
+                let k = Vec3::new(0.0, 0.0, 0.0);
+                return film.reflectance_rgb(cos_outside, n_outside, &eta, &k);
+            }
+        }
+    }
+
+    pub fn medium(&self) -> Medium {
+        return Medium {
+            id: self.medium_id,
+            ior: self.ref_idx,
+            priority: self.priority,
+            absorption: self.absorption,
+            dispersion: self.dispersion,
+            scattering: Vec3::new(0.0, 0.0, 0.0),
+            anisotropy: 0.0,
+        };
     }
 }
 
 impl Material for Dielectric {
+    fn is_dispersive(&self) -> bool {
+        return self.dispersion.is_some() || self.film.is_some();
+    }
+
+    // Smooth interfaces only ever reflect or refract in one direction
+    fn is_delta(&self) -> bool {
+        return true;
+    }
+
     fn scatter(&self, r_in: &Ray, rec: &HitRecord) -> Option<ScatteredRay> {
+        let entering = r_in.dir.dot(&rec.normal) <= 0.0;
+        let mut crossing = r_in.media.cross(&self.medium(), entering, r_in.wavelength);
+        if !crossing.is_real {
+            let mut out_ray = r_in.spawn(rec.p, r_in.dir);
+            out_ray.media = crossing.transmitted;
+            return Some(ScatteredRay {
+                out_ray,
+                attenuation: Vec3::new(1.0, 1.0, 1.0),
+            });
+        }
+
+        // The first dispersive surface a path meets picks a single wavelength for
+        // the rest of it, weighted so that it still averages out to RGB
+        let mut wavelength = r_in.wavelength;
+        let mut attenuation = Vec3::new(1.0, 1.0, 1.0);
+        if self.dispersion.is_some() && wavelength.is_none() {
+            let (lambda, weight) = spectrum::sample_wavelength();
+            wavelength = Some(lambda);
+            attenuation = weight;
+            crossing = r_in.media.cross(&self.medium(), entering, wavelength);
+        }
+
         let outward_normal: Vec3;
         let reflected = utils::reflect(&r_in.dir, &rec.normal);
-        let ni_over_nt: f64;
+        let ni_over_nt = 1.0 / crossing.eta;
         let reflect_prob: f64;
         let cosine: f64;
 
-        if r_in.dir.dot(&rec.normal) > 0.0 {
+        if !entering {
             outward_normal = -rec.normal;
-            ni_over_nt = self.ref_idx;
-            cosine = self.ref_idx * r_in.dir.dot(&rec.normal) / r_in.dir.length();
+            cosine = ni_over_nt * r_in.dir.dot(&rec.normal) / r_in.dir.length();
         } else {
             outward_normal = rec.normal;
-            ni_over_nt = 1.0 / self.ref_idx;
             cosine = -r_in.dir.dot(&rec.normal) / r_in.dir.length();
         }
 
+        // Film reflectance can differ per channel, in which case reflection is
+        // picked by its average and the attenuation makes up for the difference
+        let mut film_reflectance: Option<Vec3> = None;
         let mut refracted = Vec3::new(1.0, 0.0, 0.0);
-        let scattered: Ray;
         match utils::refract(&r_in.dir, &outward_normal, ni_over_nt) {
             Some(refr) => {
-                reflect_prob = utils::schlick(cosine, self.ref_idx);
+                match &self.film {
+                    Some(film) => {
+                        let reflectance = Dielectric::film_reflectance(
+                            film, r_in, rec, &crossing, entering, wavelength,
+                        );
+                        reflect_prob = (reflectance.x + reflectance.y + reflectance.z) / 3.0;
+                        film_reflectance = Some(reflectance);
+                    }
+                    None => reflect_prob = utils::schlick(cosine, crossing.eta),
+                }
                 refracted = refr;
             }
             None => {
@@ -109,15 +435,69 @@ impl Material for Dielectric {
             }
         };
 
-        if rand::thread_rng().gen::<f64>() < reflect_prob {
-            scattered = Ray::new(rec.p, reflected);
-        } else {
-            scattered = Ray::new(rec.p, refracted);
+        let reflecting = utils::random_double() < reflect_prob;
+        if let Some(reflectance) = film_reflectance {
+            if reflecting {
+                attenuation = attenuation * reflectance / reflect_prob;
+            } else {
+                attenuation =
+                    attenuation * (Vec3::new(1.0, 1.0, 1.0) - reflectance) / (1.0 - reflect_prob);
+            }
         }
 
+        let mut scattered = if reflecting {
+            r_in.spawn(rec.p, reflected)
+        } else {
+            let mut refracted_ray = r_in.spawn(rec.p, refracted);
+            refracted_ray.media = crossing.transmitted;
+            refracted_ray
+        };
+        scattered.wavelength = wavelength;
+
         return Some(ScatteredRay {
             out_ray: scattered,
-            attenuation: Vec3::new(1.0, 1.0, 1.0),
+            attenuation,
+        });
+    }
+}
+
+// Emits light without scattering any
+pub struct DiffuseLight {
+    pub emission: Emission,
+    rgb: Vec3,
+}
+
+impl DiffuseLight {
+    pub fn new(color: Vec3) -> DiffuseLight {
+        return DiffuseLight::from_emission(Emission::Rgb(color));
+    }
+
+    // Temperature in Kelvin, with intensity being the peak of the spectrum
+    pub fn blackbody(temperature: f64, intensity: f64) -> DiffuseLight {
+        return DiffuseLight::from_emission(Emission::Blackbody {
+            temperature,
+            intensity,
         });
     }
+
+    pub fn from_emission(emission: Emission) -> DiffuseLight {
+        return DiffuseLight {
+            emission,
+            rgb: emission.rgb(),
+        };
+    }
+}
+
+impl Material for DiffuseLight {
+    fn scatter(&self, _r_in: &Ray, _rec: &HitRecord) -> Option<ScatteredRay> {
+        return None;
+    }
+
+    fn emitted(&self, _rec: &HitRecord) -> Vec3 {
+        return self.rgb;
+    }
+
+    fn emitted_at(&self, _rec: &HitRecord, lambda: f64) -> f64 {
+        return self.emission.at(lambda);
+    }
 }
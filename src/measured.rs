@@ -0,0 +1,387 @@
+use std::f64::consts::PI;
+use std::fs;
+use std::io;
+
+use crate::hitable::HitRecord;
+use crate::material::{Material, ScatteredRay};
+use crate::ray::Ray;
+use crate::utils;
+use crate::vec3::Vec3;
+
+// Resolution of MERL tables, in the half/difference angle parameterization
+// (Rusinkiewicz 1998). phi_diff only covers [0, pi) thanks to reciprocity
+pub const MERL_THETA_HALF: usize = 90;
+pub const MERL_THETA_DIFF: usize = 90;
+pub const MERL_PHI_DIFF: usize = 180;
+const MERL_SIZE: usize = MERL_THETA_HALF * MERL_THETA_DIFF * MERL_PHI_DIFF;
+
+// Per channel scale of the stored values, from the MERL reference reader
+const MERL_SCALE: [f64; 3] = [1.0 / 1500.0, 1.15 / 1500.0, 1.66 / 1500.0];
+
+// Resolution of the tables used to importance sample the BRDF
+const SAMPLING_THETA_O: usize = 16;
+const SAMPLING_THETA_I: usize = 32;
+const SAMPLING_PHI: usize = 64;
+
+// Share of samples drawn from the cosine distribution instead of the tables,
+// so that nothing the tables miss is left unsampled
+const COSINE_FRACTION: f64 = 0.2;
+
+fn rotate_z(v: &Vec3, angle: f64) -> Vec3 {
+    let (sin, cos) = angle.sin_cos();
+    return Vec3::new(v.x * cos - v.y * sin, v.x * sin + v.y * cos, v.z);
+}
+
+fn rotate_y(v: &Vec3, angle: f64) -> Vec3 {
+    let (sin, cos) = angle.sin_cos();
+    return Vec3::new(v.x * cos + v.z * sin, v.y, -v.x * sin + v.z * cos);
+}
+
+// Returns (theta_half, theta_diff, phi_diff) for two directions in the local
+// shading frame
+fn half_diff_angles(wo: &Vec3, wi: &Vec3) -> (f64, f64, f64) {
+    let wh = (*wo + *wi).normalized();
+    let theta_half = wh.z.clamp(-1.0, 1.0).acos();
+    let phi_half = wh.y.atan2(wh.x);
+
+    let diff = rotate_y(&rotate_z(wi, -phi_half), -theta_half);
+    let theta_diff = diff.z.clamp(-1.0, 1.0).acos();
+    let phi_diff = diff.y.atan2(diff.x);
+    return (theta_half, theta_diff, phi_diff);
+}
+
+// Piecewise constant distribution over (theta_i, phi_i - phi_o) cells for one
+// range of outgoing directions
+struct SamplingTable {
+    cdf: Vec<f64>,
+}
+
+impl SamplingTable {
+    fn cell_probability(&self, index: usize) -> f64 {
+        let previous = if index == 0 { 0.0 } else { self.cdf[index - 1] };
+        return self.cdf[index] - previous;
+    }
+}
+
+// Isotropic measured BRDF, as in the MERL database (Matusik et al. 2003).
+// Looked up with trilinear interpolation in the half/difference angle table
+pub struct MeasuredBrdf {
+    // Indexed by [theta_half][theta_diff][phi_diff]
+    values: Vec<Vec3>,
+    sampling: Vec<SamplingTable>,
+}
+
+impl MeasuredBrdf {
+    // values is laid out like a MERL table, with the scale already applied
+    pub fn new(values: Vec<Vec3>) -> MeasuredBrdf {
+        assert_eq!(values.len(), MERL_SIZE, "wrong number of BRDF values");
+
+        let mut result = MeasuredBrdf {
+            values,
+            sampling: Vec::new(),
+        };
+        result.sampling = (0..SAMPLING_THETA_O)
+            .map(|i| result.build_sampling_table(i))
+            .collect();
+        return result;
+    }
+
+    // Reads the MERL binary format: three little endian i32 dimensions, then
+    // all red values, all green values and all blue values as f64
+    pub fn from_merl(path: &str) -> io::Result<MeasuredBrdf> {
+        let bytes = fs::read(path)?;
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+
+        if bytes.len() < 12 {
+            return Err(invalid("truncated MERL header"));
+        }
+        let dim =
+            |i: usize| i32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+        let dims = (dim(0), dim(4), dim(8));
+        if dims
+            != (
+                MERL_THETA_HALF as i32,
+                MERL_THETA_DIFF as i32,
+                MERL_PHI_DIFF as i32,
+            )
+        {
+            return Err(invalid("unexpected MERL table dimensions"));
+        }
+
+        let data = &bytes[12..];
+        if data.len() < MERL_SIZE * 3 * 8 {
+            return Err(invalid("truncated MERL data"));
+        }
+        let value = |channel: usize, i: usize| {
+            let start = (channel * MERL_SIZE + i) * 8;
+            let mut raw = [0u8; 8];
+            raw.copy_from_slice(&data[start..start + 8]);
+            return f64::from_le_bytes(raw) * MERL_SCALE[channel];
+        };
+
+        let values = (0..MERL_SIZE)
+            .map(|i| Vec3::new(value(0, i), value(1, i), value(2, i)))
+            .collect();
+        return Ok(MeasuredBrdf::new(values));
+    }
+
+    // Negative values mark missing measurements
+    fn value(&self, theta_half: usize, theta_diff: usize, phi_diff: usize) -> Vec3 {
+        let index = (theta_half * MERL_THETA_DIFF + theta_diff) * MERL_PHI_DIFF + phi_diff;
+        return self.values[index].max(&Vec3::new(0.0, 0.0, 0.0));
+    }
+
+    // BRDF for two directions in the local shading frame, above the surface
+    pub fn eval_local(&self, wo: &Vec3, wi: &Vec3) -> Vec3 {
+        if wo.z <= 0.0 || wi.z <= 0.0 {
+            return Vec3::new(0.0, 0.0, 0.0);
+        }
+        let (theta_half, theta_diff, mut phi_diff) = half_diff_angles(wo, wi);
+        if phi_diff < 0.0 {
+            phi_diff += PI;
+        }
+
+        // Continuous table coordinates. Entry i of theta_diff and phi_diff was
+        // measured at i degrees, and theta_half uses a square root mapping to have
+        // more resolution near the specular peak
+        let clamped = |x: f64, n: usize| x.clamp(0.0, (n - 1) as f64);
+        let th = clamped(
+            (theta_half / (PI / 2.0)).max(0.0).sqrt() * MERL_THETA_HALF as f64,
+            MERL_THETA_HALF,
+        );
+        let td = clamped(
+            theta_diff / (PI / 2.0) * MERL_THETA_DIFF as f64,
+            MERL_THETA_DIFF,
+        );
+        let pd = phi_diff / PI * MERL_PHI_DIFF as f64;
+
+        let (th0, td0, pd0) = (th.floor(), td.floor(), pd.floor());
+        let (th_t, td_t, pd_t) = (th - th0, td - td0, pd - pd0);
+        let th0 = th0 as usize;
+        let td0 = td0 as usize;
+        let th1 = (th0 + 1).min(MERL_THETA_HALF - 1);
+        let td1 = (td0 + 1).min(MERL_THETA_DIFF - 1);
+        // phi_diff wraps around
+        let pd0_index = (pd0 as i64).rem_euclid(MERL_PHI_DIFF as i64) as usize;
+        let pd1_index = (pd0_index + 1) % MERL_PHI_DIFF;
+
+        let mut result = Vec3::new(0.0, 0.0, 0.0);
+        for (h, wh) in [(th0, 1.0 - th_t), (th1, th_t)] {
+            for (d, wd) in [(td0, 1.0 - td_t), (td1, td_t)] {
+                for (p, wp) in [(pd0_index, 1.0 - pd_t), (pd1_index, pd_t)] {
+                    result += self.value(h, d, p) * (wh * wd * wp);
+                }
+            }
+        }
+        return result;
+    }
+
+    fn build_sampling_table(&self, theta_o_index: usize) -> SamplingTable {
+        let theta_o = (theta_o_index as f64 + 0.5) / SAMPLING_THETA_O as f64 * (PI / 2.0);
+        let wo = Vec3::new(theta_o.sin(), 0.0, theta_o.cos());
+        let d_theta = (PI / 2.0) / SAMPLING_THETA_I as f64;
+        let d_phi = 2.0 * PI / SAMPLING_PHI as f64;
+
+        let mut cdf = Vec::with_capacity(SAMPLING_THETA_I * SAMPLING_PHI);
+        let mut total = 0.0;
+        for i in 0..SAMPLING_THETA_I {
+            let theta = (i as f64 + 0.5) * d_theta;
+            for j in 0..SAMPLING_PHI {
+                let phi = (j as f64 + 0.5) * d_phi;
+                let wi = Vec3::new(
+                    theta.sin() * phi.cos(),
+                    theta.sin() * phi.sin(),
+                    theta.cos(),
+                );
+                let f = utils::luminance(&self.eval_local(&wo, &wi)).max(0.0);
+                total += f * theta.cos() * theta.sin() * d_theta * d_phi;
+                cdf.push(total);
+            }
+        }
+
+        // All black BRDFs just get sampled uniformly
+        if total <= 0.0 {
+            let count = cdf.len() as f64;
+            cdf = (1..=cdf.len()).map(|i| i as f64 / count).collect();
+        } else {
+            for c in cdf.iter_mut() {
+                *c /= total;
+            }
+        }
+        return SamplingTable { cdf };
+    }
+
+    fn sampling_table(&self, wo: &Vec3) -> &SamplingTable {
+        let theta_o = wo.z.clamp(-1.0, 1.0).acos();
+        let index = (theta_o / (PI / 2.0) * SAMPLING_THETA_O as f64) as usize;
+        return &self.sampling[index.min(SAMPLING_THETA_O - 1)];
+    }
+
+    fn sample_table(&self, wo: &Vec3) -> Vec3 {
+        let table = self.sampling_table(wo);
+        let u = utils::random_double();
+        let cell = table
+            .cdf
+            .partition_point(|c| *c <= u)
+            .min(table.cdf.len() - 1);
+
+        let d_theta = (PI / 2.0) / SAMPLING_THETA_I as f64;
+        let d_phi = 2.0 * PI / SAMPLING_PHI as f64;
+        let theta = ((cell / SAMPLING_PHI) as f64 + utils::random_double()) * d_theta;
+        let phi =
+            ((cell % SAMPLING_PHI) as f64 + utils::random_double()) * d_phi + wo.y.atan2(wo.x);
+        return Vec3::new(
+            theta.sin() * phi.cos(),
+            theta.sin() * phi.sin(),
+            theta.cos(),
+        );
+    }
+
+    // Solid angle density of sample_table()
+    fn table_pdf(&self, wo: &Vec3, wi: &Vec3) -> f64 {
+        let d_theta = (PI / 2.0) / SAMPLING_THETA_I as f64;
+        let d_phi = 2.0 * PI / SAMPLING_PHI as f64;
+        let theta = wi.z.clamp(-1.0, 1.0).acos();
+        let sin_theta = theta.sin();
+        if sin_theta <= 0.0 {
+            return 0.0;
+        }
+
+        let phi = (wi.y.atan2(wi.x) - wo.y.atan2(wo.x)).rem_euclid(2.0 * PI);
+        let i = ((theta / d_theta) as usize).min(SAMPLING_THETA_I - 1);
+        let j = ((phi / d_phi) as usize).min(SAMPLING_PHI - 1);
+        let probability = self
+            .sampling_table(wo)
+            .cell_probability(i * SAMPLING_PHI + j);
+        return probability / (d_theta * d_phi * sin_theta);
+    }
+
+    // Density of scatter(), with both directions in the local shading frame
+    fn pdf_local(&self, wo: &Vec3, wi: &Vec3) -> f64 {
+        if wo.z <= 0.0 || wi.z <= 0.0 {
+            return 0.0;
+        }
+        return COSINE_FRACTION * wi.z / PI + (1.0 - COSINE_FRACTION) * self.table_pdf(wo, wi);
+    }
+}
+
+impl Material for MeasuredBrdf {
+    fn scatter(&self, r_in: &Ray, rec: &HitRecord) -> Option<ScatteredRay> {
+        let wo = rec.world_to_local(&-r_in.dir.normalized());
+        if wo.z <= 0.0 {
+            return None;
+        }
+
+        let wi = if utils::random_double() < COSINE_FRACTION {
+            utils::random_cosine_direction()
+        } else {
+            self.sample_table(&wo)
+        };
+        if wi.z <= 0.0 {
+            return None;
+        }
+
+        let pdf = self.pdf_local(&wo, &wi);
+        if pdf <= 0.0 {
+            return None;
+        }
+
+        return Some(ScatteredRay {
+            out_ray: r_in.spawn(rec.p, rec.local_to_world(&wi)),
+            attenuation: self.eval_local(&wo, &wi) * (wi.z / pdf),
+        });
+    }
+
+    fn eval(&self, rec: &HitRecord, wi: &Vec3, wo: &Vec3) -> Vec3 {
+        return self.eval_local(&rec.world_to_local(wo), &rec.world_to_local(wi));
+    }
+
+    fn pdf(&self, rec: &HitRecord, wi: &Vec3, wo: &Vec3) -> f64 {
+        return self.pdf_local(&rec.world_to_local(wo), &rec.world_to_local(wi));
+    }
+
+    fn is_delta(&self) -> bool {
+        return false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIMS: [i32; 3] = [
+        MERL_THETA_HALF as i32,
+        MERL_THETA_DIFF as i32,
+        MERL_PHI_DIFF as i32,
+    ];
+
+    // Writes a MERL file with the given dimensions and values per channel into
+    // the temporary directory, and returns its path
+    fn write_merl(name: &str, dims: [i32; 3], values_per_channel: usize, value: f64) -> String {
+        let mut bytes = Vec::new();
+        for dim in dims.iter() {
+            bytes.extend_from_slice(&dim.to_le_bytes());
+        }
+        for _ in 0..values_per_channel * 3 {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        let path = std::env::temp_dir().join(format!("{}-{}.binary", name, std::process::id()));
+        fs::write(&path, bytes).unwrap();
+        return path.to_str().unwrap().to_string();
+    }
+
+    fn load_error(path: &str) -> io::Error {
+        let error = MeasuredBrdf::from_merl(path).err().unwrap();
+        fs::remove_file(path).unwrap();
+        return error;
+    }
+
+    #[test]
+    fn rejects_truncated_header() {
+        let path = std::env::temp_dir().join(format!("header-{}.binary", std::process::id()));
+        fs::write(&path, [90, 0, 0, 0, 90, 0, 0, 0]).unwrap();
+        let error = load_error(path.to_str().unwrap());
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(error.to_string(), "truncated MERL header");
+    }
+
+    #[test]
+    fn rejects_wrong_dimensions() {
+        let error = load_error(&write_merl("dimensions", [90, 90, 90], 0, 0.0));
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(error.to_string(), "unexpected MERL table dimensions");
+    }
+
+    #[test]
+    fn rejects_truncated_data() {
+        let error = load_error(&write_merl("data", DIMS, MERL_SIZE - 1, 0.0));
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(error.to_string(), "truncated MERL data");
+    }
+
+    #[test]
+    fn reports_missing_files() {
+        let error = MeasuredBrdf::from_merl("/nonexistent/brdf.binary")
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn reads_and_scales_each_channel() {
+        let path = write_merl("constant", DIMS, MERL_SIZE, 1500.0);
+        let brdf = MeasuredBrdf::from_merl(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let wo = Vec3::new(0.3, 0.2, 0.9).normalized();
+        let wi = Vec3::new(-0.5, 0.1, 0.8).normalized();
+        let f = brdf.eval_local(&wo, &wi);
+        assert!((f - Vec3::new(1.0, 1.15, 1.66)).length() < 1e-9, "{:?}", f);
+    }
+
+    #[test]
+    #[should_panic(expected = "wrong number of BRDF values")]
+    fn rejects_tables_of_the_wrong_size() {
+        MeasuredBrdf::new(vec![Vec3::new(0.0, 0.0, 0.0); 10]);
+    }
+}
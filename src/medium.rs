@@ -0,0 +1,406 @@
+use std::f64::consts::PI;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::RwLock;
+
+use crate::spectrum::{self, Dispersion, SampledSpectrum, SampledWavelengths, SPECTRAL_SAMPLES};
+use crate::utils;
+use crate::vec3::Vec3;
+
+// Past this many nested media, entering another one forgets the one with the
+// lowest priority, which only affects absurd scenes
+pub const MAX_NESTED_MEDIA: usize = 8;
+
+static NEXT_MEDIUM_ID: AtomicU32 = AtomicU32::new(1);
+
+// Parameters of every medium that has been entered so far, by id. Stacks only
+// hold ids, so that rays stay small enough to copy around freely
+static REGISTRY: RwLock<Vec<Option<Medium>>> = RwLock::new(Vec::new());
+
+// Unique id for a new medium, so that the stack can tell which one a surface
+// belongs to even if two of them have the same parameters
+pub fn new_medium_id() -> u32 {
+    return NEXT_MEDIUM_ID.fetch_add(1, Ordering::Relaxed);
+}
+
+// Records the current parameters of medium, which materials build on the fly
+fn register(medium: &Medium) {
+    let mut registry = REGISTRY.write().unwrap();
+    let index = medium.id as usize;
+    if registry.len() <= index {
+        registry.resize(index + 1, None);
+    }
+    registry[index] = Some(*medium);
+}
+
+fn lookup(id: u32) -> Medium {
+    let registry = REGISTRY.read().unwrap();
+    return registry[id as usize].expect("media are registered when entered");
+}
+
+// The inside of a closed dielectric object. Where media overlap, the one with
+// the highest priority wins and the surfaces of the others are ignored
+#[derive(Debug, Copy, Clone)]
+pub struct Medium {
+    pub id: u32,
+    pub ior: f64,
+    pub priority: u32,
+    pub absorption: Vec3,
+    // Overrides ior for rays that carry a single wavelength
+    pub dispersion: Option<Dispersion>,
+    // Scattering coefficient per unit distance, per channel. Rays travelling
+    // through media that scatter can change direction before reaching a surface
+    pub scattering: Vec3,
+    // Henyey-Greenstein asymmetry of that scattering, from -1 (backwards) through
+    // 0 (uniform) to 1 (forwards)
+    pub anisotropy: f64,
+}
+
+impl Medium {
+    pub fn ior_at(&self, wavelength: Option<f64>) -> f64 {
+        match (self.dispersion, wavelength) {
+            (Some(dispersion), Some(lambda)) => return dispersion.ior(lambda),
+            _ => return self.ior,
+        }
+    }
+}
+
+// Density of scattering by an angle with the given cosine, per unit solid angle
+pub fn henyey_greenstein(cos_theta: f64, g: f64) -> f64 {
+    let denom = 1.0 + g * g - 2.0 * g * cos_theta;
+    return (1.0 - g * g) / (4.0 * PI * denom * denom.sqrt());
+}
+
+// New direction for a ray travelling along dir that gets scattered with a
+// Henyey-Greenstein phase function. Sampled exactly, so it needs no weight
+pub fn sample_henyey_greenstein(dir: &Vec3, g: f64) -> Vec3 {
+    let u = utils::random_double();
+    let cos_theta = if g.abs() < 1e-3 {
+        1.0 - 2.0 * u
+    } else {
+        let t = (1.0 - g * g) / (1.0 - g + 2.0 * g * u);
+        ((1.0 + g * g - t * t) / (2.0 * g)).clamp(-1.0, 1.0)
+    };
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * PI * utils::random_double();
+
+    let w = dir.normalized();
+    let (u_axis, v_axis) = utils::orthonormal_basis(&w);
+    return u_axis * (sin_theta * phi.cos()) + v_axis * (sin_theta * phi.sin()) + w * cos_theta;
+}
+
+// What happens to a ray travelling some distance through a medium
+pub struct MediumSample<T> {
+    // Set if the ray scattered before covering the whole distance
+    pub scatter_distance: Option<f64>,
+    // Transmittance, plus the scattering coefficient if it scattered, over
+    // the probability of that happening
+    pub weight: T,
+}
+
+// Distance sampling with channel dependent coefficients. One of the first
+// active channels is picked to sample the distance, and weights use the
+// average pdf over all of them (spectral MIS)
+fn sample_channels<const N: usize>(
+    absorption: &[f64; N],
+    scattering: &[f64; N],
+    active: usize,
+    max_distance: f64,
+) -> MediumSample<[f64; N]> {
+    let mut extinction = [0.0; N];
+    for i in 0..N {
+        extinction[i] = absorption[i] + scattering[i];
+    }
+    let transmittance = |distance: f64| extinction.map(|e| (-e * distance).exp());
+
+    // Nothing to sample in purely absorbing media
+    if scattering[..active].iter().all(|s| *s <= 0.0) {
+        return MediumSample {
+            scatter_distance: None,
+            weight: transmittance(max_distance),
+        };
+    }
+
+    let channel = utils::random_index(active);
+    let distance = if extinction[channel] > 0.0 {
+        -(1.0 - utils::random_double()).ln() / extinction[channel]
+    } else {
+        f64::INFINITY
+    };
+
+    if distance < max_distance {
+        let t = transmittance(distance);
+        let pdf = (0..active).map(|i| extinction[i] * t[i]).sum::<f64>() / active as f64;
+        let mut weight = [0.0; N];
+        for i in 0..N {
+            weight[i] = scattering[i] * t[i] / pdf;
+        }
+        return MediumSample {
+            scatter_distance: Some(distance),
+            weight,
+        };
+    }
+
+    let t = transmittance(max_distance);
+    let probability = t[..active].iter().sum::<f64>() / active as f64;
+    return MediumSample {
+        scatter_distance: None,
+        weight: t.map(|x| x / probability),
+    };
+}
+
+// What happens to a ray crossing the surface of a medium
+pub struct Crossing {
+    // False interfaces separate a medium from itself, as far as the ray is
+    // concerned, and shouldn't bend or reflect it
+    pub is_real: bool,
+    // IOR on the far side over the IOR on the ray's side
+    pub eta: f64,
+    // Media the ray will be in if it goes through
+    pub transmitted: MediumStack,
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+struct StackEntry {
+    id: u32,
+    priority: u32,
+}
+
+// Media that contain the current point of a path, in the order they were entered
+#[derive(Debug, Copy, Clone)]
+pub struct MediumStack {
+    entries: [StackEntry; MAX_NESTED_MEDIA],
+    len: usize,
+}
+
+impl Default for MediumStack {
+    fn default() -> MediumStack {
+        return MediumStack::new();
+    }
+}
+
+impl MediumStack {
+    // Nothing but air
+    pub fn new() -> MediumStack {
+        return MediumStack {
+            entries: [StackEntry::default(); MAX_NESTED_MEDIA],
+            len: 0,
+        };
+    }
+
+    pub fn contains(&self, id: u32) -> bool {
+        return self.entries[..self.len].iter().any(|e| e.id == id);
+    }
+
+    // The medium that light is actually travelling through, if any. Between equal
+    // priorities, the most recently entered one wins
+    pub fn current(&self) -> Option<Medium> {
+        let mut result: Option<StackEntry> = None;
+        for entry in &self.entries[..self.len] {
+            match result {
+                Some(r) if entry.priority < r.priority => {}
+                _ => result = Some(*entry),
+            }
+        }
+        return result.map(|entry| lookup(entry.id));
+    }
+
+    pub fn ior(&self, wavelength: Option<f64>) -> f64 {
+        return self.current().map_or(1.0, |m| m.ior_at(wavelength));
+    }
+
+    // Fraction of light that travels distance through the current medium without
+    // being absorbed or scattered away
+    pub fn transmittance(&self, distance: f64) -> Vec3 {
+        match self.current() {
+            Some(medium) => {
+                let extinction = medium.absorption + medium.scattering;
+                return utils::beer_lambert(&extinction, distance);
+            }
+            None => return Vec3::new(1.0, 1.0, 1.0),
+        }
+    }
+
+    // Same as transmittance(), at a single wavelength
+    pub fn transmittance_at(&self, distance: f64, lambda: f64) -> f64 {
+        match self.current() {
+            Some(medium) => {
+                let extinction = medium.absorption + medium.scattering;
+                return (-spectrum::rgb_to_spectrum(&extinction, lambda) * distance).exp();
+            }
+            None => return 1.0,
+        }
+    }
+
+    pub fn anisotropy(&self) -> f64 {
+        return self.current().map_or(0.0, |m| m.anisotropy);
+    }
+
+    // Travels up to distance through the current medium, possibly scattering
+    pub fn sample(&self, distance: f64) -> MediumSample<Vec3> {
+        let medium = match self.current() {
+            Some(medium) => medium,
+            None => {
+                return MediumSample {
+                    scatter_distance: None,
+                    weight: Vec3::new(1.0, 1.0, 1.0),
+                }
+            }
+        };
+
+        let absorption = [
+            medium.absorption.x,
+            medium.absorption.y,
+            medium.absorption.z,
+        ];
+        let scattering = [
+            medium.scattering.x,
+            medium.scattering.y,
+            medium.scattering.z,
+        ];
+        let result = sample_channels(&absorption, &scattering, 3, distance);
+        return MediumSample {
+            scatter_distance: result.scatter_distance,
+            weight: Vec3::new(result.weight[0], result.weight[1], result.weight[2]),
+        };
+    }
+
+    // Same as sample(), for the wavelengths carried by a spectral path
+    pub fn sample_at(
+        &self,
+        distance: f64,
+        wavelengths: &SampledWavelengths,
+    ) -> MediumSample<SampledSpectrum> {
+        match self.current() {
+            Some(medium) => {
+                return sample_channels(
+                    &wavelengths.from_rgb(&medium.absorption),
+                    &wavelengths.from_rgb(&medium.scattering),
+                    wavelengths.count,
+                    distance,
+                )
+            }
+            None => {
+                return MediumSample {
+                    scatter_distance: None,
+                    weight: [1.0; SPECTRAL_SAMPLES],
+                }
+            }
+        }
+    }
+
+    pub fn push(&mut self, medium: Medium) {
+        register(&medium);
+        if self.contains(medium.id) {
+            return;
+        }
+
+        // When full, the medium that would be the last to win goes. It's the
+        // least likely to matter again before the ray leaves the others
+        if self.len == MAX_NESTED_MEDIA {
+            let mut lowest = 0;
+            for i in 1..self.len {
+                if self.entries[i].priority < self.entries[lowest].priority {
+                    lowest = i;
+                }
+            }
+            self.remove_at(lowest);
+        }
+        self.entries[self.len] = StackEntry {
+            id: medium.id,
+            priority: medium.priority,
+        };
+        self.len += 1;
+    }
+
+    pub fn remove(&mut self, id: u32) {
+        let index = self.entries[..self.len].iter().position(|e| e.id == id);
+        if let Some(index) = index {
+            self.remove_at(index);
+        }
+    }
+
+    fn remove_at(&mut self, index: usize) {
+        for i in index..self.len - 1 {
+            self.entries[i] = self.entries[i + 1];
+        }
+        self.len -= 1;
+    }
+
+    // Resolves the surface of medium against the media the ray is currently in.
+    // entering tells which side of the surface the ray is coming from
+    pub fn cross(&self, medium: &Medium, entering: bool, wavelength: Option<f64>) -> Crossing {
+        let mut transmitted = *self;
+        if entering {
+            transmitted.push(*medium);
+        } else {
+            transmitted.remove(medium.id);
+        }
+
+        // A ray can also end up leaving a medium it never entered, e.g. if the
+        // camera starts inside it. That always has to be a real interface
+        let stray_exit = !entering && !self.contains(medium.id);
+
+        // Otherwise the surface only matters if its medium is the winning one on
+        // either side of it
+        let is_real = stray_exit
+            || self.current().map(|m| m.id) == Some(medium.id)
+            || transmitted.current().map(|m| m.id) == Some(medium.id);
+
+        let n_i = if stray_exit {
+            medium.ior_at(wavelength)
+        } else {
+            self.ior(wavelength)
+        };
+        let n_t = transmitted.ior(wavelength);
+
+        return Crossing {
+            is_real,
+            eta: n_t / n_i,
+            transmitted,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn medium(priority: u32) -> Medium {
+        return Medium {
+            id: new_medium_id(),
+            ior: 1.0 + priority as f64,
+            priority,
+            absorption: Vec3::new(0.0, 0.0, 0.0),
+            dispersion: None,
+            scattering: Vec3::new(0.0, 0.0, 0.0),
+            anisotropy: 0.0,
+        };
+    }
+
+    #[test]
+    fn highest_priority_wins() {
+        let mut stack = MediumStack::new();
+        let (low, high) = (medium(1), medium(2));
+        stack.push(high);
+        stack.push(low);
+        assert_eq!(stack.current().unwrap().id, high.id);
+        stack.remove(high.id);
+        assert_eq!(stack.current().unwrap().id, low.id);
+        stack.remove(low.id);
+        assert!(stack.current().is_none());
+    }
+
+    #[test]
+    fn overflowing_drops_the_lowest_priority() {
+        let mut stack = MediumStack::new();
+        let lowest = medium(0);
+        stack.push(lowest);
+        let others: Vec<Medium> = (1..=MAX_NESTED_MEDIA as u32).map(medium).collect();
+        for other in &others {
+            stack.push(*other);
+        }
+        assert!(!stack.contains(lowest.id));
+        assert!(others.iter().all(|m| stack.contains(m.id)));
+        assert_eq!(stack.ior(None), 1.0 + MAX_NESTED_MEDIA as f64);
+    }
+}
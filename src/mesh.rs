@@ -0,0 +1,582 @@
+use std::rc::Rc;
+
+use crate::aabb::Aabb;
+use crate::hitable::{HitRecord, Hitable};
+use crate::light::{Light, LightSample};
+use crate::material::Material;
+use crate::ray::Ray;
+use crate::texture::Texture;
+use crate::utils;
+use crate::vec3::Vec3;
+
+const MAX_TRIANGLES_PER_LEAF: usize = 4;
+
+// Attributes are indexed separately, like in an OBJ file, so that vertices can
+// share a position while having different normals (creases) or UVs (seams)
+#[derive(Debug, Copy, Clone)]
+pub struct MeshTriangle {
+    pub positions: [usize; 3],
+    pub normals: Option<[usize; 3]>,
+    pub uvs: Option<[usize; 3]>,
+}
+
+impl MeshTriangle {
+    pub fn new(positions: [usize; 3]) -> MeshTriangle {
+        return MeshTriangle {
+            positions,
+            normals: None,
+            uvs: None,
+        };
+    }
+}
+
+struct BvhNode {
+    bounds: Aabb,
+    // Leaves reference triangle_order[first..first + count], inner nodes have
+    // count == 0 and their children at first and first + 1
+    first: usize,
+    count: usize,
+}
+
+pub struct TriangleMesh {
+    pub positions: Vec<Vec3>,
+    pub normals: Vec<Vec3>,
+    pub uvs: Vec<(f64, f64)>,
+    pub triangles: Vec<MeshTriangle>,
+    pub material: Rc<dyn Material>,
+    nodes: Vec<BvhNode>,
+    triangle_order: Vec<usize>,
+    // Running sum of triangle areas, to sample the mesh as a light
+    area_cdf: Vec<f64>,
+}
+
+impl TriangleMesh {
+    pub fn new(
+        positions: Vec<Vec3>,
+        normals: Vec<Vec3>,
+        uvs: Vec<(f64, f64)>,
+        triangles: Vec<MeshTriangle>,
+        material: Rc<dyn Material>,
+    ) -> TriangleMesh {
+        let mut mesh = TriangleMesh {
+            positions,
+            normals,
+            uvs,
+            triangles,
+            material,
+            nodes: Vec::new(),
+            triangle_order: Vec::new(),
+            area_cdf: Vec::new(),
+        };
+        mesh.build_bvh();
+        return mesh;
+    }
+
+    // Flat shaded mesh with no UVs
+    pub fn from_indices(
+        positions: Vec<Vec3>,
+        indices: &[[usize; 3]],
+        material: Rc<dyn Material>,
+    ) -> TriangleMesh {
+        let triangles = indices.iter().map(|tri| MeshTriangle::new(*tri)).collect();
+        return TriangleMesh::new(positions, Vec::new(), Vec::new(), triangles, material);
+    }
+
+    pub fn bounding_box(&self) -> Aabb {
+        match self.nodes.first() {
+            Some(root) => return root.bounds,
+            None => return Aabb::empty(),
+        }
+    }
+
+    pub fn triangle_bounds(&self, index: usize) -> Aabb {
+        let tri = &self.triangles[index];
+        return Aabb::from_points(&[
+            self.positions[tri.positions[0]],
+            self.positions[tri.positions[1]],
+            self.positions[tri.positions[2]],
+        ]);
+    }
+
+    // Moves every vertex along its normal by scale times the texture's scalar
+    // value at that vertex. Positions shared by several triangles are only moved
+    // once, so UV seams and creases don't open up cracks. On a UV seam, where
+    // the triangles give a position different UVs, it moves by the average of
+    // the values at each of them. Shading normals and the BVH are rebuilt to
+    // match the new surface
+    pub fn displace(&mut self, texture: &dyn Texture, scale: f64) {
+        let mut directions = vec![Vec3::new(0.0, 0.0, 0.0); self.positions.len()];
+        let mut heights = vec![0.0; self.positions.len()];
+        let mut lookups = vec![0; self.positions.len()];
+
+        for i in 0..self.triangles.len() {
+            let tri = self.triangles[i];
+            let face_normal = self.geometric_normal(i);
+            for corner in 0..3 {
+                let p = tri.positions[corner];
+                directions[p] += match tri.normals {
+                    Some(n) => self.normals[n[corner]],
+                    None => face_normal,
+                };
+                if let Some(uv) = tri.uvs {
+                    let (u, v) = self.uvs[uv[corner]];
+                    heights[p] += texture.scalar(u, v, &self.positions[p]);
+                    lookups[p] += 1;
+                }
+            }
+        }
+
+        for p in 0..self.positions.len() {
+            if directions[p].squared_length() == 0.0 {
+                continue;
+            }
+
+            let height = if lookups[p] > 0 {
+                heights[p] / lookups[p] as f64
+            } else {
+                texture.scalar(0.0, 0.0, &self.positions[p])
+            };
+            self.positions[p] += directions[p].normalized() * (height * scale);
+        }
+
+        self.recompute_normals();
+        self.build_bvh();
+    }
+
+    // Area weighted average of the faces that use each normal. Triangles only
+    // share a normal within a smoothing group, so creases are kept
+    pub fn recompute_normals(&mut self) {
+        for n in self.normals.iter_mut() {
+            *n = Vec3::new(0.0, 0.0, 0.0);
+        }
+
+        for tri in &self.triangles {
+            if let Some(n) = tri.normals {
+                let p0 = self.positions[tri.positions[0]];
+                let e1 = self.positions[tri.positions[1]] - p0;
+                let e2 = self.positions[tri.positions[2]] - p0;
+                let face_normal = e1.cross(&e2);
+                for index in n.iter() {
+                    self.normals[*index] += face_normal;
+                }
+            }
+        }
+
+        for n in self.normals.iter_mut() {
+            if n.squared_length() > 0.0 {
+                n.normalize();
+            }
+        }
+    }
+
+    // Has to be called again whenever positions are modified after construction
+    pub fn build_bvh(&mut self) {
+        let mut total_area = 0.0;
+        self.area_cdf = (0..self.triangles.len())
+            .map(|i| {
+                total_area += self.triangle_area(i);
+                return total_area;
+            })
+            .collect();
+
+        self.nodes.clear();
+        self.triangle_order = (0..self.triangles.len()).collect();
+        if self.triangles.is_empty() {
+            return;
+        }
+
+        let bounds: Vec<Aabb> = (0..self.triangles.len())
+            .map(|i| self.triangle_bounds(i))
+            .collect();
+
+        self.nodes.push(BvhNode {
+            bounds: Aabb::empty(),
+            first: 0,
+            count: self.triangles.len(),
+        });
+
+        // Children are always pushed in pairs, so that siblings end up adjacent
+        let mut to_split = vec![0];
+        while let Some(node_index) = to_split.pop() {
+            let first = self.nodes[node_index].first;
+            let count = self.nodes[node_index].count;
+
+            let mut node_bounds = Aabb::empty();
+            let mut centroid_bounds = Aabb::empty();
+            for &tri in &self.triangle_order[first..first + count] {
+                node_bounds = node_bounds.surrounding(&bounds[tri]);
+                centroid_bounds.grow(&bounds[tri].centroid());
+            }
+            self.nodes[node_index].bounds = node_bounds;
+
+            if count <= MAX_TRIANGLES_PER_LEAF {
+                continue;
+            }
+
+            // Median split along the axis where the centroids are most spread out
+            let axis = centroid_bounds.longest_axis();
+            self.triangle_order[first..first + count].sort_by(|a, b| {
+                let ca = bounds[*a].centroid()[axis];
+                let cb = bounds[*b].centroid()[axis];
+                return ca.partial_cmp(&cb).unwrap_or(std::cmp::Ordering::Equal);
+            });
+
+            let left_count = count / 2;
+            let left_index = self.nodes.len();
+            self.nodes.push(BvhNode {
+                bounds: Aabb::empty(),
+                first,
+                count: left_count,
+            });
+            self.nodes.push(BvhNode {
+                bounds: Aabb::empty(),
+                first: first + left_count,
+                count: count - left_count,
+            });
+
+            self.nodes[node_index].first = left_index;
+            self.nodes[node_index].count = 0;
+            to_split.push(left_index);
+            to_split.push(left_index + 1);
+        }
+    }
+
+    // Moller-Trumbore. Returns the ray parameter and the barycentrics of the
+    // second and third vertices
+    fn intersect_triangle(
+        &self,
+        index: usize,
+        r: &Ray,
+        t_min: f64,
+        t_max: f64,
+    ) -> Option<(f64, f64, f64)> {
+        let tri = &self.triangles[index];
+        let p0 = self.positions[tri.positions[0]];
+        let e1 = self.positions[tri.positions[1]] - p0;
+        let e2 = self.positions[tri.positions[2]] - p0;
+
+        let pvec = r.dir.cross(&e2);
+        let det = e1.dot(&pvec);
+        if det.abs() < 1e-12 {
+            return None;
+        }
+        let inv_det = 1.0 / det;
+
+        let tvec = r.orig - p0;
+        let b1 = tvec.dot(&pvec) * inv_det;
+        if !(0.0..=1.0).contains(&b1) {
+            return None;
+        }
+
+        let qvec = tvec.cross(&e1);
+        let b2 = r.dir.dot(&qvec) * inv_det;
+        if b2 < 0.0 || b1 + b2 > 1.0 {
+            return None;
+        }
+
+        let t = e2.dot(&qvec) * inv_det;
+        if t < t_max && t > t_min {
+            return Some((t, b1, b2));
+        }
+        return None;
+    }
+
+    fn make_record(&self, index: usize, r: &Ray, t: f64, b1: f64, b2: f64) -> HitRecord<'_> {
+        let tri = &self.triangles[index];
+        let b0 = 1.0 - b1 - b2;
+
+        let mut geometric_normal = self.geometric_normal(index);
+        let normal = match tri.normals {
+            Some(n) => {
+                (self.normals[n[0]] * b0 + self.normals[n[1]] * b1 + self.normals[n[2]] * b2)
+                    .normalized()
+            }
+            None => geometric_normal,
+        };
+
+        // Vertex normals are allowed to disagree with the winding order
+        if geometric_normal.dot(&normal) < 0.0 {
+            geometric_normal = -geometric_normal;
+        }
+
+        // Without UVs the surface is parametrized by the barycentrics
+        let (uv0, uv1, uv2) = match tri.uvs {
+            Some(uv) => (self.uvs[uv[0]], self.uvs[uv[1]], self.uvs[uv[2]]),
+            None => ((0.0, 0.0), (1.0, 0.0), (0.0, 1.0)),
+        };
+        let u = uv0.0 * b0 + uv1.0 * b1 + uv2.0 * b2;
+        let v = uv0.1 * b0 + uv1.1 * b1 + uv2.1 * b2;
+
+        // Solve for dp/du and dp/dv from the UV deltas along two of the edges
+        let p2 = self.positions[tri.positions[2]];
+        let dp02 = self.positions[tri.positions[0]] - p2;
+        let dp12 = self.positions[tri.positions[1]] - p2;
+        let (du02, dv02) = (uv0.0 - uv2.0, uv0.1 - uv2.1);
+        let (du12, dv12) = (uv1.0 - uv2.0, uv1.1 - uv2.1);
+        let det = du02 * dv12 - dv02 * du12;
+        let (dpdu, dpdv) = if det.abs() > 1e-12 {
+            (
+                (dp02 * dv12 - dp12 * dv02) / det,
+                (dp12 * du02 - dp02 * du12) / det,
+            )
+        } else {
+            (Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, 0.0))
+        };
+
+        // Mirrored UVs, where v runs the other way around the normal than usual
+        let tangent = utils::tangent_from(&normal, &dpdu);
+        let handedness = if normal.cross(&tangent).dot(&dpdv) < 0.0 {
+            -1.0
+        } else {
+            1.0
+        };
+
+        return HitRecord {
+            t,
+            p: r.point_at_parameter(t),
+            normal,
+            geometric_normal,
+            tangent,
+            handedness,
+            u,
+            v,
+            mat_ptr: self.material.as_ref(),
+            light: Some(self),
+        };
+    }
+
+    pub fn triangle_area(&self, index: usize) -> f64 {
+        let tri = &self.triangles[index];
+        let p0 = self.positions[tri.positions[0]];
+        let e1 = self.positions[tri.positions[1]] - p0;
+        let e2 = self.positions[tri.positions[2]] - p0;
+        return 0.5 * e1.cross(&e2).length();
+    }
+
+    // Follows the counter-clockwise winding of the triangle
+    pub fn geometric_normal(&self, index: usize) -> Vec3 {
+        let tri = &self.triangles[index];
+        let p0 = self.positions[tri.positions[0]];
+        let e1 = self.positions[tri.positions[1]] - p0;
+        let e2 = self.positions[tri.positions[2]] - p0;
+        return e1.cross(&e2).normalized();
+    }
+}
+
+impl Hitable for TriangleMesh {
+    fn hit(&self, r: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+
+        let has_cutout = self.material.has_cutout();
+        let mut closest_so_far = t_max;
+        let mut closest_hit = None;
+
+        let mut stack = vec![0];
+        while let Some(node_index) = stack.pop() {
+            let node = &self.nodes[node_index];
+            if !node.bounds.hit(r, t_min, closest_so_far) {
+                continue;
+            }
+
+            if node.count == 0 {
+                stack.push(node.first);
+                stack.push(node.first + 1);
+                continue;
+            }
+
+            for &tri in &self.triangle_order[node.first..node.first + node.count] {
+                if let Some((t, b1, b2)) = self.intersect_triangle(tri, r, t_min, closest_so_far) {
+                    // Cutouts are skipped here rather than by the caller, so that
+                    // the traversal goes on to find whatever is behind them
+                    if has_cutout
+                        && !self
+                            .material
+                            .is_opaque(&self.make_record(tri, r, t, b1, b2))
+                    {
+                        continue;
+                    }
+                    closest_so_far = t;
+                    closest_hit = Some((tri, t, b1, b2));
+                }
+            }
+        }
+
+        let (tri, t, b1, b2) = closest_hit?;
+        return Some(self.make_record(tri, r, t, b1, b2));
+    }
+}
+
+impl TriangleMesh {
+    // Picks a triangle with probability proportional to its area, then a
+    // uniform point on it. Returns the triangle and barycentrics of the point
+    fn sample_point(&self) -> Option<(usize, f64, f64)> {
+        let total_area = *self.area_cdf.last()?;
+        if total_area <= 0.0 {
+            return None;
+        }
+
+        let target = utils::random_double() * total_area;
+        let index = self
+            .area_cdf
+            .partition_point(|a| *a <= target)
+            .min(self.triangles.len() - 1);
+
+        let su = utils::random_double().sqrt();
+        let b1 = utils::random_double() * su;
+        return Some((index, b1, su - b1));
+    }
+
+    fn point_at(&self, index: usize, b1: f64, b2: f64) -> Vec3 {
+        let tri = &self.triangles[index];
+        return self.positions[tri.positions[0]] * (1.0 - b1 - b2)
+            + self.positions[tri.positions[1]] * b1
+            + self.positions[tri.positions[2]] * b2;
+    }
+}
+
+impl Light for TriangleMesh {
+    // Uniform over the total area, so bigger triangles get picked more often
+    fn sample(&self, origin: &Vec3) -> Option<LightSample<'_>> {
+        let (index, b1, b2) = self.sample_point()?;
+        let point = self.point_at(index, b1, b2);
+        let rec = self.make_record(index, &Ray::new(*origin, point - *origin), 1.0, b1, b2);
+        let pdf = self.pdf(origin, &rec);
+        if pdf <= 0.0 {
+            return None;
+        }
+        return Some(LightSample { rec, pdf });
+    }
+
+    fn pdf(&self, origin: &Vec3, rec: &HitRecord) -> f64 {
+        let to_light = rec.p - *origin;
+        let distance_squared = to_light.squared_length();
+        let cosine = rec.geometric_normal.dot(&to_light).abs() / distance_squared.sqrt();
+        if cosine <= 0.0 || self.area() <= 0.0 {
+            return 0.0;
+        }
+        return distance_squared / (cosine * self.area());
+    }
+
+    fn sample_area(&self) -> Option<HitRecord<'_>> {
+        let (index, b1, b2) = self.sample_point()?;
+        let point = self.point_at(index, b1, b2);
+        let normal = self.geometric_normal(index);
+        let ray = Ray::new(point + normal, -normal);
+        return Some(self.make_record(index, &ray, 1.0, b1, b2));
+    }
+
+    fn area(&self) -> f64 {
+        return self.area_cdf.last().copied().unwrap_or(0.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::Lambertian;
+    use crate::texture::ConstantTexture;
+
+    // Unit square on z = 0, facing +z, split along its 0-2 diagonal
+    fn square() -> TriangleMesh {
+        let positions = vec![
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(1.0, 1.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+        ];
+        let material = Rc::new(Lambertian::new(Vec3::new(0.5, 0.5, 0.5)));
+        return TriangleMesh::from_indices(positions, &[[0, 1, 2], [0, 2, 3]], material);
+    }
+
+    #[test]
+    fn displace_moves_shared_positions_once() {
+        let mut mesh = square();
+        let height = ConstantTexture::new(Vec3::new(0.5, 0.5, 0.5));
+        mesh.displace(&height, 2.0);
+
+        // Corners 0 and 2 are used by both triangles, but only move once
+        for p in mesh.positions.iter() {
+            assert!((p.z - 1.0).abs() < 1e-12, "{:?}", p);
+        }
+
+        // The BVH follows the new positions
+        let ray = Ray::new(Vec3::new(0.25, 0.5, 5.0), Vec3::new(0.0, 0.0, -1.0));
+        let rec = mesh.hit(&ray, 0.001, f64::MAX).unwrap();
+        assert!((rec.p.z - 1.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn mirrored_uvs_flip_the_bitangent() {
+        // v runs down the square instead of up, so along -y rather than +y
+        let mut mesh = square();
+        mesh.uvs = vec![(0.0, 1.0), (1.0, 1.0), (1.0, 0.0), (0.0, 0.0)];
+        for tri in mesh.triangles.iter_mut() {
+            tri.uvs = Some(tri.positions);
+        }
+        let ray = Ray::new(Vec3::new(0.25, 0.5, 5.0), Vec3::new(0.0, 0.0, -1.0));
+        let rec = mesh.hit(&ray, 0.001, f64::MAX).unwrap();
+        assert!((rec.tangent - Vec3::new(1.0, 0.0, 0.0)).length() < 1e-12);
+        assert!((rec.bitangent() - Vec3::new(0.0, -1.0, 0.0)).length() < 1e-12);
+        assert_eq!(rec.handedness, -1.0);
+    }
+
+    // Height is u, so that the two sides of a seam from u = 0 to u = 1 differ
+    struct Ramp;
+
+    impl Texture for Ramp {
+        fn value(&self, u: f64, _v: f64, _p: &Vec3) -> Vec3 {
+            return Vec3::new(u, u, u);
+        }
+    }
+
+    #[test]
+    fn displace_averages_the_sides_of_uv_seams() {
+        // The diagonal is a seam, at u = 0 in the first triangle and u = 1 in
+        // the second. The other two corners are at u = 0.5
+        let mut mesh = square();
+        mesh.uvs = vec![(0.0, 0.0), (0.5, 0.0), (1.0, 0.0)];
+        mesh.triangles[0].uvs = Some([0, 1, 0]);
+        mesh.triangles[1].uvs = Some([2, 2, 1]);
+        mesh.displace(&Ramp, 1.0);
+
+        for p in mesh.positions.iter() {
+            assert!((p.z - 0.5).abs() < 1e-12, "{:?}", p);
+        }
+    }
+
+    #[test]
+    fn displace_follows_shading_normals_across_a_fold() {
+        // Two triangles folded 90 degrees along the x axis, each with its own
+        // normals, so the shared edge moves along the bisector
+        let positions = vec![
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(1.0, 1.0, 0.0),
+            Vec3::new(1.0, 0.0, -1.0),
+        ];
+        let normals = vec![Vec3::new(0.0, 0.0, 1.0), Vec3::new(0.0, -1.0, 0.0)];
+        let triangles = vec![
+            MeshTriangle {
+                positions: [0, 1, 2],
+                normals: Some([0, 0, 0]),
+                uvs: None,
+            },
+            MeshTriangle {
+                positions: [0, 3, 1],
+                normals: Some([1, 1, 1]),
+                uvs: None,
+            },
+        ];
+        let material = Rc::new(Lambertian::new(Vec3::new(0.5, 0.5, 0.5)));
+        let mut mesh = TriangleMesh::new(positions, normals, Vec::new(), triangles, material);
+        let height = ConstantTexture::new(Vec3::new(1.0, 1.0, 1.0));
+        mesh.displace(&height, 2.0_f64.sqrt());
+
+        let bisector = Vec3::new(0.0, -1.0, 1.0);
+        assert!((mesh.positions[0] - bisector).length() < 1e-12);
+        assert!((mesh.positions[1] - (Vec3::new(1.0, 0.0, 0.0) + bisector)).length() < 1e-12);
+        assert!((mesh.positions[2] - Vec3::new(1.0, 1.0, 2.0_f64.sqrt())).length() < 1e-12);
+    }
+}
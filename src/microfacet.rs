@@ -0,0 +1,98 @@
+use std::f64::consts::PI;
+
+use crate::vec3::Vec3;
+
+// Smallest alpha we allow, as the distribution becomes a delta function at 0
+const MIN_ALPHA: f64 = 1e-4;
+
+// Trowbridge-Reitz/GGX normal distribution. Everything here works in the local
+// shading frame, where the macro surface normal is +z and the tangent is +x
+#[derive(Debug, Copy, Clone)]
+pub struct GgxDistribution {
+    pub alpha_x: f64,
+    pub alpha_y: f64,
+}
+
+impl GgxDistribution {
+    pub fn new(alpha_x: f64, alpha_y: f64) -> GgxDistribution {
+        return GgxDistribution {
+            alpha_x: alpha_x.max(MIN_ALPHA),
+            alpha_y: alpha_y.max(MIN_ALPHA),
+        };
+    }
+
+    // Perceptually linear roughness in [0, 1], squared like in most authoring tools
+    pub fn from_roughness(roughness_x: f64, roughness_y: f64) -> GgxDistribution {
+        return GgxDistribution::new(roughness_x * roughness_x, roughness_y * roughness_y);
+    }
+
+    pub fn isotropic(roughness: f64) -> GgxDistribution {
+        return GgxDistribution::from_roughness(roughness, roughness);
+    }
+
+    pub fn d(&self, wm: &Vec3) -> f64 {
+        if wm.z <= 0.0 {
+            return 0.0;
+        }
+
+        let x = wm.x / self.alpha_x;
+        let y = wm.y / self.alpha_y;
+        let denom = x * x + y * y + wm.z * wm.z;
+        return 1.0 / (PI * self.alpha_x * self.alpha_y * denom * denom);
+    }
+
+    // Smith's auxiliary function, for directions on either side of the surface
+    pub fn lambda(&self, w: &Vec3) -> f64 {
+        if w.z == 0.0 {
+            return f64::INFINITY;
+        }
+
+        let ax = self.alpha_x * w.x;
+        let ay = self.alpha_y * w.y;
+        let tan2 = (ax * ax + ay * ay) / (w.z * w.z);
+        return 0.5 * (-1.0 + (1.0 + tan2).sqrt());
+    }
+
+    pub fn g1(&self, w: &Vec3) -> f64 {
+        return 1.0 / (1.0 + self.lambda(w));
+    }
+
+    // Height-correlated masking-shadowing
+    pub fn g(&self, wo: &Vec3, wi: &Vec3) -> f64 {
+        return 1.0 / (1.0 + self.lambda(wo) + self.lambda(wi));
+    }
+
+    // Density of sample_visible() over microfacet normals
+    pub fn pdf_visible(&self, wo: &Vec3, wm: &Vec3) -> f64 {
+        if wo.z == 0.0 {
+            return 0.0;
+        }
+        return self.g1(wo) * wo.dot(wm).max(0.0) * self.d(wm) / wo.z.abs();
+    }
+
+    // Samples a microfacet normal visible from wo, proportionally to its
+    // projected area (Heitz 2018). wo is flipped to the upper hemisphere
+    pub fn sample_visible(&self, wo: &Vec3, u1: f64, u2: f64) -> Vec3 {
+        let wo = if wo.z < 0.0 { -wo } else { *wo };
+
+        // Stretch into the configuration where the distribution is a hemisphere
+        let vh = Vec3::new(self.alpha_x * wo.x, self.alpha_y * wo.y, wo.z).normalized();
+        let lensq = vh.x * vh.x + vh.y * vh.y;
+        let t1 = if lensq > 0.0 {
+            Vec3::new(-vh.y, vh.x, 0.0) / lensq.sqrt()
+        } else {
+            Vec3::new(1.0, 0.0, 0.0)
+        };
+        let t2 = vh.cross(&t1);
+
+        // Uniform point on the projected disk, warped towards the visible half
+        let r = u1.sqrt();
+        let phi = 2.0 * PI * u2;
+        let p1 = r * phi.cos();
+        let s = 0.5 * (1.0 + vh.z);
+        let p2 = (1.0 - s) * (1.0 - p1 * p1).sqrt() + s * r * phi.sin();
+        let nh = t1 * p1 + t2 * p2 + vh * (1.0 - p1 * p1 - p2 * p2).max(0.0).sqrt();
+
+        return Vec3::new(self.alpha_x * nh.x, self.alpha_y * nh.y, nh.z.max(1e-6)).normalized();
+    }
+}
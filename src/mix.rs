@@ -0,0 +1,138 @@
+use std::rc::Rc;
+
+use crate::hitable::HitRecord;
+use crate::material::{Material, ScatteredRay};
+use crate::ray::Ray;
+use crate::texture::Texture;
+use crate::utils;
+use crate::vec3::Vec3;
+
+// Blends two materials with a weight texture, e.g. rust over metal or a dirt
+// mask. 0 is all of a and 1 all of b. Each hit picks one of them at random
+// with those odds and lets it scatter on its own, so both keep their own
+// sampling and the result still averages out to the blend. Cut out parts only
+// count where rays actually stop, so the odds are scaled by each opacity
+pub struct MixMaterial {
+    pub a: Rc<dyn Material>,
+    pub b: Rc<dyn Material>,
+    pub weight: Rc<dyn Texture>,
+}
+
+impl MixMaterial {
+    pub fn new(a: Rc<dyn Material>, b: Rc<dyn Material>, weight: Rc<dyn Texture>) -> MixMaterial {
+        return MixMaterial { a, b, weight };
+    }
+
+    fn weight_at(&self, rec: &HitRecord) -> f64 {
+        return self.weight.scalar(rec.u, rec.v, &rec.p).clamp(0.0, 1.0);
+    }
+
+    // Odds of a and b at a point that a ray stopped at
+    fn weights(&self, rec: &HitRecord) -> (f64, f64) {
+        let weight = self.weight_at(rec);
+        let a = (1.0 - weight) * self.a.opacity(rec);
+        let b = weight * self.b.opacity(rec);
+        if a + b <= 0.0 {
+            return (1.0 - weight, weight);
+        }
+        return (a / (a + b), b / (a + b));
+    }
+
+    fn pick(&self, rec: &HitRecord) -> &dyn Material {
+        let (_, b) = self.weights(rec);
+        if b > 0.0 && utils::random_double() < b {
+            return self.b.as_ref();
+        }
+        return self.a.as_ref();
+    }
+}
+
+impl Material for MixMaterial {
+    fn scatter(&self, r_in: &Ray, rec: &HitRecord) -> Option<ScatteredRay> {
+        return self.pick(rec).scatter(r_in, rec);
+    }
+
+    fn opacity(&self, rec: &HitRecord) -> f64 {
+        let weight = self.weight_at(rec);
+        return (1.0 - weight) * self.a.opacity(rec) + weight * self.b.opacity(rec);
+    }
+
+    fn has_cutout(&self) -> bool {
+        return self.a.has_cutout() || self.b.has_cutout();
+    }
+
+    // Emission doesn't need to be sampled, so it's blended exactly
+    fn emitted(&self, rec: &HitRecord) -> Vec3 {
+        let (a, b) = self.weights(rec);
+        return self.a.emitted(rec) * a + self.b.emitted(rec) * b;
+    }
+
+    fn emitted_at(&self, rec: &HitRecord, lambda: f64) -> f64 {
+        let (a, b) = self.weights(rec);
+        return self.a.emitted_at(rec, lambda) * a + self.b.emitted_at(rec, lambda) * b;
+    }
+
+    fn is_dispersive(&self) -> bool {
+        return self.a.is_dispersive() || self.b.is_dispersive();
+    }
+
+    fn eval(&self, rec: &HitRecord, wi: &Vec3, wo: &Vec3) -> Vec3 {
+        let (a, b) = self.weights(rec);
+        return self.a.eval(rec, wi, wo) * a + self.b.eval(rec, wi, wo) * b;
+    }
+
+    // scatter() picks either material with the same odds, so the densities mix
+    // the same way
+    fn pdf(&self, rec: &HitRecord, wi: &Vec3, wo: &Vec3) -> f64 {
+        let (a, b) = self.weights(rec);
+        return self.a.pdf(rec, wi, wo) * a + self.b.pdf(rec, wi, wo) * b;
+    }
+
+    // A delta half would be missing from both eval() and pdf()
+    fn is_delta(&self) -> bool {
+        return self.a.is_delta() || self.b.is_delta();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cutout::{Cutout, CutoutMode};
+    use crate::material::Lambertian;
+    use crate::texture::ConstantTexture;
+
+    fn constant(value: f64) -> Rc<dyn Texture> {
+        return Rc::new(ConstantTexture::new(Vec3::new(value, value, value)));
+    }
+
+    #[test]
+    fn cut_out_half_never_scatters() {
+        let red = Rc::new(Lambertian::new(Vec3::new(0.8, 0.0, 0.0)));
+        let green = Rc::new(Lambertian::new(Vec3::new(0.0, 0.8, 0.0)));
+        let hole = Rc::new(Cutout::new(red, constant(0.0), CutoutMode::Threshold(0.5)));
+        let mix = MixMaterial::new(hole, green.clone(), constant(0.5));
+        let rec = HitRecord {
+            t: 1.0,
+            p: Vec3::new(0.0, 0.0, 0.0),
+            normal: Vec3::new(0.0, 0.0, 1.0),
+            geometric_normal: Vec3::new(0.0, 0.0, 1.0),
+            tangent: Vec3::new(1.0, 0.0, 0.0),
+            handedness: 1.0,
+            u: 0.0,
+            v: 0.0,
+            mat_ptr: &mix,
+            light: None,
+        };
+
+        assert_eq!(mix.opacity(&rec), 0.5);
+        assert_eq!(mix.weights(&rec), (0.0, 1.0));
+        let dir = Vec3::new(0.0, 0.6, 0.8);
+        let difference = mix.eval(&rec, &dir, &dir) - green.eval(&rec, &dir, &dir);
+        assert_eq!(difference.squared_length(), 0.0);
+        for _ in 0..100 {
+            let scattered = mix.scatter(&Ray::new(rec.p + rec.normal, -rec.normal), &rec);
+            let difference = scattered.unwrap().attenuation - green.albedo;
+            assert_eq!(difference.squared_length(), 0.0);
+        }
+    }
+}
@@ -0,0 +1,120 @@
+use std::rc::Rc;
+
+use crate::hitable::HitRecord;
+use crate::material::{Material, ScatteredRay};
+use crate::ray::Ray;
+use crate::texture::Texture;
+use crate::utils;
+use crate::vec3::Vec3;
+
+// UV offset used to take finite differences of bump maps
+const BUMP_DELTA: f64 = 0.0005;
+
+// Copy of rec with its shading frame rotated to the given normal. Normals that
+// end up below the actual surface are discarded, as they would make the
+// material scatter into the wrong side
+fn with_shading_normal<'a>(rec: &HitRecord<'a>, normal: Vec3) -> HitRecord<'a> {
+    let mut shaded = *rec;
+    if normal.dot(&rec.geometric_normal) <= 0.0 || normal.squared_length() == 0.0 {
+        return shaded;
+    }
+
+    shaded.normal = normal;
+    shaded.tangent = utils::tangent_from(&normal, &rec.tangent);
+    return shaded;
+}
+
+// Perturbs the shading normal of base with a tangent space normal map, where
+// (0.5, 0.5, 1.0) is the unperturbed normal. strength scales the tangential part
+pub struct NormalMap {
+    pub base: Rc<dyn Material>,
+    pub map: Rc<dyn Texture>,
+    pub strength: f64,
+}
+
+impl NormalMap {
+    pub fn new(base: Rc<dyn Material>, map: Rc<dyn Texture>, strength: f64) -> NormalMap {
+        return NormalMap {
+            base,
+            map,
+            strength,
+        };
+    }
+
+    fn shaded<'a>(&self, rec: &HitRecord<'a>) -> HitRecord<'a> {
+        let texel = self.map.value(rec.u, rec.v, &rec.p);
+        let local = Vec3::new(
+            (texel.x * 2.0 - 1.0) * self.strength,
+            (texel.y * 2.0 - 1.0) * self.strength,
+            (texel.z * 2.0 - 1.0).max(0.0),
+        );
+        let normal = rec.local_to_world(&local).normalized();
+        return with_shading_normal(rec, normal);
+    }
+}
+
+impl Material for NormalMap {
+    fn scatter(&self, r_in: &Ray, rec: &HitRecord) -> Option<ScatteredRay> {
+        return self.base.scatter(r_in, &self.shaded(rec));
+    }
+
+    fn base(&self) -> Option<&dyn Material> {
+        return Some(self.base.as_ref());
+    }
+
+    fn eval(&self, rec: &HitRecord, wi: &Vec3, wo: &Vec3) -> Vec3 {
+        return self.base.eval(&self.shaded(rec), wi, wo);
+    }
+
+    fn pdf(&self, rec: &HitRecord, wi: &Vec3, wo: &Vec3) -> f64 {
+        return self.base.pdf(&self.shaded(rec), wi, wo);
+    }
+}
+
+// Perturbs the shading normal of base with the gradient of a scalar height
+// texture. strength converts height differences in UV space into normal tilt
+pub struct BumpMap {
+    pub base: Rc<dyn Material>,
+    pub height: Rc<dyn Texture>,
+    pub strength: f64,
+}
+
+impl BumpMap {
+    pub fn new(base: Rc<dyn Material>, height: Rc<dyn Texture>, strength: f64) -> BumpMap {
+        return BumpMap {
+            base,
+            height,
+            strength,
+        };
+    }
+
+    fn shaded<'a>(&self, rec: &HitRecord<'a>) -> HitRecord<'a> {
+        let h = self.height.scalar(rec.u, rec.v, &rec.p);
+        let h_u = self.height.scalar(rec.u + BUMP_DELTA, rec.v, &rec.p);
+        let h_v = self.height.scalar(rec.u, rec.v + BUMP_DELTA, &rec.p);
+        let dh_du = (h_u - h) / BUMP_DELTA;
+        let dh_dv = (h_v - h) / BUMP_DELTA;
+
+        let normal = (rec.normal - (rec.tangent * dh_du + rec.bitangent() * dh_dv) * self.strength)
+            .normalized();
+        return with_shading_normal(rec, normal);
+    }
+}
+
+impl Material for BumpMap {
+    fn scatter(&self, r_in: &Ray, rec: &HitRecord) -> Option<ScatteredRay> {
+        return self.base.scatter(r_in, &self.shaded(rec));
+    }
+
+    fn base(&self) -> Option<&dyn Material> {
+        return Some(self.base.as_ref());
+    }
+
+    fn eval(&self, rec: &HitRecord, wi: &Vec3, wo: &Vec3) -> Vec3 {
+        return self.base.eval(&self.shaded(rec), wi, wo);
+    }
+
+    fn pdf(&self, rec: &HitRecord, wi: &Vec3, wo: &Vec3) -> f64 {
+        return self.base.pdf(&self.shaded(rec), wi, wo);
+    }
+}
@@ -0,0 +1,415 @@
+use crate::camera::Camera;
+use crate::hitable::{HitRecord, Hitable};
+use crate::integrator::{Integrator, Splat};
+use crate::light;
+use crate::medium;
+use crate::ray::Ray;
+use crate::sampler::Sampler;
+use crate::scene::Scene;
+use crate::spectrum::{SampledSpectrum, SampledWavelengths, SPECTRAL_SAMPLES};
+use crate::vec3::Vec3;
+
+// Unidirectional path tracer. At every surface that isn't a perfect mirror or
+// glass it samples a point on one of the lights, and weighs that against
+// hitting lights by scattering with multiple importance sampling.
+//
+// Paths end after max_depth bounces. Past roulette_depth bounces, they also get
+// stopped at random with a probability that grows as their throughput drops,
+// which keeps the result unbiased while spending less time on dim paths
+pub struct PathTracer {
+    pub max_depth: u32,
+    pub roulette_depth: u32,
+    // Carries a few wavelengths along each path instead of RGB. Handles
+    // dispersion and blackbody emitters properly, at the cost of color noise
+    pub spectral: bool,
+}
+
+impl PathTracer {
+    pub fn new(max_depth: u32) -> PathTracer {
+        return PathTracer {
+            max_depth,
+            roulette_depth: 5,
+            spectral: false,
+        };
+    }
+
+    // Use max_depth or more to turn Russian roulette off
+    pub fn with_roulette_depth(mut self, roulette_depth: u32) -> PathTracer {
+        self.roulette_depth = roulette_depth;
+        return self;
+    }
+
+    pub fn with_spectral(mut self, spectral: bool) -> PathTracer {
+        self.spectral = spectral;
+        return self;
+    }
+
+    // Probability of continuing a path with the given largest throughput
+    fn survival_probability(&self, depth: u32, max_throughput: f64) -> f64 {
+        if depth < self.roulette_depth {
+            return 1.0;
+        }
+        return max_throughput.clamp(0.0, 1.0);
+    }
+
+    fn radiance_rgb(&self, camera_ray: &Ray, scene: &Scene, sampler: &mut dyn Sampler) -> Vec3 {
+        let mut ray = *camera_ray;
+        let mut result = Vec3::new(0.0, 0.0, 0.0);
+        let mut throughput = Vec3::new(1.0, 1.0, 1.0);
+        // Density with which the previous vertex scattered the ray, if lights
+        // were sampled there too
+        let mut bsdf_pdf: Option<f64> = None;
+
+        for depth in 0..=self.max_depth {
+            let rec = match scene.world.hit(&ray, 0.001, f64::MAX) {
+                Some(rec) => rec,
+                None => {
+                    result += throughput * scene.background.radiance(&ray);
+                    break;
+                }
+            };
+
+            // Whatever medium we travelled through to get here absorbed some of the
+            // light, and may have scattered the ray before it got this far
+            let medium = ray.media.sample(rec.t * ray.dir.length());
+            throughput *= medium.weight;
+            if let Some(distance) = medium.scatter_distance {
+                if depth == self.max_depth {
+                    break;
+                }
+                let p = ray.point_at_parameter(distance / ray.dir.length());
+                let dir = medium::sample_henyey_greenstein(&ray.dir, ray.media.anisotropy());
+                ray = ray.spawn(p, dir);
+                bsdf_pdf = None;
+            } else {
+                let mut emitted = rec.mat_ptr.emitted(&rec);
+                if let Some(pdf) = bsdf_pdf {
+                    if emitted.squared_length() > 0.0 {
+                        emitted = emitted
+                            * light::power_heuristic(pdf, scene.lights.pdf(&ray.orig, &rec));
+                    }
+                }
+                result += throughput * emitted;
+                if depth == self.max_depth {
+                    break;
+                }
+
+                let sample_lights = !rec.mat_ptr.is_delta() && !scene.lights.is_empty();
+                if sample_lights {
+                    result += throughput * sample_direct(&ray, &rec, scene);
+                }
+
+                let scattered = match rec.mat_ptr.scatter(&ray, &rec) {
+                    Some(scattered) => scattered,
+                    None => break,
+                };
+                if !rec.is_consistent(&scattered.out_ray.dir) {
+                    break;
+                }
+
+                bsdf_pdf = if sample_lights {
+                    let wi = scattered.out_ray.dir.normalized();
+                    Some(rec.mat_ptr.pdf(&rec, &wi, &-ray.dir.normalized()))
+                } else {
+                    None
+                };
+                throughput *= scattered.attenuation;
+                ray = scattered.out_ray;
+            }
+
+            let max_throughput = throughput.x.max(throughput.y).max(throughput.z);
+            let survival = self.survival_probability(depth, max_throughput);
+            if survival < 1.0 {
+                if sampler.next_1d() >= survival {
+                    break;
+                }
+                throughput /= survival;
+            }
+        }
+
+        return result;
+    }
+
+    // Same as radiance_rgb(), for the wavelengths of a spectral path. Colors of
+    // materials and the background are upsampled to spectra as needed
+    fn radiance_spectral(
+        &self,
+        camera_ray: &Ray,
+        scene: &Scene,
+        sampler: &mut dyn Sampler,
+        wavelengths: &mut SampledWavelengths,
+    ) -> SampledSpectrum {
+        let mut ray = *camera_ray;
+        let mut result = [0.0; SPECTRAL_SAMPLES];
+        let mut throughput = [1.0; SPECTRAL_SAMPLES];
+        let mut bsdf_pdf: Option<f64> = None;
+
+        for depth in 0..=self.max_depth {
+            let rec = match scene.world.hit(&ray, 0.001, f64::MAX) {
+                Some(rec) => rec,
+                None => {
+                    let background = wavelengths.from_rgb(&scene.background.radiance(&ray));
+                    for i in 0..SPECTRAL_SAMPLES {
+                        result[i] += throughput[i] * background[i];
+                    }
+                    break;
+                }
+            };
+
+            let medium = ray.media.sample_at(rec.t * ray.dir.length(), wavelengths);
+            for (t, weight) in throughput.iter_mut().zip(medium.weight) {
+                *t *= weight;
+            }
+            if let Some(distance) = medium.scatter_distance {
+                if depth == self.max_depth {
+                    break;
+                }
+                let p = ray.point_at_parameter(distance / ray.dir.length());
+                let dir = medium::sample_henyey_greenstein(&ray.dir, ray.media.anisotropy());
+                ray = ray.spawn(p, dir);
+                bsdf_pdf = None;
+            } else {
+                let emitted = wavelengths.from_fn(|lambda| rec.mat_ptr.emitted_at(&rec, lambda));
+                let mis_weight = match bsdf_pdf {
+                    Some(pdf) if emitted.iter().any(|e| *e != 0.0) => {
+                        light::power_heuristic(pdf, scene.lights.pdf(&ray.orig, &rec))
+                    }
+                    _ => 1.0,
+                };
+                for i in 0..SPECTRAL_SAMPLES {
+                    result[i] += throughput[i] * emitted[i] * mis_weight;
+                }
+                if depth == self.max_depth {
+                    break;
+                }
+
+                // eval() only knows about RGB, so dispersive surfaces are left to
+                // scattering
+                let sample_lights = !rec.mat_ptr.is_delta()
+                    && !rec.mat_ptr.is_dispersive()
+                    && !scene.lights.is_empty();
+                if sample_lights {
+                    let direct = sample_direct_spectral(&ray, &rec, scene, wavelengths);
+                    for i in 0..SPECTRAL_SAMPLES {
+                        result[i] += throughput[i] * direct[i];
+                    }
+                }
+
+                let scattered = match rec.mat_ptr.scatter(&ray, &rec) {
+                    Some(scattered) => scattered,
+                    None => break,
+                };
+                if !rec.is_consistent(&scattered.out_ray.dir) {
+                    break;
+                }
+
+                // The scattered ray was bent for the hero wavelength only
+                if rec.mat_ptr.is_dispersive() {
+                    wavelengths.terminate_secondary();
+                }
+
+                bsdf_pdf = if sample_lights {
+                    let wi = scattered.out_ray.dir.normalized();
+                    Some(rec.mat_ptr.pdf(&rec, &wi, &-ray.dir.normalized()))
+                } else {
+                    None
+                };
+                let attenuation = wavelengths.from_rgb(&scattered.attenuation);
+                for (t, a) in throughput.iter_mut().zip(attenuation) {
+                    *t *= a;
+                }
+                ray = scattered.out_ray;
+            }
+
+            let max_throughput = throughput
+                .iter()
+                .take(wavelengths.count)
+                .fold(0.0, |m: f64, t| m.max(*t));
+            let survival = self.survival_probability(depth, max_throughput);
+            if survival < 1.0 {
+                if sampler.next_1d() >= survival {
+                    break;
+                }
+                for t in throughput.iter_mut() {
+                    *t /= survival;
+                }
+            }
+        }
+
+        return result;
+    }
+}
+
+impl Integrator for PathTracer {
+    fn radiance(
+        &self,
+        ray: &Ray,
+        scene: &Scene,
+        _camera: &Camera,
+        sampler: &mut dyn Sampler,
+        _splats: &mut Vec<Splat>,
+    ) -> Vec3 {
+        if !self.spectral {
+            return self.radiance_rgb(ray, scene, sampler);
+        }
+
+        let mut wavelengths = SampledWavelengths::sample();
+        let mut ray = *ray;
+        ray.wavelength = Some(wavelengths.hero());
+        let radiance = self.radiance_spectral(&ray, scene, sampler, &mut wavelengths);
+        return wavelengths.to_rgb(&radiance);
+    }
+}
+
+// Light arriving at rec from a point picked on one of the lights, weighted
+// against finding the same point by scattering. rec must be non-delta
+pub fn sample_direct(ray: &Ray, rec: &HitRecord, scene: &Scene) -> Vec3 {
+    let black = Vec3::new(0.0, 0.0, 0.0);
+    let sample = match scene.lights.sample(&rec.p) {
+        Some(sample) => sample,
+        None => return black,
+    };
+
+    let to_light = sample.rec.p - rec.p;
+    let distance = to_light.length();
+    let wi = to_light / distance;
+    let wo = -ray.dir.normalized();
+    if !rec.is_consistent(&wi) {
+        return black;
+    }
+    let f = rec.mat_ptr.eval(rec, &wi, &wo);
+    if f.squared_length() <= 0.0 {
+        return black;
+    }
+
+    let shadow = ray.spawn(rec.p, wi);
+    if scene.world.hit(&shadow, 0.001, distance - 0.001).is_some() {
+        return black;
+    }
+
+    let weight = light::power_heuristic(sample.pdf, rec.mat_ptr.pdf(rec, &wi, &wo));
+    let emitted = sample.rec.mat_ptr.emitted(&sample.rec);
+    return emitted
+        * f
+        * ray.media.transmittance(distance)
+        * (wi.dot(&rec.normal).abs() * weight / sample.pdf);
+}
+
+// Same as sample_direct(), across the wavelengths of a spectral path
+pub fn sample_direct_spectral(
+    ray: &Ray,
+    rec: &HitRecord,
+    scene: &Scene,
+    wavelengths: &SampledWavelengths,
+) -> SampledSpectrum {
+    let black = [0.0; SPECTRAL_SAMPLES];
+    let sample = match scene.lights.sample(&rec.p) {
+        Some(sample) => sample,
+        None => return black,
+    };
+
+    let to_light = sample.rec.p - rec.p;
+    let distance = to_light.length();
+    let wi = to_light / distance;
+    let wo = -ray.dir.normalized();
+    if !rec.is_consistent(&wi) {
+        return black;
+    }
+    let f = rec.mat_ptr.eval(rec, &wi, &wo);
+    if f.squared_length() <= 0.0 {
+        return black;
+    }
+
+    let shadow = ray.spawn(rec.p, wi);
+    if scene.world.hit(&shadow, 0.001, distance - 0.001).is_some() {
+        return black;
+    }
+
+    let weight = light::power_heuristic(sample.pdf, rec.mat_ptr.pdf(rec, &wi, &wo));
+    let scale = wi.dot(&rec.normal).abs() * weight / sample.pdf;
+    let f = wavelengths.from_rgb(&f);
+    let mut result = wavelengths.from_fn(|lambda| {
+        let emitted = sample.rec.mat_ptr.emitted_at(&sample.rec, lambda);
+        return emitted * ray.media.transmittance_at(distance, lambda);
+    });
+    for (r, f) in result.iter_mut().zip(f) {
+        *r *= f * scale;
+    }
+    return result;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hitable::HitableList;
+    use crate::light::LightList;
+    use crate::material::Lambertian;
+    use crate::sampler::RandomSampler;
+    use crate::scene::{self, Background};
+    use crate::sphere::Sphere;
+    use std::rc::Rc;
+
+    fn average(tracer: &PathTracer, scene: &Scene, from: Vec3, to: Vec3, samples: u32) -> Vec3 {
+        let ray = Ray::new(from, to - from);
+        let mut sum = Vec3::new(0.0, 0.0, 0.0);
+        for _ in 0..samples {
+            sum += tracer.radiance_rgb(&ray, scene, &mut RandomSampler);
+        }
+        return sum / samples as f64;
+    }
+
+    // A convex diffuse object in uniform white light reflects exactly its
+    // albedo, since every scattered ray goes straight back out
+    fn furnace() -> Scene<'static> {
+        let sphere = Sphere::new(
+            Vec3::new(0.0, 0.0, 0.0),
+            1.0,
+            Rc::new(Lambertian::new(Vec3::new(0.5, 0.5, 0.5))),
+        );
+        let world = HitableList {
+            list: vec![Box::new(sphere)],
+        };
+        return Scene::new(world, LightList::new(Vec::new()))
+            .with_background(Background::Solid(Vec3::new(1.0, 1.0, 1.0)));
+    }
+
+    #[test]
+    fn convex_sphere_in_a_white_furnace_reflects_its_albedo() {
+        let scene = furnace();
+        let tracer = PathTracer::new(10);
+        let ray = Ray::new(Vec3::new(0.3, 0.2, 5.0), Vec3::new(0.0, 0.0, -1.0));
+        for _ in 0..100 {
+            let radiance = tracer.radiance_rgb(&ray, &scene, &mut RandomSampler);
+            assert!((radiance.x - 0.5).abs() < 1e-9, "got {}", radiance.x);
+        }
+    }
+
+    #[test]
+    fn russian_roulette_keeps_the_furnace_unbiased() {
+        let scene = furnace();
+        let tracer = PathTracer::new(10).with_roulette_depth(0);
+        let from = Vec3::new(0.3, 0.2, 5.0);
+        let radiance = average(&tracer, &scene, from, Vec3::new(0.3, 0.2, 0.0), 20000);
+        assert!((radiance.x - 0.5).abs() < 0.02, "got {}", radiance.x);
+    }
+
+    #[test]
+    fn light_sampling_matches_closed_form() {
+        let scene = scene::lit_floor(true, false);
+        let tracer = PathTracer::new(10);
+        let origin = Vec3::new(0.0, 0.0, 0.0);
+        let radiance = average(&tracer, &scene, Vec3::new(3.0, 2.0, 0.0), origin, 4000);
+        let expected = scene::lit_floor_radiance();
+        assert!((radiance.x - expected).abs() < 0.01, "got {}", radiance.x);
+    }
+
+    #[test]
+    fn scattering_alone_matches_closed_form() {
+        let scene = scene::lit_floor(false, false);
+        let tracer = PathTracer::new(10);
+        let origin = Vec3::new(0.0, 0.0, 0.0);
+        let radiance = average(&tracer, &scene, Vec3::new(3.0, 2.0, 0.0), origin, 20000);
+        let expected = scene::lit_floor_radiance();
+        assert!((radiance.x - expected).abs() < 0.01, "got {}", radiance.x);
+    }
+}
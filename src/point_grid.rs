@@ -0,0 +1,95 @@
+use std::collections::HashMap;
+
+use crate::vec3::Vec3;
+
+// Spheres on a uniform grid, for finding the ones a point might be in. Each
+// sphere goes into every cell it overlaps, so that lookups only need the cell
+// of the point. Cells should be about as large as the largest sphere
+pub struct PointGrid {
+    cell_size: f64,
+    cells: HashMap<(i64, i64, i64), Vec<usize>>,
+}
+
+impl PointGrid {
+    pub fn new(cell_size: f64) -> PointGrid {
+        return PointGrid {
+            cell_size: cell_size.max(1e-9),
+            cells: HashMap::new(),
+        };
+    }
+
+    pub fn insert(&mut self, index: usize, center: &Vec3, radius: f64) {
+        let extent = Vec3::new(radius, radius, radius);
+        let min = self.cell(&(*center - extent));
+        let max = self.cell(&(*center + extent));
+        for x in min.0..=max.0 {
+            for y in min.1..=max.1 {
+                for z in min.2..=max.2 {
+                    self.cells.entry((x, y, z)).or_default().push(index);
+                }
+            }
+        }
+    }
+
+    fn cell(&self, p: &Vec3) -> (i64, i64, i64) {
+        return (
+            (p.x / self.cell_size).floor() as i64,
+            (p.y / self.cell_size).floor() as i64,
+            (p.z / self.cell_size).floor() as i64,
+        );
+    }
+
+    // Indices of the spheres that might contain p
+    pub fn near(&self, p: &Vec3) -> &[usize] {
+        match self.cells.get(&self.cell(p)) {
+            Some(indices) => return indices,
+            None => return &[],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_spheres_around_a_point() {
+        let mut grid = PointGrid::new(0.5);
+        grid.insert(0, &Vec3::new(0.0, 0.0, 0.0), 0.5);
+        grid.insert(1, &Vec3::new(10.0, 0.0, 0.0), 0.5);
+
+        assert_eq!(grid.near(&Vec3::new(0.1, -0.2, 0.3)), &[0]);
+        assert_eq!(grid.near(&Vec3::new(10.2, 0.1, -0.1)), &[1]);
+        assert!(grid.near(&Vec3::new(5.0, 0.0, 0.0)).is_empty());
+    }
+
+    #[test]
+    fn spheres_reach_into_neighboring_cells() {
+        let mut grid = PointGrid::new(1.0);
+        // Straddles the cell boundaries at 0 on every axis
+        grid.insert(7, &Vec3::new(0.1, 0.1, 0.1), 0.4);
+
+        for p in [
+            Vec3::new(-0.2, 0.1, 0.1),
+            Vec3::new(0.1, -0.2, 0.1),
+            Vec3::new(0.1, 0.1, -0.2),
+            Vec3::new(-0.1, -0.1, -0.1),
+        ]
+        .iter()
+        {
+            assert_eq!(grid.near(p), &[7], "{:?}", p);
+        }
+        assert!(grid.near(&Vec3::new(-1.5, 0.1, 0.1)).is_empty());
+    }
+
+    #[test]
+    fn several_spheres_share_a_cell() {
+        let mut grid = PointGrid::new(1.0);
+        grid.insert(2, &Vec3::new(0.5, 0.5, 0.5), 0.1);
+        grid.insert(5, &Vec3::new(0.6, 0.5, 0.5), 0.1);
+
+        let mut near = grid.near(&Vec3::new(0.55, 0.5, 0.5)).to_vec();
+        near.sort();
+        assert_eq!(near, vec![2, 5]);
+    }
+}
@@ -0,0 +1,285 @@
+use std::f64::consts::PI;
+
+use crate::hitable::HitRecord;
+use crate::material::{Material, ScatteredRay};
+use crate::medium::{self, Medium};
+use crate::microfacet::GgxDistribution;
+use crate::ray::Ray;
+use crate::rough_dielectric;
+use crate::utils;
+use crate::vec3::Vec3;
+
+fn mix(a: &Vec3, b: &Vec3, t: f64) -> Vec3 {
+    return *a * (1.0 - t) + *b * t;
+}
+
+fn schlick_weight(cosine: f64) -> f64 {
+    return (1.0 - cosine).clamp(0.0, 1.0).powi(5);
+}
+
+// Berry's distribution (GTR with gamma = 1), used for the clearcoat highlight
+fn gtr1(cos_theta_m: f64, alpha: f64) -> f64 {
+    if alpha >= 1.0 {
+        return 1.0 / PI;
+    }
+    let a2 = alpha * alpha;
+    let t = 1.0 + (a2 - 1.0) * cos_theta_m * cos_theta_m;
+    return (a2 - 1.0) / (PI * a2.ln() * t);
+}
+
+fn sample_gtr1(alpha: f64, u1: f64, u2: f64) -> Vec3 {
+    let a2 = alpha * alpha;
+    let cos_theta = ((1.0 - a2.powf(1.0 - u1)) / (1.0 - a2)).max(0.0).sqrt();
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * PI * u2;
+    return Vec3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta);
+}
+
+// Lobe sampling probabilities for the opaque part of the material
+struct LobeWeights {
+    diffuse: f64,
+    specular: f64,
+    clearcoat: f64,
+}
+
+// Disney style "principled" material (Burley 2012, 2015), meant as the one
+// material that imported glTF or Blender materials map onto. All parameters
+// except base_color, ior and emission are in [0, 1].
+//
+// transmission blends between the opaque surface and rough glass of the same
+// roughness, which is picked stochastically per hit. Like the original, the
+// opaque part gains some energy at grazing angles, as diffuse isn't reduced by
+// the specular reflection on top of it
+pub struct Principled {
+    pub base_color: Vec3,
+    pub metallic: f64,
+    pub roughness: f64,
+    // Scales the reflectance of dielectrics at normal incidence, 0.5 being 4%
+    pub specular: f64,
+    // Tints dielectric reflections towards the base color
+    pub specular_tint: f64,
+    pub sheen: f64,
+    pub sheen_tint: f64,
+    pub clearcoat: f64,
+    pub clearcoat_gloss: f64,
+    pub transmission: f64,
+    pub ior: f64,
+    pub emission: Vec3,
+    medium_id: u32,
+}
+
+impl Principled {
+    pub fn new(base_color: Vec3) -> Principled {
+        return Principled {
+            base_color,
+            metallic: 0.0,
+            roughness: 0.5,
+            specular: 0.5,
+            specular_tint: 0.0,
+            sheen: 0.0,
+            sheen_tint: 0.5,
+            clearcoat: 0.0,
+            clearcoat_gloss: 1.0,
+            transmission: 0.0,
+            ior: 1.45,
+            emission: Vec3::new(0.0, 0.0, 0.0),
+            medium_id: medium::new_medium_id(),
+        };
+    }
+
+    pub fn medium(&self) -> Medium {
+        return Medium {
+            id: self.medium_id,
+            ior: self.ior,
+            priority: 0,
+            absorption: Vec3::new(0.0, 0.0, 0.0),
+            dispersion: None,
+            scattering: Vec3::new(0.0, 0.0, 0.0),
+            anisotropy: 0.0,
+        };
+    }
+
+    fn distribution(&self) -> GgxDistribution {
+        return GgxDistribution::isotropic(self.roughness);
+    }
+
+    fn clearcoat_alpha(&self) -> f64 {
+        return 0.1 * (1.0 - self.clearcoat_gloss) + 0.001 * self.clearcoat_gloss;
+    }
+
+    // Base color with its luminance taken out
+    fn tint(&self) -> Vec3 {
+        let lum = utils::luminance(&self.base_color);
+        if lum > 0.0 {
+            return self.base_color / lum;
+        }
+        return Vec3::new(1.0, 1.0, 1.0);
+    }
+
+    fn lobe_weights(&self) -> LobeWeights {
+        let diffuse = 1.0 - self.metallic;
+        let specular = 0.25 + 0.75 * self.metallic;
+        let clearcoat = 0.25 * self.clearcoat;
+        let total = diffuse + specular + clearcoat;
+        return LobeWeights {
+            diffuse: diffuse / total,
+            specular: specular / total,
+            clearcoat: clearcoat / total,
+        };
+    }
+
+    // BRDF of the opaque part, with both directions in the local shading frame
+    // and above the surface
+    fn eval_opaque(&self, wo: &Vec3, wi: &Vec3) -> Vec3 {
+        let wh = (*wo + *wi).normalized();
+        let cos_d = wi.dot(&wh);
+        let white = Vec3::new(1.0, 1.0, 1.0);
+
+        // Diffuse with a retro-reflective boost at grazing angles on rough surfaces
+        let fd90 = 0.5 + 2.0 * self.roughness * cos_d * cos_d;
+        let fl = 1.0 + (fd90 - 1.0) * schlick_weight(wi.z);
+        let fv = 1.0 + (fd90 - 1.0) * schlick_weight(wo.z);
+        let diffuse = self.base_color * (fl * fv / PI);
+
+        let sheen_color = mix(&white, &self.tint(), self.sheen_tint);
+        let sheen = sheen_color * (self.sheen * schlick_weight(cos_d));
+
+        let specular_color = mix(&white, &self.tint(), self.specular_tint);
+        let f0 = mix(
+            &(specular_color * (0.08 * self.specular)),
+            &self.base_color,
+            self.metallic,
+        );
+        let fresnel = mix(&f0, &white, schlick_weight(cos_d));
+        let distribution = self.distribution();
+        let specular =
+            fresnel * (distribution.d(&wh) * distribution.g(wo, wi) / (4.0 * wo.z * wi.z));
+
+        let mut clearcoat = 0.0;
+        if self.clearcoat > 0.0 {
+            let d = gtr1(wh.z, self.clearcoat_alpha());
+            let f = 0.04 + 0.96 * schlick_weight(cos_d);
+            let g = GgxDistribution::new(0.25, 0.25).g(wo, wi);
+            clearcoat = 0.25 * self.clearcoat * d * f * g / (4.0 * wo.z * wi.z);
+        }
+
+        return (diffuse + sheen) * (1.0 - self.metallic)
+            + specular
+            + Vec3::new(clearcoat, clearcoat, clearcoat);
+    }
+
+    // Combined pdf of sampling wi through any of the opaque lobes
+    fn pdf_opaque(&self, wo: &Vec3, wi: &Vec3, weights: &LobeWeights) -> f64 {
+        let wh = (*wo + *wi).normalized();
+        let wo_dot_wh = wo.dot(&wh);
+        if wo_dot_wh <= 0.0 {
+            return weights.diffuse * wi.z / PI;
+        }
+
+        let specular = self.distribution().pdf_visible(wo, &wh) / (4.0 * wo_dot_wh);
+        let clearcoat = gtr1(wh.z, self.clearcoat_alpha()) * wh.z / (4.0 * wo_dot_wh);
+        return weights.diffuse * wi.z / PI
+            + weights.specular * specular
+            + weights.clearcoat * clearcoat;
+    }
+
+    fn scatter_glass(&self, r_in: &Ray, rec: &HitRecord) -> Option<ScatteredRay> {
+        let mut scattered = rough_dielectric::scatter_rough_dielectric(
+            r_in,
+            rec,
+            &self.medium(),
+            &self.distribution(),
+        )?;
+
+        // Tint each crossing by the square root so that going in and back out
+        // comes out as the base color
+        let crossed = r_in.dir.dot(&rec.normal) * scattered.out_ray.dir.dot(&rec.normal) > 0.0;
+        if crossed {
+            let tint = Vec3::new(
+                self.base_color.x.sqrt(),
+                self.base_color.y.sqrt(),
+                self.base_color.z.sqrt(),
+            );
+            scattered.attenuation *= tint;
+        }
+        return Some(scattered);
+    }
+}
+
+impl Material for Principled {
+    fn scatter(&self, r_in: &Ray, rec: &HitRecord) -> Option<ScatteredRay> {
+        let wo = rec.world_to_local(&-r_in.dir.normalized());
+
+        // Only transmitted light can be on the inside, so it always leaves
+        // through the glass
+        let glass = self.transmission * (1.0 - self.metallic);
+        if glass > 0.0 && (wo.z <= 0.0 || utils::random_double() < glass) {
+            return self.scatter_glass(r_in, rec);
+        }
+        if wo.z <= 0.0 {
+            return None;
+        }
+
+        let weights = self.lobe_weights();
+        let choice = utils::random_double();
+        let wi = if choice < weights.diffuse {
+            utils::random_cosine_direction()
+        } else {
+            let wm = if choice < weights.diffuse + weights.specular {
+                self.distribution().sample_visible(
+                    &wo,
+                    utils::random_double(),
+                    utils::random_double(),
+                )
+            } else {
+                sample_gtr1(
+                    self.clearcoat_alpha(),
+                    utils::random_double(),
+                    utils::random_double(),
+                )
+            };
+            utils::reflect(&-wo, &wm)
+        };
+        if wi.z <= 0.0 {
+            return None;
+        }
+
+        let pdf = self.pdf_opaque(&wo, &wi, &weights);
+        if pdf <= 0.0 {
+            return None;
+        }
+
+        return Some(ScatteredRay {
+            out_ray: r_in.spawn(rec.p, rec.local_to_world(&wi)),
+            attenuation: self.eval_opaque(&wo, &wi) * (wi.z / pdf),
+        });
+    }
+
+    fn emitted(&self, _rec: &HitRecord) -> Vec3 {
+        return self.emission;
+    }
+
+    fn eval(&self, rec: &HitRecord, wi: &Vec3, wo: &Vec3) -> Vec3 {
+        let wo = rec.world_to_local(wo);
+        let wi = rec.world_to_local(wi);
+        if wo.z <= 0.0 || wi.z <= 0.0 {
+            return Vec3::new(0.0, 0.0, 0.0);
+        }
+        return self.eval_opaque(&wo, &wi);
+    }
+
+    fn pdf(&self, rec: &HitRecord, wi: &Vec3, wo: &Vec3) -> f64 {
+        let wo = rec.world_to_local(wo);
+        let wi = rec.world_to_local(wi);
+        if wo.z <= 0.0 || wi.z <= 0.0 {
+            return 0.0;
+        }
+        return self.pdf_opaque(&wo, &wi, &self.lobe_weights());
+    }
+
+    // eval() and pdf() only cover the opaque part, so any glass has to be
+    // handled through scatter()
+    fn is_delta(&self) -> bool {
+        return self.transmission * (1.0 - self.metallic) > 0.0;
+    }
+}
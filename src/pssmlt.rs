@@ -0,0 +1,334 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::cell::RefCell;
+use std::f64::consts::PI;
+use std::rc::Rc;
+
+use crate::camera::Camera;
+use crate::integrator::{Film, Integrator, Renderer};
+use crate::path_tracer::PathTracer;
+use crate::sampler::{RandomSampler, Sampler};
+use crate::scene::Scene;
+use crate::utils;
+use crate::vec3::Vec3;
+
+// A coordinate in primary sample space, along with what it was before the
+// current mutation in case that gets rejected
+#[derive(Debug, Copy, Clone)]
+struct PrimarySample {
+    value: f64,
+    last_modified: u64,
+    value_backup: f64,
+    modified_backup: u64,
+}
+
+// Hands out the coordinates of a point in primary sample space, i.e. the
+// random numbers a path was traced with, in order. Each iteration either
+// replaces all of them (a large step) or nudges them a little (a small step).
+// Coordinates are only mutated once something asks for them, catching up on
+// the small steps they missed
+struct MltSampler {
+    rng: StdRng,
+    sigma: f64,
+    large_step_probability: f64,
+    samples: Vec<PrimarySample>,
+    index: usize,
+    iteration: u64,
+    large_step: bool,
+    last_large_step: u64,
+}
+
+impl MltSampler {
+    // The first path is made of fresh numbers from seed, so that bootstrapping
+    // and starting a chain with the same seed trace the same path
+    fn new(seed: u64, sigma: f64, large_step_probability: f64) -> MltSampler {
+        return MltSampler {
+            rng: StdRng::seed_from_u64(seed),
+            sigma,
+            large_step_probability,
+            samples: Vec::new(),
+            index: 0,
+            iteration: 0,
+            large_step: true,
+            last_large_step: 0,
+        };
+    }
+
+    fn reseed(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+
+    fn start_iteration(&mut self) {
+        self.iteration += 1;
+        self.large_step = self.rng.gen::<f64>() < self.large_step_probability;
+    }
+
+    fn start_path(&mut self) {
+        self.index = 0;
+    }
+
+    fn accept(&mut self) {
+        if self.large_step {
+            self.last_large_step = self.iteration;
+        }
+    }
+
+    fn reject(&mut self) {
+        for sample in self.samples.iter_mut() {
+            if sample.last_modified == self.iteration {
+                sample.value = sample.value_backup;
+                sample.last_modified = sample.modified_backup;
+            }
+        }
+        self.iteration -= 1;
+    }
+
+    fn mutate(&mut self, index: usize) {
+        // Coordinates the current path never got to are as good as new. Starting
+        // them anywhere else would leave rejection sampling loops stuck
+        if index >= self.samples.len() {
+            let value = self.rng.gen::<f64>();
+            self.samples.push(PrimarySample {
+                value,
+                last_modified: self.iteration,
+                value_backup: value,
+                modified_backup: self.last_large_step,
+            });
+            return;
+        }
+
+        let mut sample = self.samples[index];
+        // Large steps since it was last used replaced it without anyone noticing
+        if sample.last_modified < self.last_large_step {
+            sample.value = self.rng.gen::<f64>();
+            sample.last_modified = self.last_large_step;
+        }
+
+        sample.value_backup = sample.value;
+        sample.modified_backup = sample.last_modified;
+        if self.large_step {
+            sample.value = self.rng.gen::<f64>();
+        } else {
+            // All the small steps it missed add up to one with a wider normal
+            // distribution, from Box-Muller
+            let steps = (self.iteration - sample.last_modified) as f64;
+            let u1 = 1.0 - self.rng.gen::<f64>();
+            let u2 = self.rng.gen::<f64>();
+            let normal = (-2.0 * u1.ln()).sqrt() * (2.0 * PI * u2).cos();
+            sample.value += normal * self.sigma * steps.sqrt();
+            sample.value -= sample.value.floor();
+        }
+        sample.last_modified = self.iteration;
+        self.samples[index] = sample;
+    }
+}
+
+impl Sampler for MltSampler {
+    fn next_1d(&mut self) -> f64 {
+        let index = self.index;
+        self.mutate(index);
+        self.index += 1;
+        return self.samples[index].value;
+    }
+}
+
+// Primary sample space Metropolis light transport (Kelemen et al. 2002), layered
+// over the path tracer. Rather than tracing independent paths, Markov chains
+// wander through the random numbers paths are traced with, staying longer
+// where paths carry more light. That finds and then explores light that gets
+// through narrow openings, like a keyhole, or otherwise hard paths.
+//
+// The overall brightness comes from bootstrap_samples independent paths at
+// first, which also pick where the chains start. Chains mix large steps that
+// draw new random numbers, so that they can't get stuck, with small steps of
+// size sigma around the current path
+pub struct Pssmlt {
+    pub path_tracer: PathTracer,
+    pub mutations_per_pixel: u32,
+    pub bootstrap_samples: u32,
+    pub chains: u32,
+    pub sigma: f64,
+    pub large_step_probability: f64,
+}
+
+impl Pssmlt {
+    pub fn new(path_tracer: PathTracer, mutations_per_pixel: u32) -> Pssmlt {
+        return Pssmlt {
+            path_tracer,
+            mutations_per_pixel,
+            bootstrap_samples: 100000,
+            chains: 1000,
+            sigma: 0.01,
+            large_step_probability: 0.3,
+        };
+    }
+
+    pub fn with_bootstrap_samples(mut self, bootstrap_samples: u32) -> Pssmlt {
+        self.bootstrap_samples = bootstrap_samples;
+        return self;
+    }
+
+    pub fn with_chains(mut self, chains: u32) -> Pssmlt {
+        self.chains = chains;
+        return self;
+    }
+
+    pub fn with_sigma(mut self, sigma: f64) -> Pssmlt {
+        self.sigma = sigma;
+        return self;
+    }
+
+    pub fn with_large_step_probability(mut self, large_step_probability: f64) -> Pssmlt {
+        self.large_step_probability = large_step_probability;
+        return self;
+    }
+
+    fn sampler(&self, seed: u64) -> MltSampler {
+        return MltSampler::new(seed, self.sigma, self.large_step_probability);
+    }
+
+    // Traces the path that the current numbers of sampler make, returning the
+    // pixel it goes through and the light it carries
+    fn evaluate(
+        &self,
+        scene: &Scene,
+        camera: &Camera,
+        sampler: &Rc<RefCell<MltSampler>>,
+        width: u32,
+        height: u32,
+    ) -> (u32, u32, Vec3) {
+        sampler.borrow_mut().start_path();
+        let (u, v) = sampler.borrow_mut().next_2d();
+        let x = ((u * width as f64) as u32).min(width - 1);
+        // The camera's t goes up, while film rows go down
+        let y = height - 1 - ((v * height as f64) as u32).min(height - 1);
+
+        let color = {
+            let _source = utils::install_random_source(sampler.clone());
+            let ray = camera.get_ray(u, v);
+            let mut splats = Vec::new();
+            self.path_tracer
+                .radiance(&ray, scene, camera, &mut RandomSampler, &mut splats)
+        };
+
+        if !color.x.is_finite() || !color.y.is_finite() || !color.z.is_finite() {
+            return (x, y, Vec3::new(0.0, 0.0, 0.0));
+        }
+        return (x, y, color);
+    }
+}
+
+impl Renderer for Pssmlt {
+    // Paths take their random numbers from the Markov chains, so sampler goes
+    // unused
+    fn render(
+        &self,
+        scene: &Scene,
+        camera: &Camera,
+        _sampler: &mut dyn Sampler,
+        width: u32,
+        height: u32,
+    ) -> Film {
+        let mut film = Film::new(width, height);
+        // Without paths to bootstrap from or chains to run there is nothing to
+        // show
+        if self.bootstrap_samples == 0 || self.chains == 0 || self.mutations_per_pixel == 0 {
+            return film;
+        }
+        let base_seed = rand::thread_rng().gen::<u64>();
+
+        // Bootstrap: plain random paths, to see how much light there is overall
+        let mut cdf = Vec::with_capacity(self.bootstrap_samples as usize);
+        let mut total = 0.0;
+        for i in 0..self.bootstrap_samples {
+            let seed = base_seed.wrapping_add(i as u64);
+            let sampler = Rc::new(RefCell::new(self.sampler(seed)));
+            let (_, _, color) = self.evaluate(scene, camera, &sampler, width, height);
+            total += utils::luminance(&color).max(0.0);
+            cdf.push(total);
+        }
+        let mut brightness = total / self.bootstrap_samples as f64;
+        if brightness.is_nan() || brightness <= 0.0 {
+            return film;
+        }
+
+        let mut rng = rand::thread_rng();
+        let chains = self.chains as u64;
+        let mutations = self.mutations_per_pixel as u64 * width as u64 * height as u64;
+        for chain in 0..chains {
+            // Start from one of the bootstrap paths, picked in proportion to how
+            // much light it carries
+            let target = rng.gen::<f64>() * total;
+            let index = cdf.partition_point(|&c| c <= target).min(cdf.len() - 1);
+            let seed = base_seed.wrapping_add(index as u64);
+            let sampler = Rc::new(RefCell::new(self.sampler(seed)));
+            let mut current = self.evaluate(scene, camera, &sampler, width, height);
+            // Chains that started from the same path still go their own ways
+            sampler.borrow_mut().reseed(rng.gen::<u64>());
+
+            let chain_mutations = mutations / chains + u64::from(chain < mutations % chains);
+            for _ in 0..chain_mutations {
+                sampler.borrow_mut().start_iteration();
+                let proposed = self.evaluate(scene, camera, &sampler, width, height);
+
+                let current_value = utils::luminance(&current.2).max(0.0);
+                let proposed_value = utils::luminance(&proposed.2).max(0.0);
+                let accept = if current_value > 0.0 {
+                    (proposed_value / current_value).min(1.0)
+                } else {
+                    1.0
+                };
+
+                // Both paths count, weighted by how likely each is to be next,
+                // which is less noisy than only counting the one kept
+                if proposed_value > 0.0 {
+                    let weight = accept / proposed_value;
+                    film.add(proposed.0, proposed.1, &(proposed.2 * weight));
+                }
+                if current_value > 0.0 {
+                    let weight = (1.0 - accept) / current_value;
+                    film.add(current.0, current.1, &(current.2 * weight));
+                }
+
+                if rng.gen::<f64>() < accept {
+                    current = proposed;
+                    sampler.borrow_mut().accept();
+                } else {
+                    sampler.borrow_mut().reject();
+                }
+            }
+        }
+
+        // Each pixel got mutations_per_pixel samples on average, spread out in
+        // proportion to their brightness
+        brightness /= self.mutations_per_pixel as f64;
+        for pixel in film.pixels.iter_mut() {
+            *pixel = *pixel * brightness;
+        }
+        return film;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hitable::HitableList;
+    use crate::light::LightList;
+
+    #[test]
+    fn renders_black_without_bootstrap_samples() {
+        let scene = Scene::new(HitableList { list: Vec::new() }, LightList::new(Vec::new()));
+        let camera = Camera::new(
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, -1.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            90.0,
+            1.0,
+            0.0,
+            1.0,
+        );
+        let pssmlt = Pssmlt::new(PathTracer::new(5), 4).with_bootstrap_samples(0);
+        let film = pssmlt.render(&scene, &camera, &mut RandomSampler, 4, 4);
+        assert!(film.pixels.iter().all(|p| p.squared_length() == 0.0));
+    }
+}
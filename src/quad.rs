@@ -0,0 +1,106 @@
+use std::rc::Rc;
+
+use crate::hitable::{HitRecord, Hitable};
+use crate::light::{Light, LightSample};
+use crate::material::Material;
+use crate::ray::Ray;
+use crate::utils;
+use crate::vec3::Vec3;
+
+// Parallelogram spanned by the edges u and v from corner q, e.g. walls or area
+// lights. The normal follows u x v, and (u, v) texture coordinates go from 0 to
+// 1 along each edge
+pub struct Quad {
+    pub q: Vec3,
+    pub u: Vec3,
+    pub v: Vec3,
+    pub material: Rc<dyn Material>,
+}
+
+impl Quad {
+    pub fn new(q: Vec3, u: Vec3, v: Vec3, material: Rc<dyn Material>) -> Quad {
+        return Quad { q, u, v, material };
+    }
+
+    fn record_at(&self, ray: &Ray, t: f64) -> Option<HitRecord<'_>> {
+        let n = self.u.cross(&self.v);
+        let p = ray.point_at_parameter(t);
+
+        // Coordinates of p along both edges
+        let w = n / n.dot(&n);
+        let hp = p - self.q;
+        let alpha = w.dot(&hp.cross(&self.v));
+        let beta = w.dot(&self.u.cross(&hp));
+        if !(0.0..=1.0).contains(&alpha) || !(0.0..=1.0).contains(&beta) {
+            return None;
+        }
+
+        let normal = n.normalized();
+        return Some(HitRecord {
+            t,
+            p,
+            normal,
+            geometric_normal: normal,
+            tangent: self.u.normalized(),
+            handedness: 1.0,
+            u: alpha,
+            v: beta,
+            mat_ptr: self.material.as_ref(),
+            light: Some(self),
+        });
+    }
+}
+
+impl Hitable for Quad {
+    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        let normal = self.u.cross(&self.v).normalized();
+        let denom = normal.dot(&ray.dir);
+        if denom.abs() < 1e-12 {
+            return None;
+        }
+
+        let t = normal.dot(&(self.q - ray.orig)) / denom;
+        if t <= t_min || t >= t_max {
+            return None;
+        }
+
+        let rec = self.record_at(ray, t)?;
+        if self.material.is_opaque(&rec) {
+            return Some(rec);
+        }
+        return None;
+    }
+}
+
+impl Light for Quad {
+    // Uniform over the area
+    fn sample(&self, origin: &Vec3) -> Option<LightSample<'_>> {
+        let point = self.q + self.u * utils::random_double() + self.v * utils::random_double();
+        let rec = self.record_at(&Ray::new(*origin, point - *origin), 1.0)?;
+        let pdf = self.pdf(origin, &rec);
+        if pdf <= 0.0 {
+            return None;
+        }
+        return Some(LightSample { rec, pdf });
+    }
+
+    fn pdf(&self, origin: &Vec3, rec: &HitRecord) -> f64 {
+        let to_light = rec.p - *origin;
+        let distance_squared = to_light.squared_length();
+        let cosine = rec.geometric_normal.dot(&to_light).abs() / distance_squared.sqrt();
+        if cosine <= 0.0 {
+            return 0.0;
+        }
+        return distance_squared / (cosine * self.area());
+    }
+
+    fn sample_area(&self) -> Option<HitRecord<'_>> {
+        let point = self.q + self.u * utils::random_double() + self.v * utils::random_double();
+        let normal = self.u.cross(&self.v).normalized();
+        return self.record_at(&Ray::new(point + normal, -normal), 1.0);
+    }
+
+    fn area(&self) -> f64 {
+        return self.u.cross(&self.v).length();
+    }
+}
@@ -1,14 +1,35 @@
+use crate::medium::MediumStack;
 use crate::vec3::Vec3;
 
 #[derive(Debug, Copy, Clone)]
 pub struct Ray {
     pub orig: Vec3,
     pub dir: Vec3,
+    // Nested media the ray is travelling through
+    pub media: MediumStack,
+    // In nanometers, for paths that have been narrowed down to a single
+    // wavelength by a dispersive surface. None means the ray carries RGB
+    pub wavelength: Option<f64>,
 }
 
 impl Ray {
     pub fn new(orig: Vec3, dir: Vec3) -> Ray {
-        return Ray { orig, dir };
+        return Ray {
+            orig,
+            dir,
+            media: MediumStack::new(),
+            wavelength: None,
+        };
+    }
+
+    // Continues the path of this ray from orig, in the same media and wavelength
+    pub fn spawn(&self, orig: Vec3, dir: Vec3) -> Ray {
+        return Ray {
+            orig,
+            dir,
+            media: self.media,
+            wavelength: self.wavelength,
+        };
     }
 
     pub fn point_at_parameter(&self, t: f64) -> Vec3 {
@@ -0,0 +1,136 @@
+use crate::fresnel;
+use crate::hitable::HitRecord;
+use crate::material::{Material, ScatteredRay};
+use crate::medium::{self, Medium};
+use crate::microfacet::GgxDistribution;
+use crate::ray::Ray;
+use crate::utils;
+use crate::vec3::Vec3;
+
+// Frosted glass: GGX microfacet reflection and transmission (Walter et al. 2007),
+// sampled through the visible normals
+pub struct RoughDielectric {
+    pub ref_idx: f64,
+    pub distribution: GgxDistribution,
+    // Absorption coefficient per unit distance travelled inside, per channel
+    pub absorption: Vec3,
+    // See Dielectric::priority
+    pub priority: u32,
+    medium_id: u32,
+}
+
+impl RoughDielectric {
+    pub fn new(ref_idx: f64, roughness: f64) -> RoughDielectric {
+        return RoughDielectric::anisotropic(ref_idx, roughness, roughness);
+    }
+
+    pub fn anisotropic(ref_idx: f64, roughness_x: f64, roughness_y: f64) -> RoughDielectric {
+        return RoughDielectric {
+            ref_idx,
+            distribution: GgxDistribution::from_roughness(roughness_x, roughness_y),
+            absorption: Vec3::new(0.0, 0.0, 0.0),
+            priority: 0,
+            medium_id: medium::new_medium_id(),
+        };
+    }
+
+    // See Dielectric::with_transmittance()
+    pub fn with_transmittance(mut self, transmittance: Vec3, distance: f64) -> RoughDielectric {
+        self.absorption = utils::absorption_from_transmittance(&transmittance, distance);
+        return self;
+    }
+
+    pub fn with_priority(mut self, priority: u32) -> RoughDielectric {
+        self.priority = priority;
+        return self;
+    }
+
+    pub fn medium(&self) -> Medium {
+        return Medium {
+            id: self.medium_id,
+            ior: self.ref_idx,
+            priority: self.priority,
+            absorption: self.absorption,
+            dispersion: None,
+            scattering: Vec3::new(0.0, 0.0, 0.0),
+            anisotropy: 0.0,
+        };
+    }
+}
+
+impl Material for RoughDielectric {
+    fn scatter(&self, r_in: &Ray, rec: &HitRecord) -> Option<ScatteredRay> {
+        return scatter_rough_dielectric(r_in, rec, &self.medium(), &self.distribution);
+    }
+}
+
+// Shared with other materials that have a rough glass part to them
+pub(crate) fn scatter_rough_dielectric(
+    r_in: &Ray,
+    rec: &HitRecord,
+    medium: &Medium,
+    distribution: &GgxDistribution,
+) -> Option<ScatteredRay> {
+    let mut wo = rec.world_to_local(&-r_in.dir.normalized());
+
+    let entering = wo.z > 0.0;
+    let crossing = r_in.media.cross(medium, entering, r_in.wavelength);
+    if !crossing.is_real {
+        let mut out_ray = r_in.spawn(rec.p, r_in.dir);
+        out_ray.media = crossing.transmitted;
+        return Some(ScatteredRay {
+            out_ray,
+            attenuation: Vec3::new(1.0, 1.0, 1.0),
+        });
+    }
+
+    // Work as if wo was always above the surface, and flip the result back
+    // if we were actually coming from the inside
+    if !entering {
+        wo = -wo;
+    }
+
+    let (wi, reflecting, weight) = sample_rough_interface(distribution, &wo, crossing.eta)?;
+    let wi = if entering { wi } else { -wi };
+    let mut out_ray = r_in.spawn(rec.p, rec.local_to_world(&wi));
+    if !reflecting {
+        out_ray.media = crossing.transmitted;
+    }
+
+    return Some(ScatteredRay {
+        out_ray,
+        attenuation: Vec3::new(weight, weight, weight),
+    });
+}
+
+// Samples a rough interface between two dielectrics through its visible
+// normals, with wo above the surface and eta being the IOR below over the IOR
+// above. Returns the new direction, whether it was reflected, and its weight
+pub(crate) fn sample_rough_interface(
+    distribution: &GgxDistribution,
+    wo: &Vec3,
+    eta: f64,
+) -> Option<(Vec3, bool, f64)> {
+    let wm = distribution.sample_visible(wo, utils::random_double(), utils::random_double());
+
+    // Reflection and refraction are picked proportionally to the Fresnel
+    // term, so it cancels out of the weights. Total internal reflection
+    // just means the reflection probability is one
+    let reflectance = fresnel::dielectric_reflectance(wo.dot(&wm), eta);
+    let reflecting = utils::random_double() < reflectance;
+    let wi = if reflecting {
+        let wi = utils::reflect(&-*wo, &wm);
+        if wi.z <= 0.0 {
+            return None;
+        }
+        wi
+    } else {
+        match utils::transmit(wo, &wm, eta) {
+            Some(wi) if wi.z < 0.0 => wi,
+            _ => return None,
+        }
+    };
+
+    let weight = distribution.g(wo, &wi) / distribution.g1(wo);
+    return Some((wi, reflecting, weight));
+}
@@ -0,0 +1,24 @@
+use crate::utils;
+
+// Source of the random numbers an integrator makes its own decisions with,
+// like pixel positions and when to stop a path
+pub trait Sampler {
+    // Uniform in [0, 1)
+    fn next_1d(&mut self) -> f64;
+
+    fn next_2d(&mut self) -> (f64, f64) {
+        let x = self.next_1d();
+        let y = self.next_1d();
+        return (x, y);
+    }
+}
+
+// Independent uniform samples from utils::random_double(), and so from
+// whatever random source is installed there
+pub struct RandomSampler;
+
+impl Sampler for RandomSampler {
+    fn next_1d(&mut self) -> f64 {
+        return utils::random_double();
+    }
+}
@@ -0,0 +1,125 @@
+use crate::hitable::HitableList;
+use crate::light::LightList;
+use crate::ray::Ray;
+use crate::vec3::Vec3;
+
+// Light coming from infinitely far away, for rays that don't hit anything
+#[derive(Debug, Copy, Clone)]
+pub enum Background {
+    Solid(Vec3),
+    // Blends from bottom straight down to top straight up
+    Gradient { bottom: Vec3, top: Vec3 },
+}
+
+impl Background {
+    // The blue sky of the original renderer
+    pub fn sky() -> Background {
+        return Background::Gradient {
+            bottom: Vec3::new(1.0, 1.0, 1.0),
+            top: Vec3::new(0.5, 0.7, 1.0),
+        };
+    }
+
+    pub fn radiance(&self, ray: &Ray) -> Vec3 {
+        match self {
+            Background::Solid(color) => return *color,
+            Background::Gradient { bottom, top } => {
+                let t = 0.5 * (ray.dir.normalized().y + 1.0);
+                return *bottom * (1.0 - t) + *top * t;
+            }
+        }
+    }
+}
+
+// Everything an integrator needs to know about what it renders. Lights should
+// be part of world as well, see LightList
+pub struct Scene<'a> {
+    pub world: HitableList<'a>,
+    pub lights: LightList,
+    pub background: Background,
+}
+
+impl<'a> Scene<'a> {
+    pub fn new(world: HitableList<'a>, lights: LightList) -> Scene<'a> {
+        return Scene {
+            world,
+            lights,
+            background: Background::sky(),
+        };
+    }
+
+    pub fn with_background(mut self, background: Background) -> Scene<'a> {
+        self.background = background;
+        return self;
+    }
+}
+
+// A Lambertian floor of albedo 0.5 at y = 0, under a two-sided 2 x 2 light one
+// unit above the origin, against black. With wall, a second Lambertian surface
+// stands at x = -1.5 so that some of the light arrives indirectly. Without it
+// the floor only ever sees the light, see lit_floor_radiance()
+#[cfg(test)]
+pub fn lit_floor(sample_lights: bool, wall: bool) -> Scene<'static> {
+    use crate::light::Light;
+    use crate::material::{DiffuseLight, Lambertian, Material};
+    use crate::quad::Quad;
+    use std::rc::Rc;
+
+    let white: Rc<dyn Material> = Rc::new(Lambertian::new(Vec3::new(0.5, 0.5, 0.5)));
+    let light = Rc::new(Quad::new(
+        Vec3::new(-1.0, 1.0, -1.0),
+        Vec3::new(2.0, 0.0, 0.0),
+        Vec3::new(0.0, 0.0, 2.0),
+        Rc::new(DiffuseLight::new(Vec3::new(1.0, 1.0, 1.0))),
+    ));
+    let mut list: Vec<Box<dyn crate::hitable::Hitable>> = vec![
+        Box::new(Quad::new(
+            Vec3::new(-10.0, 0.0, 10.0),
+            Vec3::new(20.0, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, -20.0),
+            white.clone(),
+        )),
+        Box::new(light.clone()),
+    ];
+    if wall {
+        list.push(Box::new(Quad::new(
+            Vec3::new(-1.5, 0.0, 10.0),
+            Vec3::new(0.0, 0.0, -20.0),
+            Vec3::new(0.0, 3.0, 0.0),
+            white,
+        )));
+    }
+
+    let mut lights: Vec<Rc<dyn Light>> = Vec::new();
+    if sample_lights {
+        lights.push(light);
+    }
+    return Scene::new(HitableList { list }, LightList::new(lights))
+        .with_background(Background::Solid(Vec3::new(0.0, 0.0, 0.0)));
+}
+
+// Radiance leaving the origin of lit_floor() without the wall: 0.5 / pi times
+// the irradiance from a square light straight above, which adds up the form
+// factors of its four quarters
+#[cfg(test)]
+pub fn lit_floor_radiance() -> f64 {
+    let x = 1.0_f64 / 2.0_f64.sqrt();
+    let quarter = x * x.atan();
+    let irradiance = 2.0 * (quarter + quarter);
+    return 0.5 / std::f64::consts::PI * irradiance;
+}
+
+// Looks at the corner between the floor and the wall of lit_floor(), with the
+// light in view as well
+#[cfg(test)]
+pub fn lit_floor_camera() -> crate::camera::Camera {
+    return crate::camera::Camera::new(
+        Vec3::new(3.0, 2.0, 0.5),
+        Vec3::new(-0.5, 0.4, 0.0),
+        Vec3::new(0.0, 1.0, 0.0),
+        40.0,
+        1.0,
+        0.0,
+        1.0,
+    );
+}
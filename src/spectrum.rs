@@ -0,0 +1,335 @@
+use std::sync::OnceLock;
+
+use crate::utils;
+use crate::vec3::Vec3;
+
+// Visible range, in nanometers
+pub const LAMBDA_MIN: f64 = 380.0;
+pub const LAMBDA_MAX: f64 = 780.0;
+
+// Piecewise gaussian with different widths on each side of the peak
+fn gaussian(lambda: f64, mu: f64, sigma_left: f64, sigma_right: f64) -> f64 {
+    let sigma = if lambda < mu { sigma_left } else { sigma_right };
+    let t = (lambda - mu) / sigma;
+    return (-0.5 * t * t).exp();
+}
+
+// CIE 1931 2 degree color matching functions, using the multi-lobe fit from
+// Wyman et al. 2013 instead of tabulated data
+pub fn cie_xyz(lambda: f64) -> Vec3 {
+    let x = 1.056 * gaussian(lambda, 599.8, 37.9, 31.0)
+        + 0.362 * gaussian(lambda, 442.0, 16.0, 26.7)
+        - 0.065 * gaussian(lambda, 501.1, 20.4, 26.2);
+    let y =
+        0.821 * gaussian(lambda, 568.8, 46.9, 40.5) + 0.286 * gaussian(lambda, 530.9, 16.3, 31.1);
+    let z =
+        1.217 * gaussian(lambda, 437.0, 11.8, 36.0) + 0.681 * gaussian(lambda, 459.0, 26.0, 13.8);
+    return Vec3::new(x, y, z);
+}
+
+// To linear sRGB primaries, D65 white point
+pub fn xyz_to_linear_srgb(xyz: &Vec3) -> Vec3 {
+    return Vec3::new(
+        3.2406 * xyz.x - 1.5372 * xyz.y - 0.4986 * xyz.z,
+        -0.9689 * xyz.x + 1.8758 * xyz.y + 0.0415 * xyz.z,
+        0.0557 * xyz.x - 0.2040 * xyz.y + 1.0570 * xyz.z,
+    );
+}
+
+// Average linear sRGB response over the visible range, per channel
+fn mean_rgb_response() -> Vec3 {
+    static MEAN: OnceLock<Vec3> = OnceLock::new();
+    return *MEAN.get_or_init(|| {
+        let steps = (LAMBDA_MAX - LAMBDA_MIN) as usize;
+        let mut sum = Vec3::new(0.0, 0.0, 0.0);
+        for i in 0..steps {
+            sum += xyz_to_linear_srgb(&cie_xyz(LAMBDA_MIN + i as f64 + 0.5));
+        }
+        return sum / steps as f64;
+    });
+}
+
+// RGB contribution of a single wavelength sampled uniformly over the visible
+// range. It's normalized so that averaging it over many wavelengths gives
+// (1, 1, 1), so that a path that only sees a single wavelength is still an
+// unbiased estimate of the RGB result. Can be negative for some wavelengths
+pub fn wavelength_to_rgb_weight(lambda: f64) -> Vec3 {
+    return xyz_to_linear_srgb(&cie_xyz(lambda)) / mean_rgb_response();
+}
+
+// Returns a wavelength and its RGB weight
+pub fn sample_wavelength() -> (f64, Vec3) {
+    let u = utils::random_double();
+    let lambda = LAMBDA_MIN + u * (LAMBDA_MAX - LAMBDA_MIN);
+    return (lambda, wavelength_to_rgb_weight(lambda));
+}
+
+// Wavelength dependent index of refraction
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Dispersion {
+    // n = a + b / lambda^2, with lambda in micrometers
+    Cauchy { a: f64, b: f64 },
+    // n^2 = 1 + sum(b_i lambda^2 / (lambda^2 - c_i)), with lambda in micrometers
+    Sellmeier { b: [f64; 3], c: [f64; 3] },
+}
+
+impl Dispersion {
+    // Schott N-BK7 crown glass
+    pub fn bk7() -> Dispersion {
+        return Dispersion::Sellmeier {
+            b: [1.03961212, 0.231792344, 1.01046945],
+            c: [0.00600069867, 0.0200179144, 103.560653],
+        };
+    }
+
+    pub fn diamond() -> Dispersion {
+        return Dispersion::Sellmeier {
+            b: [0.3306, 4.3356, 0.0],
+            c: [0.030625, 0.011236, 0.0],
+        };
+    }
+
+    pub fn fused_silica() -> Dispersion {
+        return Dispersion::Sellmeier {
+            b: [0.6961663, 0.4079426, 0.8974794],
+            c: [0.0046791, 0.0135121, 97.934003],
+        };
+    }
+
+    pub fn ior(&self, lambda_nm: f64) -> f64 {
+        let l = lambda_nm / 1000.0;
+        let l2 = l * l;
+        match self {
+            Dispersion::Cauchy { a, b } => return a + b / l2,
+            Dispersion::Sellmeier { b, c } => {
+                let mut n2 = 1.0;
+                for i in 0..3 {
+                    n2 += b[i] * l2 / (l2 - c[i]);
+                }
+                return n2.sqrt();
+            }
+        }
+    }
+
+    // IOR at the helium d-line, which is what glass catalogs usually quote
+    pub fn nominal_ior(&self) -> f64 {
+        return self.ior(587.56);
+    }
+}
+
+// Smits 1999 basis spectra for RGB to spectrum conversion, as 10 equal bins
+// over [380, 720]nm
+const SMITS_WHITE: [f64; 10] = [
+    1.0000, 1.0000, 0.9999, 0.9993, 0.9992, 0.9998, 1.0000, 1.0000, 1.0000, 1.0000,
+];
+const SMITS_CYAN: [f64; 10] = [
+    0.9710, 0.9426, 1.0007, 1.0007, 1.0007, 1.0007, 0.1564, 0.0000, 0.0000, 0.0000,
+];
+const SMITS_MAGENTA: [f64; 10] = [
+    1.0000, 1.0000, 0.9685, 0.2229, 0.0000, 0.0458, 0.8369, 1.0000, 1.0000, 0.9959,
+];
+const SMITS_YELLOW: [f64; 10] = [
+    0.0001, 0.0000, 0.1088, 0.6651, 1.0000, 1.0000, 0.9996, 0.9586, 0.9685, 0.9840,
+];
+const SMITS_RED: [f64; 10] = [
+    0.1012, 0.0515, 0.0000, 0.0000, 0.0000, 0.0000, 0.8325, 1.0149, 1.0149, 1.0149,
+];
+const SMITS_GREEN: [f64; 10] = [
+    0.0000, 0.0000, 0.0273, 0.7937, 1.0000, 0.9418, 0.1719, 0.0000, 0.0000, 0.0025,
+];
+const SMITS_BLUE: [f64; 10] = [
+    1.0000, 1.0000, 0.8916, 0.3323, 0.0000, 0.0000, 0.0003, 0.0369, 0.0483, 0.0496,
+];
+
+fn smits_basis(basis: &[f64; 10], lambda: f64) -> f64 {
+    let x = ((lambda - 380.0) / (720.0 - 380.0) * 10.0 - 0.5).clamp(0.0, 9.0);
+    let i = (x.floor() as usize).min(8);
+    let t = x - i as f64;
+    return basis[i] * (1.0 - t) + basis[i + 1] * t;
+}
+
+// Value at lambda of a smooth spectrum whose color is rgb (Smits 1999). Linear
+// in rgb, so it also works for emission and absorption above 1
+pub fn rgb_to_spectrum(rgb: &Vec3, lambda: f64) -> f64 {
+    let (r, g, b) = (rgb.x, rgb.y, rgb.z);
+    let basis = |spectrum: &[f64; 10]| smits_basis(spectrum, lambda);
+
+    if r <= g && r <= b {
+        let mut result = r * basis(&SMITS_WHITE);
+        if g <= b {
+            result += (g - r) * basis(&SMITS_CYAN) + (b - g) * basis(&SMITS_BLUE);
+        } else {
+            result += (b - r) * basis(&SMITS_CYAN) + (g - b) * basis(&SMITS_GREEN);
+        }
+        return result;
+    } else if g <= r && g <= b {
+        let mut result = g * basis(&SMITS_WHITE);
+        if r <= b {
+            result += (r - g) * basis(&SMITS_MAGENTA) + (b - r) * basis(&SMITS_BLUE);
+        } else {
+            result += (b - g) * basis(&SMITS_MAGENTA) + (r - b) * basis(&SMITS_RED);
+        }
+        return result;
+    }
+
+    let mut result = b * basis(&SMITS_WHITE);
+    if r <= g {
+        result += (r - b) * basis(&SMITS_YELLOW) + (g - r) * basis(&SMITS_GREEN);
+    } else {
+        result += (g - b) * basis(&SMITS_YELLOW) + (r - g) * basis(&SMITS_RED);
+    }
+    return result;
+}
+
+// Planck's law, normalized so that the peak of the curve is 1
+pub fn blackbody(lambda_nm: f64, temperature: f64) -> f64 {
+    const H: f64 = 6.62607015e-34;
+    const C: f64 = 299792458.0;
+    const K_B: f64 = 1.380649e-23;
+    let planck = |lambda_m: f64| {
+        return 2.0 * H * C * C
+            / (lambda_m.powi(5) * ((H * C / (lambda_m * K_B * temperature)).exp() - 1.0));
+    };
+
+    // Wien's displacement law
+    let peak = 2.897771955e-3 / temperature;
+    return planck(lambda_nm * 1e-9) / planck(peak);
+}
+
+// Converts a spectrum to RGB by averaging over the visible range, consistently
+// with wavelength_to_rgb_weight()
+pub fn spectrum_to_rgb(spectrum: impl Fn(f64) -> f64) -> Vec3 {
+    let steps = (LAMBDA_MAX - LAMBDA_MIN) as usize;
+    let mut sum = Vec3::new(0.0, 0.0, 0.0);
+    for i in 0..steps {
+        let lambda = LAMBDA_MIN + i as f64 + 0.5;
+        sum += wavelength_to_rgb_weight(lambda) * spectrum(lambda);
+    }
+    return sum / steps as f64;
+}
+
+// Light emitted by a surface, either as an RGB color or as a physical spectrum
+#[derive(Debug, Copy, Clone)]
+pub enum Emission {
+    Rgb(Vec3),
+    // Temperature in Kelvin. Scaled so that the peak of the spectrum is intensity
+    Blackbody { temperature: f64, intensity: f64 },
+}
+
+impl Emission {
+    pub fn at(&self, lambda: f64) -> f64 {
+        match self {
+            Emission::Rgb(rgb) => return rgb_to_spectrum(rgb, lambda),
+            Emission::Blackbody {
+                temperature,
+                intensity,
+            } => return intensity * blackbody(lambda, *temperature),
+        }
+    }
+
+    // Integrates the whole spectrum, so it's worth caching
+    pub fn rgb(&self) -> Vec3 {
+        match self {
+            Emission::Rgb(rgb) => return *rgb,
+            Emission::Blackbody { .. } => return spectrum_to_rgb(|lambda| self.at(lambda)),
+        }
+    }
+}
+
+pub const SPECTRAL_SAMPLES: usize = 4;
+
+// Spectral values carried along a path, one per sampled wavelength
+pub type SampledSpectrum = [f64; SPECTRAL_SAMPLES];
+
+// Hero wavelength sampling (Wilkie et al. 2014): one uniformly sampled
+// wavelength plus others at equal offsets from it, all following the same path
+#[derive(Debug, Copy, Clone)]
+pub struct SampledWavelengths {
+    pub lambda: [f64; SPECTRAL_SAMPLES],
+    // Secondary wavelengths get dropped when the path goes through a surface
+    // that would have sent them in other directions
+    pub count: usize,
+}
+
+impl SampledWavelengths {
+    pub fn sample() -> SampledWavelengths {
+        let range = LAMBDA_MAX - LAMBDA_MIN;
+        let hero = utils::random_double() * range;
+        let mut lambda = [0.0; SPECTRAL_SAMPLES];
+        for (i, l) in lambda.iter_mut().enumerate() {
+            let offset = hero + i as f64 * range / SPECTRAL_SAMPLES as f64;
+            *l = LAMBDA_MIN + offset % range;
+        }
+        return SampledWavelengths {
+            lambda,
+            count: SPECTRAL_SAMPLES,
+        };
+    }
+
+    pub fn hero(&self) -> f64 {
+        return self.lambda[0];
+    }
+
+    pub fn terminate_secondary(&mut self) {
+        self.count = 1;
+    }
+
+    pub fn from_rgb(&self, rgb: &Vec3) -> SampledSpectrum {
+        return self.lambda.map(|lambda| rgb_to_spectrum(rgb, lambda));
+    }
+
+    pub fn from_fn(&self, spectrum: impl Fn(f64) -> f64) -> SampledSpectrum {
+        return self.lambda.map(spectrum);
+    }
+
+    // Film side conversion of the radiance carried by these wavelengths
+    pub fn to_rgb(&self, values: &SampledSpectrum) -> Vec3 {
+        let mut rgb = Vec3::new(0.0, 0.0, 0.0);
+        for (lambda, value) in self.lambda.iter().zip(values).take(self.count) {
+            rgb += wavelength_to_rgb_weight(*lambda) * *value;
+        }
+        return rgb / self.count as f64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Fraunhofer F and C lines, either side of the d-line nominal_ior() uses
+    const F_LINE: f64 = 486.13;
+    const C_LINE: f64 = 656.27;
+
+    fn abbe_number(glass: &Dispersion) -> f64 {
+        return (glass.nominal_ior() - 1.0) / (glass.ior(F_LINE) - glass.ior(C_LINE));
+    }
+
+    #[test]
+    fn catalog_glasses_match_their_datasheets() {
+        assert!((Dispersion::bk7().nominal_ior() - 1.5168).abs() < 1e-4);
+        assert!((abbe_number(&Dispersion::bk7()) - 64.17).abs() < 0.05);
+        assert!((Dispersion::fused_silica().nominal_ior() - 1.4585).abs() < 1e-4);
+        assert!((abbe_number(&Dispersion::fused_silica()) - 67.82).abs() < 0.05);
+        assert!((Dispersion::diamond().nominal_ior() - 2.4175).abs() < 1e-4);
+    }
+
+    #[test]
+    fn blue_bends_more_than_red() {
+        let glasses = [
+            Dispersion::bk7(),
+            Dispersion::fused_silica(),
+            Dispersion::diamond(),
+            Dispersion::Cauchy { a: 1.5, b: 0.004 },
+        ];
+        for glass in glasses.iter() {
+            assert!(glass.ior(450.0) > glass.ior(550.0));
+            assert!(glass.ior(550.0) > glass.ior(650.0));
+        }
+    }
+
+    #[test]
+    fn cauchy_is_a_plus_b_over_lambda_squared() {
+        let glass = Dispersion::Cauchy { a: 1.5, b: 0.004 };
+        assert!((glass.ior(500.0) - (1.5 + 0.004 / 0.25)).abs() < 1e-12);
+    }
+}
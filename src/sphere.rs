@@ -1,14 +1,17 @@
+use std::f64::consts::{FRAC_PI_2, PI};
 use std::rc::Rc;
 
 use crate::hitable::{HitRecord, Hitable};
+use crate::light::{Light, LightSample};
 use crate::material::Material;
 use crate::ray::Ray;
+use crate::utils;
 use crate::vec3::Vec3;
 
 pub struct Sphere {
     pub center: Vec3,
     pub radius: f64,
-    pub material: Rc<Material>,
+    pub material: Rc<dyn Material>,
 }
 
 impl Sphere {
@@ -21,9 +24,43 @@ impl Sphere {
     }
 }
 
+// Latitude/longitude mapping of a point on the unit sphere into [0, 1]^2
+pub fn sphere_uv(p: &Vec3) -> (f64, f64) {
+    let phi = p.z.atan2(p.x);
+    let theta = p.y.clamp(-1.0, 1.0).asin();
+    let u = 1.0 - (phi + PI) / (2.0 * PI);
+    let v = (theta + FRAC_PI_2) / PI;
+    return (u, v);
+}
+
+// Direction of increasing u, i.e. clockwise around the y axis when seen from above
+pub fn sphere_tangent(p: &Vec3) -> Vec3 {
+    return utils::tangent_from(p, &Vec3::new(p.z, 0.0, -p.x));
+}
+
+impl Sphere {
+    fn record_at(&self, ray: &Ray, t: f64) -> HitRecord<'_> {
+        let p = ray.point_at_parameter(t);
+        let normal = (p - self.center) / self.radius;
+        let (u, v) = sphere_uv(&normal);
+        return HitRecord {
+            t,
+            p,
+            normal,
+            geometric_normal: normal,
+            tangent: sphere_tangent(&normal),
+            handedness: 1.0,
+            u,
+            v,
+            mat_ptr: self.material.as_ref(),
+            light: Some(self),
+        };
+    }
+}
+
 impl Hitable for Sphere {
-    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
-        let oc = &ray.orig - &self.center;
+    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        let oc = ray.orig - self.center;
         let a = ray.dir.dot(&ray.dir);
         let b = oc.dot(&ray.dir);
         let c = oc.dot(&oc) - self.radius * self.radius;
@@ -32,28 +69,94 @@ impl Hitable for Sphere {
             let t_1 = (-b - (b * b - a * c).sqrt()) / a;
 
             if t_1 < t_max && t_1 > t_min {
-                let t_1_p = ray.point_at_parameter(t_1);
-                return Some(HitRecord {
-                    t: t_1,
-                    p: t_1_p,
-                    normal: (t_1_p - self.center) / self.radius,
-                    mat_ptr: self.material.as_ref(),
-                });
+                let rec = self.record_at(ray, t_1);
+                if self.material.is_opaque(&rec) {
+                    return Some(rec);
+                }
             }
 
             let t_2 = (-b + (b * b - a * c).sqrt()) / a;
 
             if t_2 < t_max && t_2 > t_min {
-                let t_2_pt = ray.point_at_parameter(t_2);
-                return Some(HitRecord {
-                    t: t_2,
-                    p: t_2_pt,
-                    normal: (t_2_pt - self.center) / self.radius,
-                    mat_ptr: self.material.as_ref(),
-                });
+                let rec = self.record_at(ray, t_2);
+                if self.material.is_opaque(&rec) {
+                    return Some(rec);
+                }
             }
         }
 
         return None;
     }
 }
+
+impl Sphere {
+    // 1 - cos of the half angle of the cone the sphere covers as seen from a
+    // point at distance from its center, written to stay accurate when tiny
+    fn one_minus_cos_max(&self, distance: f64) -> f64 {
+        let sin2_max = (self.radius * self.radius) / (distance * distance);
+        return sin2_max / (1.0 + (1.0 - sin2_max).max(0.0).sqrt());
+    }
+}
+
+impl Light for Sphere {
+    // Uniform over the cone of directions the sphere covers, or over its area
+    // from the inside
+    fn sample(&self, origin: &Vec3) -> Option<LightSample<'_>> {
+        let to_center = self.center - *origin;
+        let distance = to_center.length();
+
+        if distance <= self.radius {
+            let point = self.center + utils::random_in_unit_sphere().normalized() * self.radius;
+            let rec = self.record_at(&Ray::new(*origin, point - *origin), 1.0);
+            let pdf = self.pdf(origin, &rec);
+            if pdf <= 0.0 {
+                return None;
+            }
+            return Some(LightSample { rec, pdf });
+        }
+
+        let one_minus_cos_max = self.one_minus_cos_max(distance);
+        let cos_theta = 1.0 - utils::random_double() * one_minus_cos_max;
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = 2.0 * PI * utils::random_double();
+
+        let axis = to_center / distance;
+        let (b1, b2) = utils::orthonormal_basis(&axis);
+        let dir = (b1 * phi.cos() + b2 * phi.sin()) * sin_theta + axis * cos_theta;
+
+        // Nearest intersection along dir, which grazes the sphere at worst
+        let along = distance * cos_theta;
+        let half_chord = (self.radius * self.radius - distance * distance * sin_theta * sin_theta)
+            .max(0.0)
+            .sqrt();
+        let rec = self.record_at(&Ray::new(*origin, dir), along - half_chord);
+        return Some(LightSample {
+            rec,
+            pdf: 1.0 / (2.0 * PI * one_minus_cos_max),
+        });
+    }
+
+    fn pdf(&self, origin: &Vec3, rec: &HitRecord) -> f64 {
+        let distance = (self.center - *origin).length();
+        if distance > self.radius {
+            return 1.0 / (2.0 * PI * self.one_minus_cos_max(distance));
+        }
+
+        let to_light = rec.p - *origin;
+        let distance_squared = to_light.squared_length();
+        let cosine = rec.geometric_normal.dot(&to_light).abs() / distance_squared.sqrt();
+        if cosine <= 0.0 {
+            return 0.0;
+        }
+        return distance_squared / (cosine * self.area());
+    }
+
+    fn sample_area(&self) -> Option<HitRecord<'_>> {
+        let dir = utils::random_in_unit_sphere().normalized();
+        return Some(self.record_at(&Ray::new(self.center, dir), self.radius));
+    }
+
+    fn area(&self) -> f64 {
+        return 4.0 * PI * self.radius * self.radius;
+    }
+}
@@ -0,0 +1,321 @@
+use std::f64::consts::PI;
+
+use crate::camera::Camera;
+use crate::hitable::{HitRecord, Hitable};
+use crate::integrator::{Film, Renderer};
+use crate::light;
+use crate::path_tracer;
+use crate::point_grid::PointGrid;
+use crate::ray::Ray;
+use crate::sampler::Sampler;
+use crate::scene::Scene;
+use crate::utils;
+use crate::vec3::Vec3;
+
+// How much of the photons found in a pass are kept when shrinking the radius.
+// Lower shrinks faster, and 2/3 is what Hachisuka and Jensen recommend
+const ALPHA: f64 = 2.0 / 3.0;
+
+// Where a camera path first landed on something that isn't a perfect mirror or
+// glass, in the current pass
+struct VisiblePoint<'a> {
+    rec: HitRecord<'a>,
+    // Unit vector back along the camera path
+    wo: Vec3,
+    // Throughput of the camera path up to here
+    beta: Vec3,
+}
+
+// What a pixel has gathered over all the passes so far
+struct Pixel {
+    // Sum of the light arriving straight from the lights, or along mirrors
+    // and glass
+    direct: Vec3,
+    radius: f64,
+    // Photons the radius has been shrunk for so far
+    photons: f64,
+    // Sum of the photon power found within radius, scaled for the current one
+    tau: Vec3,
+    // Photon power and count found in the current pass
+    phi: Vec3,
+    found: u64,
+}
+
+// Stochastic progressive photon mapping (Hachisuka and Jensen 2009). Each pass
+// traces a camera ray per pixel until it lands on something that isn't a
+// perfect mirror or glass, then traces photons from the lights and adds up
+// the ones that land within a radius of that point. Radii shrink every pass,
+// so that the estimate converges, even for light that bounces off mirrors or
+// through glass on both sides of a diffuse surface, which path tracing can't
+// find when lights are small.
+//
+// Photons start on scene.lights only, so other emitters and the background
+// only light the scene directly and through mirrors and glass. Scattering
+// media only absorb light here, and everything is in RGB
+pub struct Sppm {
+    pub iterations: u32,
+    pub photons_per_iteration: u32,
+    // Starting radius of every pixel, in scene units. Too small is noisy, too
+    // large blurry for longer
+    pub initial_radius: f64,
+    pub max_depth: u32,
+}
+
+impl Sppm {
+    pub fn new(iterations: u32, photons_per_iteration: u32, initial_radius: f64) -> Sppm {
+        return Sppm {
+            iterations,
+            photons_per_iteration,
+            initial_radius,
+            max_depth: 50,
+        };
+    }
+
+    pub fn with_max_depth(mut self, max_depth: u32) -> Sppm {
+        self.max_depth = max_depth;
+        return self;
+    }
+
+    // Follows ray through mirrors and glass, adding up the light it finds
+    // along the way into direct, and returns where it stops
+    fn trace_camera_path<'a>(
+        &self,
+        camera_ray: &Ray,
+        scene: &'a Scene,
+        direct: &mut Vec3,
+    ) -> Option<VisiblePoint<'a>> {
+        let mut ray = *camera_ray;
+        let mut beta = Vec3::new(1.0, 1.0, 1.0);
+
+        for depth in 0..=self.max_depth {
+            let rec = match scene.world.hit(&ray, 0.001, f64::MAX) {
+                Some(rec) => rec,
+                None => {
+                    *direct += beta * scene.background.radiance(&ray);
+                    return None;
+                }
+            };
+            beta *= ray.media.transmittance(rec.t * ray.dir.length());
+            *direct += beta * rec.mat_ptr.emitted(&rec);
+
+            if !rec.mat_ptr.is_delta() {
+                *direct += beta * direct_light(&ray, &rec, scene);
+                return Some(VisiblePoint {
+                    rec,
+                    wo: -ray.dir.normalized(),
+                    beta,
+                });
+            }
+            if depth == self.max_depth {
+                break;
+            }
+
+            let scattered = rec.mat_ptr.scatter(&ray, &rec)?;
+            if !rec.is_consistent(&scattered.out_ray.dir) {
+                break;
+            }
+            beta *= scattered.attenuation;
+            ray = scattered.out_ray;
+        }
+        return None;
+    }
+
+    // Traces one photon from the lights, adding its power to the pixels whose
+    // visible points are close enough to where it lands
+    fn trace_photon(
+        &self,
+        scene: &Scene,
+        points: &[Option<VisiblePoint>],
+        grid: &PointGrid,
+        pixels: &mut [Pixel],
+    ) {
+        let sample = match scene.lights.sample_emission() {
+            Some(sample) => sample,
+            None => return,
+        };
+        let mut ray = Ray::new(sample.rec.p, sample.dir);
+        let mut beta = sample.weight();
+
+        for depth in 0..self.max_depth {
+            let rec = match scene.world.hit(&ray, 0.001, f64::MAX) {
+                Some(rec) => rec,
+                None => return,
+            };
+            beta *= ray.media.transmittance(rec.t * ray.dir.length());
+
+            // Light straight from the lights is left to direct_light()
+            if depth > 0 && !rec.mat_ptr.is_delta() {
+                let wi = -ray.dir.normalized();
+                for &index in grid.near(&rec.p) {
+                    let point = points[index].as_ref().unwrap();
+                    let pixel = &mut pixels[index];
+                    if (point.rec.p - rec.p).squared_length() > pixel.radius * pixel.radius {
+                        continue;
+                    }
+                    pixel.phi += beta * point.rec.mat_ptr.eval(&point.rec, &wi, &point.wo);
+                    pixel.found += 1;
+                }
+            }
+
+            let scattered = match rec.mat_ptr.scatter(&ray, &rec) {
+                Some(scattered) => scattered,
+                None => return,
+            };
+            if !rec.is_consistent(&scattered.out_ray.dir) {
+                return;
+            }
+
+            // Russian roulette, keeping photons about as bright as they started
+            let a = scattered.attenuation;
+            let survival = a.x.max(a.y).max(a.z).min(1.0);
+            if survival <= 0.0 || utils::random_double() >= survival {
+                return;
+            }
+            beta *= a / survival;
+            ray = scattered.out_ray;
+        }
+    }
+}
+
+impl Renderer for Sppm {
+    fn render(
+        &self,
+        scene: &Scene,
+        camera: &Camera,
+        sampler: &mut dyn Sampler,
+        width: u32,
+        height: u32,
+    ) -> Film {
+        let mut pixels: Vec<Pixel> = (0..width * height)
+            .map(|_| Pixel {
+                direct: Vec3::new(0.0, 0.0, 0.0),
+                radius: self.initial_radius,
+                photons: 0.0,
+                tau: Vec3::new(0.0, 0.0, 0.0),
+                phi: Vec3::new(0.0, 0.0, 0.0),
+                found: 0,
+            })
+            .collect();
+
+        for _ in 0..self.iterations {
+            let mut points = Vec::with_capacity(pixels.len());
+            for y in 0..height {
+                // The camera's t goes up, while film rows go down
+                let j = height - 1 - y;
+                for x in 0..width {
+                    let (du, dv) = sampler.next_2d();
+                    let u = (x as f64 + du) / width as f64;
+                    let v = (j as f64 + dv) / height as f64;
+                    let ray = camera.get_ray(u, v);
+                    let pixel = &mut pixels[(y * width + x) as usize];
+                    points.push(self.trace_camera_path(&ray, scene, &mut pixel.direct));
+                }
+            }
+
+            // Photons look for the visible points whose radius they land in
+            let max_radius = pixels.iter().fold(0.0, |m: f64, pixel| m.max(pixel.radius));
+            let mut grid = PointGrid::new(max_radius);
+            for (index, point) in points.iter().enumerate() {
+                if let Some(point) = point {
+                    if point.beta.squared_length() > 0.0 {
+                        grid.insert(index, &point.rec.p, pixels[index].radius);
+                    }
+                }
+            }
+            for _ in 0..self.photons_per_iteration {
+                self.trace_photon(scene, &points, &grid, &mut pixels);
+            }
+
+            // Shrink the radius so as to keep ALPHA of the new photons, and scale
+            // what was gathered before to match
+            for (pixel, point) in pixels.iter_mut().zip(points.iter()) {
+                if pixel.found > 0 {
+                    let found = pixel.found as f64;
+                    let photons = pixel.photons + ALPHA * found;
+                    let radius = pixel.radius * (photons / (pixel.photons + found)).sqrt();
+                    let beta = point.as_ref().unwrap().beta;
+                    let scale = (radius * radius) / (pixel.radius * pixel.radius);
+                    pixel.tau = (pixel.tau + beta * pixel.phi) * scale;
+                    pixel.photons = photons;
+                    pixel.radius = radius;
+                }
+                pixel.phi = Vec3::new(0.0, 0.0, 0.0);
+                pixel.found = 0;
+            }
+        }
+
+        let mut film = Film::new(width, height);
+        let iterations = self.iterations as f64;
+        let photons = iterations * self.photons_per_iteration as f64;
+        for (i, pixel) in pixels.iter().enumerate() {
+            let area = PI * pixel.radius * pixel.radius;
+            film.pixels[i] = pixel.direct / iterations + pixel.tau / (photons * area);
+        }
+        return film;
+    }
+}
+
+// Light arriving at rec straight from one of the lights, by sampling both the
+// lights and the material
+fn direct_light(ray: &Ray, rec: &HitRecord, scene: &Scene) -> Vec3 {
+    let mut result = path_tracer::sample_direct(ray, rec, scene);
+
+    let scattered = match rec.mat_ptr.scatter(ray, rec) {
+        Some(scattered) => scattered,
+        None => return result,
+    };
+    if !rec.is_consistent(&scattered.out_ray.dir) {
+        return result;
+    }
+    let out_ray = scattered.out_ray;
+    let next = match scene.world.hit(&out_ray, 0.001, f64::MAX) {
+        Some(next) => next,
+        None => return result,
+    };
+    let emitted = next.mat_ptr.emitted(&next);
+    if emitted.squared_length() <= 0.0 {
+        return result;
+    }
+    let light_pdf = scene.lights.pdf(&out_ray.orig, &next);
+    if light_pdf <= 0.0 {
+        return result;
+    }
+
+    let wi = out_ray.dir.normalized();
+    let bsdf_pdf = rec.mat_ptr.pdf(rec, &wi, &-ray.dir.normalized());
+    let weight = light::power_heuristic(bsdf_pdf, light_pdf);
+    let transmittance = out_ray.media.transmittance(next.t * out_ray.dir.length());
+    result += scattered.attenuation * emitted * transmittance * weight;
+    return result;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::integrator;
+    use crate::path_tracer::PathTracer;
+    use crate::sampler::RandomSampler;
+    use crate::scene;
+
+    fn average(film: &Film) -> f64 {
+        let sum: f64 = film.pixels.iter().map(|pixel| pixel.x).sum();
+        return sum / film.pixels.len() as f64;
+    }
+
+    #[test]
+    fn agrees_with_path_tracing() {
+        let scene = scene::lit_floor(true, true);
+        let camera = scene::lit_floor_camera();
+        let sppm = Sppm::new(16, 20000, 0.05);
+        let tracer = PathTracer::new(10);
+        let found = sppm.render(&scene, &camera, &mut RandomSampler, 16, 16);
+        let expected = integrator::render(&scene, &camera, &tracer, &mut RandomSampler, 16, 16, 64);
+        let (found, expected) = (average(&found), average(&expected));
+        assert!(
+            (found - expected).abs() < 0.04 * expected,
+            "got {} for {}",
+            found,
+            expected
+        );
+    }
+}
@@ -0,0 +1,908 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::f64::consts::PI;
+use std::fmt;
+use std::rc::Rc;
+
+use crate::camera::Camera;
+use crate::material::Material;
+use crate::mesh::{MeshTriangle, TriangleMesh};
+use crate::vec3::Vec3;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SubdivisionScheme {
+    // Handles any polygons, produces quads after the first level
+    CatmullClark,
+    // Expects triangles. Other polygons are fan triangulated before refining
+    Loop,
+}
+
+pub enum SubdivisionLevel<'a> {
+    Uniform(u32),
+    // One level for the whole mesh, picked from the screen: the smallest at
+    // which no control edge covers more than max_edge_pixels. Faces far from
+    // the camera get refined as much as the closest ones, which avoids cracks
+    // between faces of different levels
+    ScreenSpace {
+        camera: &'a Camera,
+        image_height: u32,
+        max_edge_pixels: f64,
+        max_level: u32,
+    },
+}
+
+// What's wrong with the faces, UVs or creases given to a ControlMesh. Faces
+// are given by their index
+#[derive(Debug, Clone, PartialEq)]
+pub enum ControlMeshError {
+    TooFewVertices { face: usize },
+    VertexOutOfRange { face: usize },
+    RepeatedVertex { face: usize },
+    // Number of UV lists given, when there are faces more or less
+    UvFaceCount(usize),
+    UvCornerCount { face: usize },
+    // The two vertices aren't next to each other in any face
+    NotAnEdge(usize, usize),
+    // Sharpness has to be 0 or more, and can be f64::INFINITY
+    InvalidSharpness(f64),
+}
+
+impl fmt::Display for ControlMeshError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ControlMeshError::TooFewVertices { face } => {
+                return write!(f, "face {} has fewer than 3 vertices", face);
+            }
+            ControlMeshError::VertexOutOfRange { face } => {
+                return write!(f, "face {} has a vertex index out of range", face);
+            }
+            ControlMeshError::RepeatedVertex { face } => {
+                return write!(f, "face {} uses the same vertex more than once", face);
+            }
+            ControlMeshError::UvFaceCount(count) => {
+                return write!(f, "got UVs for {} faces, need one list per face", count);
+            }
+            ControlMeshError::UvCornerCount { face } => {
+                return write!(f, "face {} needs a UV for every corner", face);
+            }
+            ControlMeshError::NotAnEdge(a, b) => {
+                return write!(f, "vertices {} and {} don't share an edge", a, b);
+            }
+            ControlMeshError::InvalidSharpness(sharpness) => {
+                return write!(f, "crease sharpness {} isn't 0 or more", sharpness);
+            }
+        }
+    }
+}
+
+impl Error for ControlMeshError {}
+
+// Quad/triangle control cage. UVs are face-varying (one per face corner) and are
+// interpolated linearly, so that seams stay where the modeler put them. Faces,
+// UVs and creases are checked when they're given, so they're only readable
+// from outside
+pub struct ControlMesh {
+    pub positions: Vec<Vec3>,
+    faces: Vec<Vec<usize>>,
+    face_uvs: Option<Vec<Vec<(f64, f64)>>>,
+    // Semi-sharp creases, keyed by the sorted vertex pair of the edge. Sharpness
+    // decreases by one per level; f64::INFINITY keeps the edge sharp forever.
+    // Boundary edges are always treated as infinitely sharp
+    creases: HashMap<(usize, usize), f64>,
+    pub scheme: SubdivisionScheme,
+}
+
+fn edge_key(a: usize, b: usize) -> (usize, usize) {
+    if a < b {
+        return (a, b);
+    }
+    return (b, a);
+}
+
+fn lerp_uv(a: (f64, f64), b: (f64, f64), t: f64) -> (f64, f64) {
+    return (a.0 + (b.0 - a.0) * t, a.1 + (b.1 - a.1) * t);
+}
+
+fn average_uv(uvs: &[(f64, f64)]) -> (f64, f64) {
+    let n = uvs.len() as f64;
+    let sum = uvs
+        .iter()
+        .fold((0.0, 0.0), |acc, uv| (acc.0 + uv.0, acc.1 + uv.1));
+    return (sum.0 / n, sum.1 / n);
+}
+
+// Union-find lookup with path halving
+fn find_root(group: &mut [usize], mut i: usize) -> usize {
+    while group[i] != i {
+        group[i] = group[group[i]];
+        i = group[i];
+    }
+    return i;
+}
+
+// Edge and vertex adjacency for one level of the mesh
+struct Topology {
+    edge_verts: Vec<(usize, usize)>,
+    edge_faces: Vec<Vec<usize>>,
+    edge_sharpness: Vec<f64>,
+    edge_lookup: HashMap<(usize, usize), usize>,
+    vertex_edges: Vec<Vec<usize>>,
+    vertex_faces: Vec<Vec<usize>>,
+}
+
+impl Topology {
+    fn new(mesh: &ControlMesh) -> Topology {
+        let mut topo = Topology {
+            edge_verts: Vec::new(),
+            edge_faces: Vec::new(),
+            edge_sharpness: Vec::new(),
+            edge_lookup: HashMap::new(),
+            vertex_edges: vec![Vec::new(); mesh.positions.len()],
+            vertex_faces: vec![Vec::new(); mesh.positions.len()],
+        };
+
+        for (f, face) in mesh.faces.iter().enumerate() {
+            for i in 0..face.len() {
+                let a = face[i];
+                let b = face[(i + 1) % face.len()];
+                topo.vertex_faces[a].push(f);
+
+                let key = edge_key(a, b);
+                let edge = match topo.edge_lookup.get(&key) {
+                    Some(e) => *e,
+                    None => {
+                        let e = topo.edge_verts.len();
+                        topo.edge_lookup.insert(key, e);
+                        topo.edge_verts.push(key);
+                        topo.edge_faces.push(Vec::new());
+                        topo.edge_sharpness
+                            .push(*mesh.creases.get(&key).unwrap_or(&0.0));
+                        topo.vertex_edges[key.0].push(e);
+                        topo.vertex_edges[key.1].push(e);
+                        e
+                    }
+                };
+                topo.edge_faces[edge].push(f);
+            }
+        }
+
+        // Boundaries and non-manifold edges can't use the smooth rules
+        for e in 0..topo.edge_verts.len() {
+            if topo.edge_faces[e].len() != 2 {
+                topo.edge_sharpness[e] = f64::INFINITY;
+            }
+        }
+
+        return topo;
+    }
+
+    fn edge(&self, a: usize, b: usize) -> usize {
+        return self.edge_lookup[&edge_key(a, b)];
+    }
+
+    fn other_vertex(&self, e: usize, v: usize) -> usize {
+        let (a, b) = self.edge_verts[e];
+        if a == v {
+            return b;
+        }
+        return a;
+    }
+
+    // Blends a smooth vertex position with the crease/corner rules, depending on
+    // how many sharp edges meet at the vertex (DeRose et al. 1998)
+    fn vertex_point(&self, positions: &[Vec3], v: usize, smooth: Vec3) -> Vec3 {
+        let sharp_edges: Vec<usize> = self.vertex_edges[v]
+            .iter()
+            .cloned()
+            .filter(|e| self.edge_sharpness[*e] > 0.0)
+            .collect();
+
+        if sharp_edges.len() < 2 {
+            return smooth;
+        }
+
+        let sharp = if sharp_edges.len() == 2 {
+            let a = positions[self.other_vertex(sharp_edges[0], v)];
+            let b = positions[self.other_vertex(sharp_edges[1], v)];
+            (a + positions[v] * 6.0 + b) / 8.0
+        } else {
+            positions[v]
+        };
+
+        let sharpness = sharp_edges
+            .iter()
+            .map(|e| self.edge_sharpness[*e])
+            .sum::<f64>()
+            / sharp_edges.len() as f64;
+        if sharpness >= 1.0 {
+            return sharp;
+        }
+        return smooth * (1.0 - sharpness) + sharp * sharpness;
+    }
+
+    fn edge_point(&self, positions: &[Vec3], e: usize, smooth: Vec3) -> Vec3 {
+        let (a, b) = self.edge_verts[e];
+        let midpoint = (positions[a] + positions[b]) * 0.5;
+        let sharpness = self.edge_sharpness[e];
+        if sharpness >= 1.0 {
+            return midpoint;
+        } else if sharpness <= 0.0 {
+            return smooth;
+        }
+        return smooth * (1.0 - sharpness) + midpoint * sharpness;
+    }
+
+    // Creases for the child mesh: each sharp edge splits into two edges that are
+    // one level less sharp. edge_point_index maps an edge to its new vertex
+    fn child_creases(
+        &self,
+        mesh: &ControlMesh,
+        edge_point_index: impl Fn(usize) -> usize,
+    ) -> HashMap<(usize, usize), f64> {
+        let mut creases = HashMap::new();
+        for (key, sharpness) in mesh.creases.iter() {
+            let child_sharpness = sharpness - 1.0;
+            if child_sharpness <= 0.0 {
+                continue;
+            }
+
+            if let Some(e) = self.edge_lookup.get(key) {
+                let mid = edge_point_index(*e);
+                creases.insert(edge_key(key.0, mid), child_sharpness);
+                creases.insert(edge_key(mid, key.1), child_sharpness);
+            }
+        }
+        return creases;
+    }
+}
+
+impl ControlMesh {
+    // Rejects faces with fewer than 3 vertices, indices past the end of
+    // positions or the same vertex twice, which refining would otherwise trip
+    // over much later
+    pub fn new(
+        positions: Vec<Vec3>,
+        faces: Vec<Vec<usize>>,
+        scheme: SubdivisionScheme,
+    ) -> Result<ControlMesh, ControlMeshError> {
+        for (f, face) in faces.iter().enumerate() {
+            if face.len() < 3 {
+                return Err(ControlMeshError::TooFewVertices { face: f });
+            }
+            if face.iter().any(|&v| v >= positions.len()) {
+                return Err(ControlMeshError::VertexOutOfRange { face: f });
+            }
+            for (i, v) in face.iter().enumerate() {
+                if face[i + 1..].contains(v) {
+                    return Err(ControlMeshError::RepeatedVertex { face: f });
+                }
+            }
+        }
+        return Ok(ControlMesh {
+            positions,
+            faces,
+            face_uvs: None,
+            creases: HashMap::new(),
+            scheme,
+        });
+    }
+
+    // Takes one UV per corner of every face, in the same order as faces
+    pub fn with_uvs(
+        mut self,
+        face_uvs: Vec<Vec<(f64, f64)>>,
+    ) -> Result<ControlMesh, ControlMeshError> {
+        if face_uvs.len() != self.faces.len() {
+            return Err(ControlMeshError::UvFaceCount(face_uvs.len()));
+        }
+        for (f, (face, uvs)) in self.faces.iter().zip(face_uvs.iter()).enumerate() {
+            if uvs.len() != face.len() {
+                return Err(ControlMeshError::UvCornerCount { face: f });
+            }
+        }
+        self.face_uvs = Some(face_uvs);
+        return Ok(self);
+    }
+
+    // Marks the edge between a and b as sharp, see creases
+    pub fn add_crease(
+        &mut self,
+        a: usize,
+        b: usize,
+        sharpness: f64,
+    ) -> Result<(), ControlMeshError> {
+        if sharpness.is_nan() || sharpness < 0.0 {
+            return Err(ControlMeshError::InvalidSharpness(sharpness));
+        }
+        let key = edge_key(a, b);
+        let is_edge = self.faces.iter().any(|face| {
+            (0..face.len()).any(|i| edge_key(face[i], face[(i + 1) % face.len()]) == key)
+        });
+        if a == b || !is_edge {
+            return Err(ControlMeshError::NotAnEdge(a, b));
+        }
+        self.creases.insert(key, sharpness);
+        return Ok(());
+    }
+
+    pub fn faces(&self) -> &[Vec<usize>] {
+        return &self.faces;
+    }
+
+    pub fn face_uvs(&self) -> Option<&[Vec<(f64, f64)>]> {
+        return self.face_uvs.as_deref();
+    }
+
+    pub fn creases(&self) -> &HashMap<(usize, usize), f64> {
+        return &self.creases;
+    }
+
+    // How many times tessellate() refines the whole mesh for level
+    pub fn level_for(&self, level: &SubdivisionLevel) -> u32 {
+        match *level {
+            SubdivisionLevel::Uniform(level) => return level,
+            SubdivisionLevel::ScreenSpace {
+                camera,
+                image_height,
+                max_edge_pixels,
+                max_level,
+            } => {
+                let mut longest_on_screen: f64 = 0.0;
+                for face in &self.faces {
+                    for i in 0..face.len() {
+                        let a = self.positions[face[i]];
+                        let b = self.positions[face[(i + 1) % face.len()]];
+                        let mid = (a + b) * 0.5;
+                        if let Some(scale) = camera.pixels_per_unit(&mid, image_height) {
+                            longest_on_screen = longest_on_screen.max((b - a).length() * scale);
+                        }
+                    }
+                }
+
+                // Every level halves the edges
+                if longest_on_screen <= max_edge_pixels {
+                    return 0;
+                }
+                let needed = (longest_on_screen / max_edge_pixels).log2().ceil() as u32;
+                return needed.min(max_level);
+            }
+        }
+    }
+
+    pub fn subdivide(&self, levels: u32) -> ControlMesh {
+        let mut result = self.clone_mesh();
+        for _ in 0..levels {
+            result = result.subdivide_once();
+        }
+        return result;
+    }
+
+    pub fn subdivide_once(&self) -> ControlMesh {
+        match self.scheme {
+            SubdivisionScheme::CatmullClark => return self.catmull_clark(),
+            SubdivisionScheme::Loop => return self.triangulated().loop_subdivision(),
+        }
+    }
+
+    // Refines the cage and converts it into a renderable mesh. Normals are
+    // smoothed across smooth edges and split along every edge that is still
+    // sharp after refinement, so that creases also show up in the shading
+    pub fn tessellate(&self, level: &SubdivisionLevel, material: Rc<dyn Material>) -> TriangleMesh {
+        let refined = self.subdivide(self.level_for(level));
+        return refined.to_triangle_mesh(material);
+    }
+
+    fn clone_mesh(&self) -> ControlMesh {
+        return ControlMesh {
+            positions: self.positions.clone(),
+            faces: self.faces.clone(),
+            face_uvs: self.face_uvs.clone(),
+            creases: self.creases.clone(),
+            scheme: self.scheme,
+        };
+    }
+
+    fn triangulated(&self) -> ControlMesh {
+        if self.faces.iter().all(|f| f.len() == 3) {
+            return self.clone_mesh();
+        }
+
+        let mut faces = Vec::new();
+        let mut face_uvs = Vec::new();
+        for (f, face) in self.faces.iter().enumerate() {
+            for i in 1..face.len() - 1 {
+                faces.push(vec![face[0], face[i], face[i + 1]]);
+                if let Some(uvs) = &self.face_uvs {
+                    face_uvs.push(vec![uvs[f][0], uvs[f][i], uvs[f][i + 1]]);
+                }
+            }
+        }
+
+        let mut result = self.clone_mesh();
+        result.faces = faces;
+        if self.face_uvs.is_some() {
+            result.face_uvs = Some(face_uvs);
+        }
+        return result;
+    }
+
+    fn catmull_clark(&self) -> ControlMesh {
+        let topo = Topology::new(self);
+        let num_verts = self.positions.len();
+        let num_faces = self.faces.len();
+
+        let face_points: Vec<Vec3> = self
+            .faces
+            .iter()
+            .map(|face| {
+                let sum = face
+                    .iter()
+                    .fold(Vec3::new(0.0, 0.0, 0.0), |acc, v| acc + self.positions[*v]);
+                sum / face.len() as f64
+            })
+            .collect();
+
+        let edge_points: Vec<Vec3> = (0..topo.edge_verts.len())
+            .map(|e| {
+                let (a, b) = topo.edge_verts[e];
+                let smooth = if topo.edge_faces[e].len() == 2 {
+                    (self.positions[a]
+                        + self.positions[b]
+                        + face_points[topo.edge_faces[e][0]]
+                        + face_points[topo.edge_faces[e][1]])
+                        / 4.0
+                } else {
+                    (self.positions[a] + self.positions[b]) * 0.5
+                };
+                topo.edge_point(&self.positions, e, smooth)
+            })
+            .collect();
+
+        let vertex_points: Vec<Vec3> = (0..num_verts)
+            .map(|v| {
+                let n = topo.vertex_edges[v].len();
+                if n == 0 || topo.vertex_faces[v].is_empty() {
+                    return self.positions[v];
+                }
+
+                let f = topo.vertex_faces[v]
+                    .iter()
+                    .fold(Vec3::new(0.0, 0.0, 0.0), |acc, f| acc + face_points[*f])
+                    / topo.vertex_faces[v].len() as f64;
+                let r = topo.vertex_edges[v]
+                    .iter()
+                    .fold(Vec3::new(0.0, 0.0, 0.0), |acc, e| {
+                        let (a, b) = topo.edge_verts[*e];
+                        acc + (self.positions[a] + self.positions[b]) * 0.5
+                    })
+                    / n as f64;
+                let smooth = (f + r * 2.0 + self.positions[v] * (n as f64 - 3.0)) / n as f64;
+                topo.vertex_point(&self.positions, v, smooth)
+            })
+            .collect();
+
+        // New vertices are laid out as [vertex points, face points, edge points]
+        let face_point_index = |f: usize| num_verts + f;
+        let edge_point_index = |e: usize| num_verts + num_faces + e;
+
+        let mut faces = Vec::new();
+        let mut face_uvs = Vec::new();
+        for (f, face) in self.faces.iter().enumerate() {
+            let n = face.len();
+            let uvs = self.face_uvs.as_ref().map(|uvs| &uvs[f]);
+            let center_uv = uvs.map(|uvs| average_uv(uvs));
+
+            for i in 0..n {
+                let prev = (i + n - 1) % n;
+                let next = (i + 1) % n;
+                faces.push(vec![
+                    face[i],
+                    edge_point_index(topo.edge(face[i], face[next])),
+                    face_point_index(f),
+                    edge_point_index(topo.edge(face[prev], face[i])),
+                ]);
+
+                if let (Some(uvs), Some(center_uv)) = (uvs, center_uv) {
+                    face_uvs.push(vec![
+                        uvs[i],
+                        lerp_uv(uvs[i], uvs[next], 0.5),
+                        center_uv,
+                        lerp_uv(uvs[prev], uvs[i], 0.5),
+                    ]);
+                }
+            }
+        }
+
+        let mut positions = vertex_points;
+        positions.extend(face_points);
+        positions.extend(edge_points);
+
+        return ControlMesh {
+            positions,
+            faces,
+            face_uvs: self.face_uvs.as_ref().map(|_| face_uvs),
+            creases: topo.child_creases(self, edge_point_index),
+            scheme: self.scheme,
+        };
+    }
+
+    fn loop_subdivision(&self) -> ControlMesh {
+        let topo = Topology::new(self);
+        let num_verts = self.positions.len();
+
+        let opposite = |f: usize, e: usize| {
+            let (a, b) = topo.edge_verts[e];
+            return *self.faces[f]
+                .iter()
+                .find(|v| **v != a && **v != b)
+                .expect("new() rejects faces that repeat vertices");
+        };
+
+        let edge_points: Vec<Vec3> = (0..topo.edge_verts.len())
+            .map(|e| {
+                let (a, b) = topo.edge_verts[e];
+                let smooth = if topo.edge_faces[e].len() == 2 {
+                    let c = opposite(topo.edge_faces[e][0], e);
+                    let d = opposite(topo.edge_faces[e][1], e);
+                    (self.positions[a] + self.positions[b]) * (3.0 / 8.0)
+                        + (self.positions[c] + self.positions[d]) * (1.0 / 8.0)
+                } else {
+                    (self.positions[a] + self.positions[b]) * 0.5
+                };
+                topo.edge_point(&self.positions, e, smooth)
+            })
+            .collect();
+
+        let vertex_points: Vec<Vec3> = (0..num_verts)
+            .map(|v| {
+                let n = topo.vertex_edges[v].len();
+                if n == 0 {
+                    return self.positions[v];
+                }
+
+                let nf = n as f64;
+                let c = 3.0 / 8.0 + 0.25 * (2.0 * PI / nf).cos();
+                let beta = (5.0 / 8.0 - c * c) / nf;
+                let neighbors = topo.vertex_edges[v]
+                    .iter()
+                    .fold(Vec3::new(0.0, 0.0, 0.0), |acc, e| {
+                        acc + self.positions[topo.other_vertex(*e, v)]
+                    });
+                let smooth = self.positions[v] * (1.0 - nf * beta) + neighbors * beta;
+                topo.vertex_point(&self.positions, v, smooth)
+            })
+            .collect();
+
+        let edge_point_index = |e: usize| num_verts + e;
+
+        let mut faces = Vec::new();
+        let mut face_uvs = Vec::new();
+        for (f, face) in self.faces.iter().enumerate() {
+            let (a, b, c) = (face[0], face[1], face[2]);
+            let ab = edge_point_index(topo.edge(a, b));
+            let bc = edge_point_index(topo.edge(b, c));
+            let ca = edge_point_index(topo.edge(c, a));
+            faces.push(vec![a, ab, ca]);
+            faces.push(vec![ab, b, bc]);
+            faces.push(vec![ca, bc, c]);
+            faces.push(vec![ab, bc, ca]);
+
+            if let Some(uvs) = &self.face_uvs {
+                let (uv_a, uv_b, uv_c) = (uvs[f][0], uvs[f][1], uvs[f][2]);
+                let uv_ab = lerp_uv(uv_a, uv_b, 0.5);
+                let uv_bc = lerp_uv(uv_b, uv_c, 0.5);
+                let uv_ca = lerp_uv(uv_c, uv_a, 0.5);
+                face_uvs.push(vec![uv_a, uv_ab, uv_ca]);
+                face_uvs.push(vec![uv_ab, uv_b, uv_bc]);
+                face_uvs.push(vec![uv_ca, uv_bc, uv_c]);
+                face_uvs.push(vec![uv_ab, uv_bc, uv_ca]);
+            }
+        }
+
+        let mut positions = vertex_points;
+        positions.extend(edge_points);
+
+        return ControlMesh {
+            positions,
+            faces,
+            face_uvs: self.face_uvs.as_ref().map(|_| face_uvs),
+            creases: topo.child_creases(self, edge_point_index),
+            scheme: self.scheme,
+        };
+    }
+
+    // Newell's method, so that non-planar quads still get a sensible normal.
+    // The length is proportional to the face area
+    fn face_normal(&self, f: usize) -> Vec3 {
+        let face = &self.faces[f];
+        let mut normal = Vec3::new(0.0, 0.0, 0.0);
+        for i in 0..face.len() {
+            let a = self.positions[face[i]];
+            let b = self.positions[face[(i + 1) % face.len()]];
+            normal += Vec3::new(
+                (a.y - b.y) * (a.z + b.z),
+                (a.z - b.z) * (a.x + b.x),
+                (a.x - b.x) * (a.y + b.y),
+            );
+        }
+        return normal;
+    }
+
+    pub fn to_triangle_mesh(&self, material: Rc<dyn Material>) -> TriangleMesh {
+        let topo = Topology::new(self);
+        let face_normals: Vec<Vec3> = (0..self.faces.len()).map(|f| self.face_normal(f)).collect();
+
+        // Faces around a vertex are grouped whenever they share a smooth edge, and
+        // every group gets its own averaged normal
+        let mut normals = Vec::new();
+        let mut corner_normal: HashMap<(usize, usize), usize> = HashMap::new();
+        for v in 0..self.positions.len() {
+            let faces = &topo.vertex_faces[v];
+            let mut group: Vec<usize> = (0..faces.len()).collect();
+
+            for e in &topo.vertex_edges[v] {
+                if topo.edge_sharpness[*e] > 0.0 {
+                    continue;
+                }
+                let f0 = faces.iter().position(|f| *f == topo.edge_faces[*e][0]);
+                let f1 = faces.iter().position(|f| *f == topo.edge_faces[*e][1]);
+                if let (Some(f0), Some(f1)) = (f0, f1) {
+                    let (r0, r1) = (find_root(&mut group, f0), find_root(&mut group, f1));
+                    group[r0] = r1;
+                }
+            }
+
+            let mut group_normal: HashMap<usize, usize> = HashMap::new();
+            for i in 0..faces.len() {
+                let root = find_root(&mut group, i);
+                let index = *group_normal.entry(root).or_insert_with(|| {
+                    normals.push(Vec3::new(0.0, 0.0, 0.0));
+                    normals.len() - 1
+                });
+                normals[index] += face_normals[faces[i]];
+                corner_normal.insert((faces[i], v), index);
+            }
+        }
+        for n in normals.iter_mut() {
+            if n.squared_length() > 0.0 {
+                n.normalize();
+            }
+        }
+
+        let mut uvs = Vec::new();
+        let mut triangles = Vec::new();
+        for (f, face) in self.faces.iter().enumerate() {
+            let uv_start = uvs.len();
+            if let Some(face_uvs) = &self.face_uvs {
+                uvs.extend(face_uvs[f].iter().cloned());
+            }
+
+            // Fan triangulation, which for quads splits along the 0-2 diagonal
+            for i in 1..face.len() - 1 {
+                let corners = [0, i, i + 1];
+                triangles.push(MeshTriangle {
+                    positions: [face[corners[0]], face[corners[1]], face[corners[2]]],
+                    normals: Some([
+                        corner_normal[&(f, face[corners[0]])],
+                        corner_normal[&(f, face[corners[1]])],
+                        corner_normal[&(f, face[corners[2]])],
+                    ]),
+                    uvs: self.face_uvs.as_ref().map(|_| {
+                        [
+                            uv_start + corners[0],
+                            uv_start + corners[1],
+                            uv_start + corners[2],
+                        ]
+                    }),
+                });
+            }
+        }
+
+        return TriangleMesh::new(self.positions.clone(), normals, uvs, triangles, material);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn triangle_positions() -> Vec<Vec3> {
+        return vec![
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+        ];
+    }
+
+    // [-1, 1]^3, with vertex i at the corner whose coordinates are the bits of i
+    fn cube(scheme: SubdivisionScheme) -> ControlMesh {
+        let positions = (0..8)
+            .map(|i| {
+                let coordinate = |bit: usize| if i & bit != 0 { 1.0 } else { -1.0 };
+                Vec3::new(coordinate(4), coordinate(2), coordinate(1))
+            })
+            .collect();
+        let faces = vec![
+            vec![0, 1, 3, 2],
+            vec![4, 6, 7, 5],
+            vec![0, 4, 5, 1],
+            vec![2, 3, 7, 6],
+            vec![0, 2, 6, 4],
+            vec![1, 5, 7, 3],
+        ];
+        return ControlMesh::new(positions, faces, scheme).unwrap();
+    }
+
+    fn assert_close(a: &Vec3, b: &Vec3) {
+        assert!((*a - *b).length() < 1e-9, "{:?} != {:?}", a, b);
+    }
+
+    fn has_point(mesh: &ControlMesh, p: &Vec3) -> bool {
+        return mesh.positions.iter().any(|q| (*q - *p).length() < 1e-9);
+    }
+
+    #[test]
+    fn catmull_clark_refines_a_cube() {
+        let refined = cube(SubdivisionScheme::CatmullClark).subdivide_once();
+
+        // A new vertex per old vertex, face and edge, and a quad per old corner
+        assert_eq!(refined.positions.len(), 8 + 6 + 12);
+        assert_eq!(refined.faces.len(), 24);
+        assert!(refined.faces.iter().all(|f| f.len() == 4));
+
+        // Corners move to (F + 2R + (n - 3)P) / n, face points are the centers
+        // and edge points average the edge with the two face points
+        assert_close(
+            &refined.positions[7],
+            &Vec3::new(5.0 / 9.0, 5.0 / 9.0, 5.0 / 9.0),
+        );
+        assert_close(
+            &refined.positions[0],
+            &Vec3::new(-5.0 / 9.0, -5.0 / 9.0, -5.0 / 9.0),
+        );
+        assert!(has_point(&refined, &Vec3::new(1.0, 0.0, 0.0)));
+        assert!(has_point(&refined, &Vec3::new(0.75, 0.75, 0.0)));
+    }
+
+    #[test]
+    fn sharp_creases_keep_corners_and_edges() {
+        let mut mesh = cube(SubdivisionScheme::CatmullClark);
+        let edges = [
+            (0, 1),
+            (2, 3),
+            (4, 5),
+            (6, 7),
+            (0, 2),
+            (1, 3),
+            (4, 6),
+            (5, 7),
+            (0, 4),
+            (1, 5),
+            (2, 6),
+            (3, 7),
+        ];
+        for (a, b) in edges.iter() {
+            mesh.add_crease(*a, *b, f64::INFINITY).unwrap();
+        }
+        let refined = mesh.subdivide_once();
+
+        for v in 0..8 {
+            assert_close(&refined.positions[v], &mesh.positions[v]);
+        }
+        assert!(has_point(&refined, &Vec3::new(1.0, 1.0, 0.0)));
+        assert!(has_point(&refined, &Vec3::new(-1.0, 0.0, 1.0)));
+        assert_eq!(refined.creases.len(), 24);
+        assert!(refined.creases.values().all(|s| *s == f64::INFINITY));
+    }
+
+    #[test]
+    fn crease_sharpness_drops_by_one_per_level() {
+        let mut mesh = cube(SubdivisionScheme::CatmullClark);
+        mesh.add_crease(6, 7, 2.0).unwrap();
+
+        let once = mesh.subdivide_once();
+        assert_eq!(once.creases.len(), 2);
+        assert!(once.creases.values().all(|s| *s == 1.0));
+        // Fully sharp for this level, so the new edge point is the midpoint
+        assert!(has_point(&once, &Vec3::new(1.0, 1.0, 0.0)));
+        assert!(once
+            .creases
+            .keys()
+            .all(|(a, b)| *a == 6 || *a == 7 || *b == 6 || *b == 7));
+
+        assert!(once.subdivide_once().creases.is_empty());
+    }
+
+    #[test]
+    fn loop_refines_a_tetrahedron() {
+        let positions = vec![
+            Vec3::new(1.0, 1.0, 1.0),
+            Vec3::new(1.0, -1.0, -1.0),
+            Vec3::new(-1.0, 1.0, -1.0),
+            Vec3::new(-1.0, -1.0, 1.0),
+        ];
+        let faces = vec![vec![0, 1, 2], vec![0, 3, 1], vec![0, 2, 3], vec![1, 3, 2]];
+        let mesh = ControlMesh::new(positions.clone(), faces, SubdivisionScheme::Loop).unwrap();
+        let refined = mesh.subdivide_once();
+
+        assert_eq!(refined.positions.len(), 4 + 6);
+        assert_eq!(refined.faces.len(), 16);
+
+        // With three neighbors beta is 3/16, and the neighbors add up to -p, so
+        // every vertex moves to p / 4. Edge points are 3/8 (a + b) + 1/8 (c + d),
+        // which is (a + b) / 4 here
+        for (moved, p) in refined.positions.iter().zip(positions.iter()) {
+            assert_close(moved, &(*p * 0.25));
+        }
+        for a in 0..4 {
+            for b in a + 1..4 {
+                assert!(has_point(&refined, &((positions[a] + positions[b]) * 0.25)));
+            }
+        }
+    }
+
+    #[test]
+    fn loop_triangulates_quads_first() {
+        let refined = cube(SubdivisionScheme::Loop).subdivide_once();
+        // 12 triangles with 18 edges between them
+        assert_eq!(refined.faces.len(), 12 * 4);
+        assert_eq!(refined.positions.len(), 8 + 18);
+    }
+
+    fn triangle(faces: Vec<Vec<usize>>) -> Result<ControlMesh, ControlMeshError> {
+        return ControlMesh::new(triangle_positions(), faces, SubdivisionScheme::Loop);
+    }
+
+    #[test]
+    fn rejects_faces_with_too_few_vertices() {
+        let error = ControlMeshError::TooFewVertices { face: 0 };
+        assert_eq!(triangle(vec![vec![]]).err(), Some(error.clone()));
+        assert_eq!(triangle(vec![vec![0, 1]]).err(), Some(error));
+    }
+
+    #[test]
+    fn rejects_indices_past_positions() {
+        let error = ControlMeshError::VertexOutOfRange { face: 1 };
+        assert_eq!(
+            triangle(vec![vec![0, 1, 2], vec![0, 1, 3]]).err(),
+            Some(error)
+        );
+    }
+
+    #[test]
+    fn rejects_repeated_vertices() {
+        let error = ControlMeshError::RepeatedVertex { face: 0 };
+        assert_eq!(triangle(vec![vec![0, 1, 1]]).err(), Some(error.clone()));
+        assert_eq!(triangle(vec![vec![0, 1, 0, 2]]).err(), Some(error));
+    }
+
+    #[test]
+    fn rejects_uvs_that_dont_match_faces() {
+        let mesh = || triangle(vec![vec![0, 1, 2]]).unwrap();
+        let corners = mesh().with_uvs(vec![vec![(0.0, 0.0), (1.0, 0.0)]]);
+        assert_eq!(
+            corners.err(),
+            Some(ControlMeshError::UvCornerCount { face: 0 })
+        );
+        let faces = mesh().with_uvs(Vec::new());
+        assert_eq!(faces.err(), Some(ControlMeshError::UvFaceCount(0)));
+    }
+
+    #[test]
+    fn rejects_creases_off_the_edges() {
+        let mut mesh = cube(SubdivisionScheme::CatmullClark);
+        // Opposite corners of a face, and a vertex with itself
+        assert_eq!(
+            mesh.add_crease(0, 3, 1.0),
+            Err(ControlMeshError::NotAnEdge(0, 3))
+        );
+        assert_eq!(
+            mesh.add_crease(2, 2, 1.0),
+            Err(ControlMeshError::NotAnEdge(2, 2))
+        );
+        let sharpness = mesh.add_crease(0, 1, -1.0);
+        assert_eq!(sharpness, Err(ControlMeshError::InvalidSharpness(-1.0)));
+        assert!(mesh.creases().is_empty());
+        assert_eq!(mesh.add_crease(1, 0, 1.0), Ok(()));
+    }
+}
@@ -0,0 +1,92 @@
+use crate::hitable::HitRecord;
+use crate::material::{Material, ScatteredRay};
+use crate::medium::{self, Medium};
+use crate::microfacet::GgxDistribution;
+use crate::ray::Ray;
+use crate::rough_dielectric;
+use crate::vec3::Vec3;
+
+// Single scattering albedo that makes a random walk in a semi-infinite medium
+// come back out with the given multiple scattering albedo (Chiang et al. 2016)
+fn single_scattering_albedo(albedo: f64) -> f64 {
+    let a = albedo.clamp(0.0, 1.0);
+    let t = 4.09712 + 4.20863 * a - (9.59217 + 41.6808 * a + 17.7126 * a * a).sqrt();
+    return 1.0 - t * t;
+}
+
+// Translucent material where light diffuses below the surface, like skin,
+// marble, milk or wax. The surface is a dielectric interface, and the inside a
+// scattering medium that the integrator random walks through until the path
+// finds its way back out, so it works on any closed geometry.
+//
+// albedo is the overall color of the surface, and mean_free_path how far light
+// travels inside between scattering events, per channel and in scene units.
+// Paths still end at the integrator's bounce limit, so mean free paths much
+// smaller than the object make it darker than albedo
+pub struct Subsurface {
+    pub albedo: Vec3,
+    pub mean_free_path: Vec3,
+    pub ior: f64,
+    // See Medium::anisotropy
+    pub anisotropy: f64,
+    pub distribution: GgxDistribution,
+    // See Dielectric::priority
+    pub priority: u32,
+    medium_id: u32,
+}
+
+impl Subsurface {
+    pub fn new(albedo: Vec3, mean_free_path: Vec3, ior: f64) -> Subsurface {
+        return Subsurface {
+            albedo,
+            mean_free_path,
+            ior,
+            anisotropy: 0.0,
+            distribution: GgxDistribution::isotropic(0.0),
+            priority: 0,
+            medium_id: medium::new_medium_id(),
+        };
+    }
+
+    pub fn with_roughness(mut self, roughness: f64) -> Subsurface {
+        self.distribution = GgxDistribution::isotropic(roughness);
+        return self;
+    }
+
+    pub fn with_anisotropy(mut self, anisotropy: f64) -> Subsurface {
+        self.anisotropy = anisotropy;
+        return self;
+    }
+
+    pub fn medium(&self) -> Medium {
+        let channel = |albedo: f64, mean_free_path: f64| {
+            let extinction = 1.0 / mean_free_path.max(1e-9);
+            let scattering = extinction * single_scattering_albedo(albedo);
+            return (extinction - scattering, scattering);
+        };
+        let (absorption_r, scattering_r) = channel(self.albedo.x, self.mean_free_path.x);
+        let (absorption_g, scattering_g) = channel(self.albedo.y, self.mean_free_path.y);
+        let (absorption_b, scattering_b) = channel(self.albedo.z, self.mean_free_path.z);
+
+        return Medium {
+            id: self.medium_id,
+            ior: self.ior,
+            priority: self.priority,
+            absorption: Vec3::new(absorption_r, absorption_g, absorption_b),
+            dispersion: None,
+            scattering: Vec3::new(scattering_r, scattering_g, scattering_b),
+            anisotropy: self.anisotropy,
+        };
+    }
+}
+
+impl Material for Subsurface {
+    fn scatter(&self, r_in: &Ray, rec: &HitRecord) -> Option<ScatteredRay> {
+        return rough_dielectric::scatter_rough_dielectric(
+            r_in,
+            rec,
+            &self.medium(),
+            &self.distribution,
+        );
+    }
+}
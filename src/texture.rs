@@ -0,0 +1,208 @@
+use std::fs;
+use std::io;
+
+use crate::vec3::Vec3;
+
+pub trait Texture {
+    fn value(&self, u: f64, v: f64, p: &Vec3) -> Vec3;
+
+    // Single channel lookup for masks and height maps
+    fn scalar(&self, u: f64, v: f64, p: &Vec3) -> f64 {
+        let value = self.value(u, v, p);
+        return (value.x + value.y + value.z) / 3.0;
+    }
+}
+
+pub struct ConstantTexture {
+    pub color: Vec3,
+}
+
+impl ConstantTexture {
+    pub fn new(color: Vec3) -> ConstantTexture {
+        return ConstantTexture { color };
+    }
+}
+
+impl Texture for ConstantTexture {
+    fn value(&self, _u: f64, _v: f64, _p: &Vec3) -> Vec3 {
+        return self.color;
+    }
+}
+
+// Checkerboard in UV space, with scale squares along each direction
+pub struct CheckerTexture {
+    pub even: Vec3,
+    pub odd: Vec3,
+    pub scale: f64,
+}
+
+impl CheckerTexture {
+    pub fn new(even: Vec3, odd: Vec3, scale: f64) -> CheckerTexture {
+        return CheckerTexture { even, odd, scale };
+    }
+}
+
+impl Texture for CheckerTexture {
+    fn value(&self, u: f64, v: f64, _p: &Vec3) -> Vec3 {
+        let cell = (u * self.scale).floor() + (v * self.scale).floor();
+        if cell.rem_euclid(2.0) < 1.0 {
+            return self.even;
+        }
+        return self.odd;
+    }
+}
+
+// Bilinearly filtered image that repeats outside of [0, 1]^2. Values are kept
+// as stored in the file (no gamma conversion), which is what data textures like
+// height and normal maps need
+pub struct ImageTexture {
+    pub width: usize,
+    pub height: usize,
+    // Row major, with the first row at the top of the image (v = 1)
+    pub pixels: Vec<Vec3>,
+}
+
+impl ImageTexture {
+    // Panics unless there are exactly width * height pixels, which lookups
+    // rely on
+    pub fn new(width: usize, height: usize, pixels: Vec<Vec3>) -> ImageTexture {
+        assert_eq!(
+            pixels.len(),
+            width * height,
+            "wrong number of pixels for the image size"
+        );
+        return ImageTexture {
+            width,
+            height,
+            pixels,
+        };
+    }
+
+    // Reads both ASCII (P3) and binary (P6) PPM files
+    pub fn from_ppm(path: &str) -> io::Result<ImageTexture> {
+        let bytes = fs::read(path)?;
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+
+        // Header is whitespace separated, with comments from '#' to the end of the line
+        let mut header = Vec::new();
+        let mut pos = 0;
+        while header.len() < 4 && pos < bytes.len() {
+            if bytes[pos] == b'#' {
+                while pos < bytes.len() && bytes[pos] != b'\n' {
+                    pos += 1;
+                }
+            } else if bytes[pos].is_ascii_whitespace() {
+                pos += 1;
+            } else {
+                let start = pos;
+                while pos < bytes.len() && !bytes[pos].is_ascii_whitespace() {
+                    pos += 1;
+                }
+                header.push(String::from_utf8_lossy(&bytes[start..pos]).to_string());
+            }
+        }
+        if header.len() < 4 {
+            return Err(invalid("truncated PPM header"));
+        }
+
+        let parse = |s: &str| s.parse::<usize>().map_err(|_| invalid("bad PPM header"));
+        let width = parse(&header[1])?;
+        let height = parse(&header[2])?;
+        let max_value = parse(&header[3])? as f64;
+
+        let samples: Vec<f64> = match header[0].as_str() {
+            "P3" => String::from_utf8_lossy(&bytes[pos..])
+                .split_whitespace()
+                .map(|s| match s.parse::<f64>() {
+                    Ok(value) => Ok(value / max_value),
+                    Err(_) => Err(invalid("bad PPM sample")),
+                })
+                .collect::<io::Result<Vec<f64>>>()?,
+            "P6" => {
+                // Exactly one whitespace byte separates the header from the data
+                let data = &bytes[(pos + 1).min(bytes.len())..];
+                if max_value < 256.0 {
+                    data.iter().map(|b| *b as f64 / max_value).collect()
+                } else {
+                    data.chunks(2)
+                        .map(|c| {
+                            ((c[0] as u32) << 8 | *c.get(1).unwrap_or(&0) as u32) as f64 / max_value
+                        })
+                        .collect()
+                }
+            }
+            _ => return Err(invalid("only P3 and P6 PPM files are supported")),
+        };
+
+        if samples.len() < width * height * 3 {
+            return Err(invalid("truncated PPM data"));
+        }
+
+        let pixels = samples
+            .chunks(3)
+            .take(width * height)
+            .map(|c| Vec3::new(c[0], c[1], c[2]))
+            .collect();
+        return Ok(ImageTexture::new(width, height, pixels));
+    }
+
+    fn texel(&self, x: i64, y: i64) -> Vec3 {
+        let x = x.rem_euclid(self.width as i64) as usize;
+        let y = y.rem_euclid(self.height as i64) as usize;
+        return self.pixels[y * self.width + x];
+    }
+}
+
+impl Texture for ImageTexture {
+    fn value(&self, u: f64, v: f64, _p: &Vec3) -> Vec3 {
+        if self.pixels.is_empty() {
+            return Vec3::new(0.0, 0.0, 0.0);
+        }
+
+        // Texel centers sit at half integer coordinates
+        let x = u * self.width as f64 - 0.5;
+        let y = (1.0 - v) * self.height as f64 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (tx, ty) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as i64, y0 as i64);
+
+        let top = self.texel(x0, y0) * (1.0 - tx) + self.texel(x0 + 1, y0) * tx;
+        let bottom = self.texel(x0, y0 + 1) * (1.0 - tx) + self.texel(x0 + 1, y0 + 1) * tx;
+        return top * (1.0 - ty) + bottom * ty;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Writes contents into the temporary directory, and returns its path
+    fn write_ppm(name: &str, contents: &str) -> String {
+        let path = std::env::temp_dir().join(format!("{}-{}.ppm", name, std::process::id()));
+        fs::write(&path, contents).unwrap();
+        return path.to_str().unwrap().to_string();
+    }
+
+    #[test]
+    fn reads_ascii_ppm() {
+        let path = write_ppm("ascii", "P3\n# two pixels\n2 1\n255\n255 0 0 0 51 255\n");
+        let texture = ImageTexture::from_ppm(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!((texture.width, texture.height), (2, 1));
+        assert!((texture.pixels[1] - Vec3::new(0.0, 0.2, 1.0)).length() < 1e-12);
+    }
+
+    #[test]
+    fn rejects_bad_ascii_samples() {
+        let path = write_ppm("bad-sample", "P3\n2 1\n255\n255 0 0 0 oops 255\n");
+        let error = ImageTexture::from_ppm(&path).err().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    #[should_panic(expected = "wrong number of pixels")]
+    fn rejects_pixels_without_a_size() {
+        ImageTexture::new(0, 1, vec![Vec3::new(1.0, 1.0, 1.0)]);
+    }
+}
@@ -1,16 +1,68 @@
 use rand::Rng;
+use std::cell::RefCell;
+use std::rc::Rc;
 
+use crate::sampler::Sampler;
 use crate::vec3::Vec3;
 
-pub fn random_in_unit_sphere() -> Vec3 {
-    let mut rng = rand::thread_rng();
+thread_local! {
+    static RANDOM_SOURCE: RefCell<Option<Rc<RefCell<dyn Sampler>>>> = RefCell::new(None);
+}
+
+// Puts the thread's random generator back when dropped, see
+// install_random_source()
+pub struct RandomSourceGuard {
+    _private: (),
+}
+
+impl Drop for RandomSourceGuard {
+    fn drop(&mut self) {
+        RANDOM_SOURCE.with(|current| *current.borrow_mut() = None);
+    }
+}
+
+// Makes random_double() on this thread draw from source rather than the
+// thread's random generator, until the guard is dropped, panics included.
+// Integrators that need to replay or perturb paths, like Metropolis, install
+// their own here. Only one source can be installed at a time
+pub fn install_random_source(source: Rc<RefCell<dyn Sampler>>) -> RandomSourceGuard {
+    RANDOM_SOURCE.with(|current| {
+        let mut current = current.borrow_mut();
+        assert!(current.is_none(), "a random source is already installed");
+        *current = Some(source);
+    });
+    return RandomSourceGuard { _private: () };
+}
+
+// Uniform in [0, 1). Everything random about a path goes through this, from
+// the camera lens to materials and lights. Sources that call back in here
+// themselves, like RandomSampler, get the thread's random generator
+pub fn random_double() -> f64 {
+    let from_source = RANDOM_SOURCE.with(|current| match current.borrow().as_ref() {
+        Some(source) => match source.try_borrow_mut() {
+            Ok(mut source) => Some(source.next_1d()),
+            Err(_) => None,
+        },
+        None => None,
+    });
+    match from_source {
+        Some(value) => return value,
+        None => return rand::thread_rng().gen::<f64>(),
+    }
+}
 
+// Uniform over 0..n, for n > 0
+pub fn random_index(n: usize) -> usize {
+    return ((random_double() * n as f64) as usize).min(n - 1);
+}
+
+pub fn random_in_unit_sphere() -> Vec3 {
     loop {
         // Random point within [-1, 1] cube
         let p = Vec3::new(
-            2.0 * rng.gen::<f64>() - 1.0,
-            2.0 * rng.gen::<f64>() - 1.0,
-            2.0 * rng.gen::<f64>() - 1.0,
+            2.0 * random_double() - 1.0,
+            2.0 * random_double() - 1.0,
+            2.0 * random_double() - 1.0,
         );
 
         if p.squared_length() < 1.0 {
@@ -20,10 +72,8 @@ pub fn random_in_unit_sphere() -> Vec3 {
 }
 
 pub fn random_in_unit_disk() -> Vec3 {
-    let mut rng = rand::thread_rng();
-
     loop {
-        let p = Vec3::new(rng.gen::<f64>(), rng.gen::<f64>(), 0.0) * 2.0 - Vec3::new(1.0, 1.0, 0.0);
+        let p = Vec3::new(random_double(), random_double(), 0.0) * 2.0 - Vec3::new(1.0, 1.0, 0.0);
 
         if p.dot(&p) < 1.0 {
             return p;
@@ -31,6 +81,39 @@ pub fn random_in_unit_disk() -> Vec3 {
     }
 }
 
+// Direction in the local shading frame (z up) distributed proportionally to
+// its cosine with z, i.e. with pdf cos(theta) / pi
+pub fn random_cosine_direction() -> Vec3 {
+    let r1 = random_double();
+    let r2 = random_double();
+
+    let phi = 2.0 * std::f64::consts::PI * r1;
+    let r = r2.sqrt();
+    return Vec3::new(r * phi.cos(), r * phi.sin(), (1.0 - r2).max(0.0).sqrt());
+}
+
+// Builds two unit vectors perpendicular to the unit vector n and to each other
+// (Duff et al. 2017)
+pub fn orthonormal_basis(n: &Vec3) -> (Vec3, Vec3) {
+    let sign = 1.0_f64.copysign(n.z);
+    let a = -1.0 / (sign + n.z);
+    let b = n.x * n.y * a;
+    return (
+        Vec3::new(1.0 + sign * n.x * n.x * a, sign * b, -sign * n.x),
+        Vec3::new(b, sign + n.y * n.y * a, -n.y),
+    );
+}
+
+// Unit tangent closest to dir that is perpendicular to the unit normal n. Falls
+// back to an arbitrary tangent if dir is degenerate or parallel to n
+pub fn tangent_from(n: &Vec3, dir: &Vec3) -> Vec3 {
+    let t = *dir - n * n.dot(dir);
+    if t.squared_length() > 1e-20 {
+        return t.normalized();
+    }
+    return orthonormal_basis(n).0;
+}
+
 pub fn reflect(v: &Vec3, n: &Vec3) -> Vec3 {
     return *v - n * v.dot(n) * 2.0;
 }
@@ -46,8 +129,83 @@ pub fn refract(v: &Vec3, n: &Vec3, ni_over_nt: f64) -> Option<Vec3> {
     }
 }
 
+// Refracts the unit vector wo, which points away from the surface on the same
+// side as the unit normal n, into the other side. eta is the IOR on the other
+// side over the IOR on the side of wo. Returns None on total internal reflection
+pub fn transmit(wo: &Vec3, n: &Vec3, eta: f64) -> Option<Vec3> {
+    let cos_i = wo.dot(n);
+    let sin2_t = (1.0 - cos_i * cos_i).max(0.0) / (eta * eta);
+    if sin2_t >= 1.0 {
+        return None;
+    }
+
+    let cos_t = (1.0 - sin2_t).sqrt();
+    return Some(-wo / eta + n * (cos_i / eta - cos_t));
+}
+
+// Fraction of light left after travelling distance through a medium with the
+// given absorption coefficients
+pub fn beer_lambert(absorption: &Vec3, distance: f64) -> Vec3 {
+    return Vec3::new(
+        (-absorption.x * distance).exp(),
+        (-absorption.y * distance).exp(),
+        (-absorption.z * distance).exp(),
+    );
+}
+
+// Inverse of beer_lambert(), for specifying absorption as the color seen
+// through a given thickness. Zero transmittance is clamped to keep it finite
+pub fn absorption_from_transmittance(transmittance: &Vec3, distance: f64) -> Vec3 {
+    let coefficient = |t: f64| -t.clamp(1e-6, 1.0).ln() / distance;
+    return Vec3::new(
+        coefficient(transmittance.x),
+        coefficient(transmittance.y),
+        coefficient(transmittance.z),
+    );
+}
+
+// Rec. 709 luminance of a linear RGB color
+pub fn luminance(c: &Vec3) -> f64 {
+    return 0.2126 * c.x + 0.7152 * c.y + 0.0722 * c.z;
+}
+
 pub fn schlick(cosine: f64, ref_idx: f64) -> f64 {
     let mut r0: f64 = (1.0 - ref_idx) / (1.0 + ref_idx);
     r0 *= r0;
     return r0 + (1.0 - r0) * ((1.0 - cosine).powf(5.0));
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampler::RandomSampler;
+    use std::panic;
+
+    fn is_installed() -> bool {
+        return RANDOM_SOURCE.with(|current| current.borrow().is_some());
+    }
+
+    #[test]
+    fn sources_can_draw_from_random_double() {
+        let _source = install_random_source(Rc::new(RefCell::new(RandomSampler)));
+        let x = random_double();
+        assert!((0.0..1.0).contains(&x));
+    }
+
+    #[test]
+    fn panics_remove_the_source() {
+        let result = panic::catch_unwind(|| {
+            let _source = install_random_source(Rc::new(RefCell::new(RandomSampler)));
+            panic!("lost path");
+        });
+        assert!(result.is_err());
+        assert!(!is_installed());
+    }
+
+    #[test]
+    #[should_panic(expected = "already installed")]
+    fn sources_cannot_be_nested() {
+        let _outer = install_random_source(Rc::new(RefCell::new(RandomSampler)));
+        let _inner = install_random_source(Rc::new(RefCell::new(RandomSampler)));
+    }
+}
@@ -0,0 +1,262 @@
+use std::f64::consts::PI;
+
+use crate::bdpt::{self, Connector, Transport, Vertex};
+use crate::camera::Camera;
+use crate::integrator::{Film, Renderer};
+use crate::point_grid::PointGrid;
+use crate::sampler::Sampler;
+use crate::scene::Scene;
+use crate::vec3::Vec3;
+
+// What each kind of technique contributed to the image, MIS weights included.
+// They add up to combined
+pub struct VcmFilms {
+    pub combined: Film,
+    // Camera subpaths that found a light or the background on their own
+    pub camera_tracing: Film,
+    // Camera subpaths connected to a point picked on the lights
+    pub direct_lighting: Film,
+    // Camera subpaths connected to light subpaths
+    pub connections: Film,
+    // Light subpaths connected to the camera
+    pub light_tracing: Film,
+    // Light subpath vertices merged into nearby camera subpath vertices
+    pub merging: Film,
+}
+
+impl VcmFilms {
+    fn new(width: u32, height: u32) -> VcmFilms {
+        return VcmFilms {
+            combined: Film::new(width, height),
+            camera_tracing: Film::new(width, height),
+            direct_lighting: Film::new(width, height),
+            connections: Film::new(width, height),
+            light_tracing: Film::new(width, height),
+            merging: Film::new(width, height),
+        };
+    }
+}
+
+// Vertex connection and merging (Georgiev et al. 2012). Every iteration traces
+// a light subpath per pixel and stores its vertices, then a camera subpath per
+// pixel. Camera subpaths get connected to one light subpath the way
+// bidirectional path tracing does, and also merged with every stored light
+// vertex within a radius, like photon mapping. All of these are weighted
+// against each other with the balance heuristic, so each technique handles
+// the light it's best at: merging takes over caustics seen through mirrors and
+// glass, connections the rest.
+//
+// The radius shrinks over iterations, as initial_radius * i^((alpha - 1) / 2)
+// for the i-th, so that merging converges. Works in RGB only, merges on
+// surfaces only, and paths end after max_depth bounces
+pub struct Vcm {
+    pub iterations: u32,
+    pub initial_radius: f64,
+    pub alpha: f64,
+    pub max_depth: u32,
+}
+
+impl Vcm {
+    pub fn new(iterations: u32, initial_radius: f64) -> Vcm {
+        return Vcm {
+            iterations,
+            initial_radius,
+            alpha: 0.75,
+            max_depth: 50,
+        };
+    }
+
+    pub fn with_alpha(mut self, alpha: f64) -> Vcm {
+        self.alpha = alpha;
+        return self;
+    }
+
+    pub fn with_max_depth(mut self, max_depth: u32) -> Vcm {
+        self.max_depth = max_depth;
+        return self;
+    }
+
+    // Same as render(), but also keeps what each technique contributed
+    pub fn render_techniques(
+        &self,
+        scene: &Scene,
+        camera: &Camera,
+        sampler: &mut dyn Sampler,
+        width: u32,
+        height: u32,
+    ) -> VcmFilms {
+        let mut films = VcmFilms::new(width, height);
+        let max_depth = self.max_depth as usize;
+        let weight = 1.0 / self.iterations as f64;
+        let light_path_count = (width * height) as usize;
+        let mut splats = Vec::new();
+
+        for iteration in 1..=self.iterations {
+            let radius = self.initial_radius * (iteration as f64).powf((self.alpha - 1.0) / 2.0);
+            let connector = Connector {
+                scene,
+                camera,
+                eta: PI * radius * radius * light_path_count as f64,
+            };
+
+            // Light subpaths, connected to the camera straight away and kept
+            // for the camera subpaths to connect and merge with
+            let mut light_paths = Vec::with_capacity(light_path_count);
+            for _ in 0..light_path_count {
+                let mut light_path = Vec::new();
+                bdpt::generate_light_subpath(scene, max_depth + 1, &mut light_path);
+                for s in 2..=light_path.len() {
+                    connector.connect(&light_path, &[], s, 1, &mut splats);
+                }
+                for splat in splats.drain(..) {
+                    films.light_tracing.add_splat(&splat, weight);
+                }
+                light_paths.push(light_path);
+            }
+
+            // Lights themselves are left to the other techniques
+            let mut stored = Vec::new();
+            let mut grid = PointGrid::new(radius);
+            for (path_index, light_path) in light_paths.iter().enumerate() {
+                for (vertex_index, vertex) in light_path.iter().enumerate().skip(1) {
+                    if vertex.can_merge() {
+                        grid.insert(stored.len(), &vertex.p, radius);
+                        stored.push((path_index, vertex_index));
+                    }
+                }
+            }
+
+            for y in 0..height {
+                // The camera's t goes up, while film rows go down
+                let j = height - 1 - y;
+                for x in 0..width {
+                    let (du, dv) = sampler.next_2d();
+                    let u = (x as f64 + du) / width as f64;
+                    let v = (j as f64 + dv) / height as f64;
+                    let ray = camera.get_ray(u, v);
+
+                    let mut camera_path = vec![Vertex::camera(camera, ray.orig)];
+                    let escaped = bdpt::random_walk(
+                        scene,
+                        ray,
+                        Vec3::new(1.0, 1.0, 1.0),
+                        camera.pdf_dir(&ray.orig, &ray.dir),
+                        max_depth + 2,
+                        Transport::Radiance,
+                        &mut camera_path,
+                    );
+                    films.camera_tracing.add(x, y, &(escaped * weight));
+
+                    let light_path = &light_paths[(y * width + x) as usize];
+                    for t in 2..=camera_path.len() {
+                        for s in 0..=light_path.len() {
+                            if s + t - 2 > max_depth {
+                                continue;
+                            }
+                            let color =
+                                connector.connect(light_path, &camera_path, s, t, &mut splats);
+                            let film = match s {
+                                0 => &mut films.camera_tracing,
+                                1 => &mut films.direct_lighting,
+                                _ => &mut films.connections,
+                            };
+                            film.add(x, y, &(color * weight));
+                        }
+                    }
+
+                    let merged = self.merge(
+                        &connector,
+                        &light_paths,
+                        &stored,
+                        &grid,
+                        &camera_path,
+                        radius,
+                    );
+                    films.merging.add(x, y, &(merged * weight));
+                }
+            }
+        }
+
+        for i in 0..films.combined.pixels.len() {
+            films.combined.pixels[i] = films.camera_tracing.pixels[i]
+                + films.direct_lighting.pixels[i]
+                + films.connections.pixels[i]
+                + films.light_tracing.pixels[i]
+                + films.merging.pixels[i];
+        }
+        return films;
+    }
+
+    // Light from the stored light subpath vertices within radius of each vertex
+    // of camera_path
+    fn merge(
+        &self,
+        connector: &Connector,
+        light_paths: &[Vec<Vertex>],
+        stored: &[(usize, usize)],
+        grid: &PointGrid,
+        camera_path: &[Vertex],
+        radius: f64,
+    ) -> Vec3 {
+        let mut result = Vec3::new(0.0, 0.0, 0.0);
+        for t in 2..=camera_path.len() {
+            let pt = &camera_path[t - 1];
+            let rec = match &pt.rec {
+                Some(rec) if pt.can_merge() => rec,
+                _ => continue,
+            };
+
+            for &index in grid.near(&pt.p) {
+                let (path_index, s) = stored[index];
+                let light_path = &light_paths[path_index];
+                let vertex = &light_path[s];
+                if s + t - 2 > self.max_depth as usize
+                    || (vertex.p - pt.p).squared_length() > radius * radius
+                    || !rec.is_consistent(&vertex.wo)
+                {
+                    continue;
+                }
+                let f = rec.mat_ptr.eval(rec, &vertex.wo, &pt.wo);
+                if f.squared_length() <= 0.0 {
+                    continue;
+                }
+
+                // Weighted as if the light subpath had been connected to pt from
+                // the vertex before, which merging then skips sampling for
+                let qs = &light_path[s - 1];
+                let qs_minus = if s > 1 {
+                    Some(&light_path[s - 2])
+                } else {
+                    None
+                };
+                let sampled = if s == 1 { Some(qs) } else { None };
+                let (connections, merges) =
+                    connector.strategy_ratios(light_path, camera_path, sampled, s, t);
+                let own = qs.pdf(connector.camera, qs_minus, pt) * connector.eta;
+                let connected = if qs.delta { 0.0 } else { 1.0 };
+                let total = connected + connections + merges;
+                if own <= 0.0 || total <= 0.0 {
+                    continue;
+                }
+
+                result += pt.beta * f * vertex.beta * (own / (total * connector.eta));
+            }
+        }
+        return result;
+    }
+}
+
+impl Renderer for Vcm {
+    fn render(
+        &self,
+        scene: &Scene,
+        camera: &Camera,
+        sampler: &mut dyn Sampler,
+        width: u32,
+        height: u32,
+    ) -> Film {
+        return self
+            .render_techniques(scene, camera, sampler, width, height)
+            .combined;
+    }
+}
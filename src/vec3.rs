@@ -2,6 +2,7 @@ use std::ops::Add;
 use std::ops::AddAssign;
 use std::ops::Div;
 use std::ops::DivAssign;
+use std::ops::Index;
 use std::ops::Mul;
 use std::ops::MulAssign;
 use std::ops::Neg;
@@ -42,9 +43,9 @@ impl Vec3 {
 
     pub fn normalize(&mut self) {
         let length = 1.0 / self.length();
-        self.x = self.x * length;
-        self.y = self.y * length;
-        self.z = self.z * length;
+        self.x *= length;
+        self.y *= length;
+        self.z *= length;
     }
 
     pub fn normalized(&self) -> Vec3 {
@@ -67,6 +68,35 @@ impl Vec3 {
             z: self.x * other.y - self.y * other.x,
         };
     }
+
+    pub fn min(&self, other: &Vec3) -> Vec3 {
+        return Vec3::new(
+            self.x.min(other.x),
+            self.y.min(other.y),
+            self.z.min(other.z),
+        );
+    }
+
+    pub fn max(&self, other: &Vec3) -> Vec3 {
+        return Vec3::new(
+            self.x.max(other.x),
+            self.y.max(other.y),
+            self.z.max(other.z),
+        );
+    }
+}
+
+impl Index<usize> for Vec3 {
+    type Output = f64;
+
+    fn index(&self, axis: usize) -> &f64 {
+        match axis {
+            0 => return &self.x,
+            1 => return &self.y,
+            2 => return &self.z,
+            _ => panic!("Vec3 axis {} out of range", axis),
+        }
+    }
 }
 
 impl Add for Vec3 {
@@ -81,10 +111,10 @@ impl Add for Vec3 {
     }
 }
 
-impl<'a, 'b> Add<&'b Vec3> for &'a Vec3 {
+impl Add<&Vec3> for &Vec3 {
     type Output = Vec3;
 
-    fn add(self, other: &'b Vec3) -> Vec3 {
+    fn add(self, other: &Vec3) -> Vec3 {
         return Vec3 {
             x: self.x + other.x,
             y: self.y + other.y,
@@ -115,10 +145,10 @@ impl Sub for Vec3 {
     }
 }
 
-impl<'a, 'b> Sub<&'b Vec3> for &'a Vec3 {
+impl Sub<&Vec3> for &Vec3 {
     type Output = Vec3;
 
-    fn sub(self, other: &'b Vec3) -> Vec3 {
+    fn sub(self, other: &Vec3) -> Vec3 {
         return Vec3 {
             x: self.x - other.x,
             y: self.y - other.y,
@@ -130,9 +160,9 @@ impl<'a, 'b> Sub<&'b Vec3> for &'a Vec3 {
 impl SubAssign for Vec3 {
     fn sub_assign(&mut self, other: Vec3) {
         *self = Vec3 {
-            x: self.x + other.x,
-            y: self.y + other.y,
-            z: self.z + other.z,
+            x: self.x - other.x,
+            y: self.y - other.y,
+            z: self.z - other.z,
         };
     }
 }
@@ -149,10 +179,10 @@ impl Mul for Vec3 {
     }
 }
 
-impl<'a, 'b> Mul<&'b Vec3> for &'a Vec3 {
+impl Mul<&Vec3> for &Vec3 {
     type Output = Vec3;
 
-    fn mul(self, other: &'b Vec3) -> Vec3 {
+    fn mul(self, other: &Vec3) -> Vec3 {
         return Vec3 {
             x: self.x * other.x,
             y: self.y * other.y,
@@ -173,7 +203,7 @@ impl Mul<f64> for Vec3 {
     }
 }
 
-impl<'a> Mul<f64> for &'a Vec3 {
+impl Mul<f64> for &Vec3 {
     type Output = Vec3;
 
     fn mul(self, other: f64) -> Vec3 {
@@ -207,10 +237,10 @@ impl Div for Vec3 {
     }
 }
 
-impl<'a, 'b> Div<&'b Vec3> for &'a Vec3 {
+impl Div<&Vec3> for &Vec3 {
     type Output = Vec3;
 
-    fn div(self, other: &'b Vec3) -> Vec3 {
+    fn div(self, other: &Vec3) -> Vec3 {
         return Vec3 {
             x: self.x / other.x,
             y: self.y / other.y,
@@ -231,7 +261,7 @@ impl Div<f64> for Vec3 {
     }
 }
 
-impl<'a> Div<f64> for &'a Vec3 {
+impl Div<f64> for &Vec3 {
     type Output = Vec3;
 
     fn div(self, other: f64) -> Vec3 {
@@ -275,7 +305,7 @@ impl Neg for Vec3 {
     }
 }
 
-impl<'a> Neg for &'a Vec3 {
+impl Neg for &Vec3 {
     type Output = Vec3;
 
     fn neg(self) -> Vec3 {